@@ -0,0 +1,140 @@
+// 每次回显的内存分配次数, 用计数的全局分配器统计整个进程
+// 测量的循环里客户端使用固定的缓冲区, 不分配内存, 统计到的都是服务端和编解码的分配
+// cargo bench --bench allocations
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+use ws_server::{Config, Decoder, Message, Server};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const ECHOES: usize = 20000;
+// 缓冲区增长到稳定的大小之前的分配不计算在内
+const WARMUP: usize = 1000;
+const PAYLOAD: usize = 100;
+
+// 客户端发送的 binary frame, payload 不超过 125 字节
+fn masked_frame(payload: &[u8]) -> Vec<u8> {
+    let mask_key = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![0b1000_0010, 0b1000_0000 | payload.len() as u8];
+    frame.extend_from_slice(&mask_key);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask_key[i % 4]),
+    );
+    frame
+}
+
+// 执行 run 期间平均每次的分配次数
+fn per_iteration(iterations: usize, mut run: impl FnMut()) -> f64 {
+    for _ in 0..WARMUP {
+        run();
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..iterations {
+        run();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / iterations as f64
+}
+
+fn start(permessage_deflate: bool) -> SocketAddr {
+    let server = Server::builder()
+        .permessage_deflate(permessage_deflate)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+// 通过 loopback 回显, 协商了压缩时服务端的回复是压缩过的, 长度不固定
+fn echo(permessage_deflate: bool) -> f64 {
+    let stream = TcpStream::connect(start(permessage_deflate)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let extensions = match permessage_deflate {
+        true => "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        false => "",
+    };
+    write!(
+        writer,
+        "GET / HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n{}\r\n",
+        extensions
+    )
+    .unwrap();
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    let frame = masked_frame(&[7; PAYLOAD]);
+    let mut reply = [0; PAYLOAD + 2];
+    per_iteration(ECHOES, || {
+        writer.write_all(&frame).unwrap();
+        reader.read_exact(&mut reply[..2]).unwrap();
+        // 100 字节的 payload 压缩之后也不超过 125 字节, 长度只有 1 个字节
+        let length = (reply[1] & 0x7f) as usize;
+        reader.read_exact(&mut reply[2..2 + length]).unwrap();
+    })
+}
+
+// 不经过网络, 只有解码客户端的 frame 和编码回复的 frame
+fn encode_decode() -> f64 {
+    let request = masked_frame(&[7; PAYLOAD]);
+    let mut frame = Vec::new();
+    let mut decoder = Decoder::new(&Config::default(), None);
+    let mut buffer = Vec::new();
+    per_iteration(ECHOES, || {
+        let message = decoder
+            .decode_message_into(&mut &request[..], &mut buffer)
+            .unwrap();
+        message.encode_into(&mut frame);
+        // payload 的内存交还给下一次解码
+        if let Message::Binary(data) = message {
+            buffer = data;
+        }
+    })
+}
+
+fn main() {
+    println!(
+        "allocations per {}-byte binary message, {} messages",
+        PAYLOAD, ECHOES
+    );
+    println!("  encode + decode:              {:.2}", encode_decode());
+    println!("  echo:                         {:.2}", echo(false));
+    println!("  echo with permessage-deflate: {:.2}", echo(true));
+}
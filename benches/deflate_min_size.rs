@@ -0,0 +1,154 @@
+// deflate_min_size 对服务端 cpu 时间的影响, 消息大多很短, 偶尔有一条较长的文本
+// 十条消息里九条是 6 到 8 字节, 一条是大约 4 KiB 的文本, 协商了 permessage-deflate 并保留上下文
+// 客户端解压每一个压缩过的回复并和发送的内容比较
+// 服务端的 cpu 时间从 /proc 读取, 只统计服务端的线程, 所以只能在 linux 上运行
+// cargo bench --bench deflate_min_size
+use flate2::{Decompress, FlushDecompress};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+use ws_server::{MaskKeys, Message, Server};
+
+const MESSAGES: usize = 100_000;
+const MIN_SIZES: [usize; 2] = [0, 64];
+// /proc 里的 cpu 时间以 USER_HZ 为单位, linux 上固定是 100
+const TICKS_PER_SECOND: u64 = 100;
+
+const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Host: localhost\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Extensions: permessage-deflate\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+// 线程的 utime 和 stime 之和, stat 的第 14 和 15 项, 进程名可能有空格, 从 ')' 之后开始数
+fn ticks(stat: &str) -> u64 {
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+// 除了运行客户端的当前线程以外所有线程的 cpu 时间
+fn server_cpu() -> Duration {
+    let me = fs::read_link("/proc/thread-self").unwrap();
+    let me = me.file_name().unwrap();
+    let total: u64 = fs::read_dir("/proc/self/task")
+        .unwrap()
+        .map(|task| task.unwrap())
+        .filter(|task| task.file_name() != me)
+        .filter_map(|task| fs::read_to_string(task.path().join("stat")).ok())
+        .map(|stat| ticks(&stat))
+        .sum();
+    Duration::from_millis(total * 1000 / TICKS_PER_SECOND)
+}
+
+fn start(deflate_min_size: usize) -> SocketAddr {
+    let server = Server::builder()
+        .deflate_min_size(deflate_min_size)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+fn messages() -> Vec<Message> {
+    let words = [
+        "echo",
+        "websocket",
+        "frame",
+        "deflate",
+        "server",
+        "client",
+        "message",
+    ];
+    (0..10)
+        .map(|i| match i {
+            9 => {
+                let text: Vec<&str> = (0..600).map(|j| words[j * 7 % words.len()]).collect();
+                Message::Text(text.join(" "))
+            }
+            // 6 到 8 字节
+            _ => Message::Text(format!("msg {:0>width$}", i, width = 2 + i % 3)),
+        })
+        .collect()
+}
+
+// 读取一个没有 mask 的回复, 返回 rsv1 和 payload
+fn read_reply(reader: &mut impl Read, payload: &mut Vec<u8>) -> bool {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).unwrap();
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).unwrap();
+            u16::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+    payload.resize(length, 0);
+    reader.read_exact(payload).unwrap();
+    header[0] & 0x40 != 0
+}
+
+// 回显 MESSAGES 条消息, 返回服务端使用的 cpu 时间和压缩过的回复数量
+fn run(deflate_min_size: usize) -> (Duration, usize) {
+    let stream = TcpStream::connect(start(deflate_min_size)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(HANDSHAKE).unwrap();
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    let messages = messages();
+    let mut keys = MaskKeys::from_seed(1);
+    let frames: Vec<Vec<u8>> = messages
+        .iter()
+        .map(|m| m.encode_masked(&mut keys))
+        .collect();
+    let mut decompress = Decompress::new(false);
+    let mut payload = Vec::new();
+    let mut inflated = Vec::with_capacity(64 * 1024);
+    let mut compressed = 0;
+
+    let before = server_cpu();
+    for i in 0..MESSAGES {
+        let message = &messages[i % messages.len()];
+        writer.write_all(&frames[i % frames.len()]).unwrap();
+        let reply = match read_reply(&mut reader, &mut payload) {
+            true => {
+                compressed += 1;
+                payload.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+                inflated.clear();
+                decompress
+                    .decompress_vec(&payload, &mut inflated, FlushDecompress::Sync)
+                    .unwrap();
+                &inflated
+            }
+            false => &payload,
+        };
+        assert_eq!(reply.as_slice(), message.clone().into_bytes().as_slice());
+    }
+    (server_cpu() - before, compressed)
+}
+
+fn main() {
+    println!(
+        "{} echoes, 9 in 10 messages 6-8 bytes, 1 in 10 about 4 KiB",
+        MESSAGES
+    );
+    for min_size in MIN_SIZES {
+        let (cpu, compressed) = run(min_size);
+        println!(
+            "  deflate_min_size({:>2}): server cpu {:.2?}, {} replies compressed",
+            min_size, cpu, compressed
+        );
+    }
+}
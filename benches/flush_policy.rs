@@ -0,0 +1,110 @@
+// 客户端一次发送大量小消息时, 不同 flush_policy 的回显吞吐量
+// 一个线程不停地发送 8 字节的 text frame, 另一个线程读取所有的回复
+// cargo bench --bench flush_policy, 加上 --features tokio 同时测试 AsyncServer
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::{FlushPolicy, MaskKeys, Message, Server, ServerBuilder};
+
+const MESSAGES: usize = 200_000;
+const RUNS: usize = 3;
+const PAYLOAD: &str = "8 bytes!";
+
+const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Host: localhost\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+const POLICIES: [FlushPolicy; 3] = [
+    FlushPolicy::Immediate,
+    FlushPolicy::Batched(64),
+    FlushPolicy::Timed(Duration::from_millis(1)),
+];
+
+fn start(builder: ServerBuilder) -> SocketAddr {
+    let server = builder.bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+#[cfg(feature = "tokio")]
+fn start_async(builder: ServerBuilder) -> SocketAddr {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let server = builder.bind_async("127.0.0.1:0").await.unwrap();
+            sender.send(server.local_addr().unwrap()).unwrap();
+            let _ = server.run().await;
+        });
+    });
+    receiver.recv().unwrap()
+}
+
+// 发送 MESSAGES 条消息并读完所有的回复, 返回每秒的消息数量
+fn throughput(addr: SocketAddr) -> f64 {
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(HANDSHAKE).unwrap();
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    let frame = Message::Text(PAYLOAD.into()).encode_masked(&mut MaskKeys::from_seed(1));
+    // 每次写 1000 条, 减少客户端自己的系统调用
+    let chunk = frame.repeat(1000);
+    let started = Instant::now();
+    let sending = thread::spawn(move || {
+        for _ in 0..MESSAGES / 1000 {
+            writer.write_all(&chunk).unwrap();
+        }
+        writer
+    });
+    // 回复没有 mask, 2 字节的头加 8 字节的 payload
+    let mut replies = vec![0; 10 * 1000];
+    for _ in 0..MESSAGES / 1000 {
+        reader.read_exact(&mut replies).unwrap();
+    }
+    let elapsed = started.elapsed();
+    assert_eq!(&replies[replies.len() - 8..], PAYLOAD.as_bytes());
+    drop(sending.join().unwrap());
+    MESSAGES as f64 / elapsed.as_secs_f64()
+}
+
+// 每种 policy 运行 RUNS 次, 取最好的一次
+fn report(name: &str, start: impl Fn(ServerBuilder) -> SocketAddr) {
+    for policy in POLICIES {
+        let best = (0..RUNS)
+            .map(|_| throughput(start(Server::builder().flush_policy(policy))))
+            .fold(0.0, f64::max);
+        println!(
+            "  {:<5} {:<15} {:>10.0} msg/s",
+            name,
+            format!("{:?}", policy),
+            best
+        );
+    }
+}
+
+fn main() {
+    println!(
+        "{} {}-byte text messages echoed over loopback",
+        MESSAGES,
+        PAYLOAD.len()
+    );
+    report("sync", start);
+    #[cfg(feature = "tokio")]
+    report("async", start_async);
+}
@@ -0,0 +1,67 @@
+// 100 MiB 的 payload 加上和去掉 mask 的时间, 和逐个字节 xor 的写法对比
+// cargo bench --bench mask
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+use ws_server::{Config, Decoder, MaskKeys, Message};
+
+const LENGTH: usize = 100 * 1024 * 1024;
+const RUNS: usize = 5;
+
+// 之前的实现, 每个字节取一次 key
+fn mask_bytewise(data: &mut [u8], mask_key: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask_key[i % 4];
+    }
+}
+
+// 多次运行取最快的一次, 排除第一次运行时分配内存的影响
+fn fastest(mut run: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let started = Instant::now();
+            run();
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let payload: Vec<u8> = (0..LENGTH).map(|i| (i * 31 % 251) as u8).collect();
+    let message = Message::Binary(payload.clone());
+    let mut keys = MaskKeys::from_seed(1);
+    let mut frame = Vec::with_capacity(LENGTH + 14);
+
+    let mut copy = payload.clone();
+    // 只复制不加 mask, 下面几项减去这部分就是 mask 的时间
+    let copy_only = fastest(|| copy.copy_from_slice(black_box(&payload)));
+    let bytewise = fastest(|| {
+        copy.copy_from_slice(&payload);
+        mask_bytewise(black_box(&mut copy), [0x37, 0xfa, 0x21, 0x3d]);
+    });
+    // encode_masked_into 同样是复制 payload 之后加上 mask
+    let encode = fastest(|| message.encode_masked_into(&mut keys, black_box(&mut frame)));
+
+    let config = Config {
+        max_payload_length: LENGTH as u64,
+        max_message_length: LENGTH as u64,
+        ..Config::default()
+    };
+    let mut decoder = Decoder::new(&config, None);
+    let mut buffer = Vec::with_capacity(LENGTH);
+    let decode = fastest(|| {
+        let decoded = decoder
+            .decode_message_into(&mut &frame[..], &mut buffer)
+            .unwrap();
+        assert_eq!(decoded.len(), LENGTH);
+        buffer = decoded.into_bytes();
+    });
+
+    println!("100 MiB payload, fastest of {} runs", RUNS);
+    println!("  copy only:            {:>8.1?}", copy_only);
+    println!("  copy + bytewise mask: {:>8.1?}", bytewise);
+    println!("  encode_masked_into:   {:>8.1?}", encode);
+    println!("  decode_message_into:  {:>8.1?}", decode);
+}
@@ -0,0 +1,75 @@
+// TCP_NODELAY 对往返延迟的影响
+// 客户端在一个 tcp 段里发送两条 4 字节的 binary 消息, 等两个回复都到了再发下一次
+// 关闭 TCP_NODELAY 时第二个回复被 Nagle 算法扣住, 直到收到客户端延迟发送的 ACK
+// cargo bench --bench nodelay
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::Server;
+
+const ITERATIONS: u32 = 200;
+
+const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Host: localhost\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+fn start(tcp_nodelay: bool) -> SocketAddr {
+    let server = Server::builder()
+        .tcp_nodelay(tcp_nodelay)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+// 带 mask 的 binary frame, mask key 是 0, payload 不变
+fn binary(payload: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0x82, 0x80 | 4, 0, 0, 0, 0];
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn mean_round_trip(addr: SocketAddr) -> Duration {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(HANDSHAKE).unwrap();
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    let request = [binary(*b"ping"), binary(*b"pong")].concat();
+    // 两个回复都是 2 字节的头加 4 字节的 payload
+    let mut replies = [0; 12];
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let started = Instant::now();
+        writer.write_all(&request).unwrap();
+        reader.read_exact(&mut replies).unwrap();
+        total += started.elapsed();
+        assert_eq!(&replies[2..6], b"ping");
+        assert_eq!(&replies[8..], b"pong");
+    }
+    total / ITERATIONS
+}
+
+fn main() {
+    println!(
+        "{} round trips of two 4-byte messages over loopback",
+        ITERATIONS
+    );
+    for tcp_nodelay in [true, false] {
+        let mean = mean_round_trip(start(tcp_nodelay));
+        println!("  tcp_nodelay({}): mean {:.1?}", tcp_nodelay, mean);
+    }
+}
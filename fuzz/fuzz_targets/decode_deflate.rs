@@ -0,0 +1,26 @@
+// 和 decode_message 一样, 但是协商了 permessage-deflate, 并且使用很小的长度限制
+// 覆盖解压, 分片拼接和各种长度检查
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use ws_server::{Config, Decoder, DeflateParams};
+
+fuzz_target!(|data: &[u8]| {
+    let config = Config {
+        max_payload_length: 1024,
+        max_message_length: 4096,
+        max_fragments: 8,
+        ..Config::default()
+    };
+    let params = DeflateParams {
+        client_no_context_takeover: data.first().is_some_and(|b| b & 1 == 1),
+        ..DeflateParams::default()
+    };
+    let mut decoder = Decoder::new(&config, Some(&params));
+    let mut reader = Cursor::new(data);
+    let mut buffer = Vec::new();
+    while let Ok(message) = decoder.decode_message_into(&mut reader, &mut buffer) {
+        buffer = message.into_bytes();
+    }
+});
@@ -0,0 +1,11 @@
+// 任意的字节交给 decode_message, 只能返回 Ok 或者 Err, 不能 panic
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    // 一直解码到出错为止, 数据读完时返回 ConnectionClosed
+    while ws_server::decode_message(&mut reader).is_ok() {}
+});
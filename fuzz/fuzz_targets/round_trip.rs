@@ -0,0 +1,26 @@
+// 加上 mask 编码的消息经过 decode_message 之后必须和原来的消息相同
+// 长度经常落在 7 位, 16 位和 64 位长度编码的边界上
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use ws_server::{decode_message, MaskKeys, Message};
+
+const BOUNDARIES: [usize; 9] = [0, 1, 124, 125, 126, 127, 65535, 65536, 65537];
+
+fuzz_target!(|input: (u8, u32, String)| {
+    let (kind, length, text) = input;
+    let length = match length % 4 {
+        0 => BOUNDARIES[length as usize / 4 % BOUNDARIES.len()],
+        _ => length as usize % 70_000,
+    };
+    let message = match kind % 3 {
+        0 => Message::Text(text),
+        // 多字节的字符重复到边界附近的长度
+        1 => Message::Text("é".repeat(length / 2)),
+        _ => Message::Binary((0..length).map(|i| (i as u8) ^ kind).collect()),
+    };
+    let frame = message.encode_masked(&mut MaskKeys::new());
+    let decoded = decode_message(&mut Cursor::new(frame)).expect("a valid frame must decode");
+    assert_eq!(decoded, message);
+});
@@ -0,0 +1,138 @@
+// 握手的访问日志, 每个请求 (包括被拒绝的和健康检查) 一行
+// 格式和 nginx 的 combined 一样, referer 的位置换成了 Origin:
+// 127.0.0.1 - - [14/Oct/2026:04:33:49 +0000] "GET /chat HTTP/1.1" 101 - "https://example.com" "Mozilla/5.0"
+use log::info;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// 访问日志写到哪里
+pub enum AccessLog {
+    Stderr,
+    // 通过 log 以 info 级别输出, target 是 ws_server::access, 可以用 RUST_LOG 单独控制
+    Log,
+    // 追加到文件, 通过 AccessLog::file 打开
+    File(Mutex<File>),
+}
+
+impl AccessLog {
+    pub fn file(path: impl AsRef<Path>) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::File(Mutex::new(file)))
+    }
+
+    // 写日志失败不影响握手
+    pub(crate) fn write(&self, entry: &AccessEntry) {
+        match self {
+            AccessLog::Stderr => {
+                let _ = writeln!(io::stderr().lock(), "{}", entry);
+            }
+            AccessLog::Log => info!(target: "ws_server::access", "{}", entry),
+            AccessLog::File(file) => {
+                let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+                let _ = writeln!(file, "{}", entry);
+            }
+        }
+    }
+}
+
+// 一行访问日志, 没有的字段输出 -
+pub(crate) struct AccessEntry {
+    time: SystemTime,
+    peer: Option<IpAddr>,
+    // 请求行没有解析出来时是 None
+    target: Option<String>,
+    origin: Option<String>,
+    user_agent: Option<String>,
+    // 返回给客户端的状态码, 响应没有写出去时是 None
+    pub(crate) status: Option<u16>,
+}
+
+impl AccessEntry {
+    pub(crate) fn new(peer: Option<IpAddr>, request: Option<&crate::Request>) -> Self {
+        let header = |name| request.and_then(|request| request.headers.get(name).cloned());
+        AccessEntry {
+            time: SystemTime::now(),
+            peer,
+            target: request.map(|request| request.target.clone()),
+            origin: header("origin"),
+            user_agent: header("user-agent"),
+            status: None,
+        }
+    }
+}
+
+impl fmt::Display for AccessEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{}", peer)?,
+            None => f.write_str("-")?,
+        }
+        write!(f, " - - [{}] ", format_time(self.time))?;
+        match &self.target {
+            Some(target) => write!(f, "\"GET {} HTTP/1.1\"", escape(target))?,
+            None => f.write_str("\"-\"")?,
+        }
+        match self.status {
+            Some(status) => write!(f, " {} -", status)?,
+            None => f.write_str(" - -")?,
+        }
+        for value in [&self.origin, &self.user_agent] {
+            match value {
+                Some(value) => write!(f, " \"{}\"", escape(value))?,
+                None => f.write_str(" \"-\"")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 客户端发来的值不能破坏日志的格式, 引号, 反斜杠和控制字符转义成 \xHH
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c.is_control() {
+            for byte in c.to_string().bytes() {
+                escaped.push_str(&format!("\\x{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// 格式化成 14/Oct/2026:04:33:49 +0000, 统一使用 UTC
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // 从 1970-01-01 开始的天数换算成年月日 (Howard Hinnant 的 civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,815 @@
+// 基于 tokio 的异步实现, frame 的解析和消息处理与同步版本共用
+use crate::{
+    access_log::AccessEntry,
+    connection::{close_message, handle_message, ConnectionLimit, Encoder, Pending, Reply},
+    deflate::Deflater,
+    error::truncated,
+    handler::{EchoAction, Lifecycle},
+    handshake, listener,
+    message::{header_length, parse_header, Frame},
+    metrics::{Counters, Transferred},
+    proxy_protocol,
+    rate_limit::{self, RateLimiter},
+    server::log_disconnect,
+    session::Session,
+    CloseReason, Config, ConnectionContext, Decoder, EchoHandler, FlushPolicy, HandlerFactory,
+    HandshakeError, Message, MessageHandler, Metrics, Sender, ServerBuilder, WsError,
+};
+use log::{error, info, warn};
+use std::{
+    future::Future,
+    io, mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter, ReadBuf,
+    },
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, watch, Notify},
+    task::JoinSet,
+    time::{self, Instant, Sleep},
+};
+
+// 每个连接最多排队等待发送的消息数量, 超过之后 Sender::send 返回错误
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+
+// 异步的 websocket 服务端, 每个连接是一个 tokio task
+pub struct AsyncServer<H = EchoHandler> {
+    listener: TcpListener,
+    config: Config,
+    handler: H,
+    counters: Arc<Counters>,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+// 关闭服务的阶段, 只会往后变化, 连接的 task 通过 watch 得到通知
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    // 已经停止 accept, 给所有连接发送了 1001 的 close 帧, 等待客户端关闭
+    Closing,
+    // 超过了 shutdown_grace_period, 直接断开剩下的连接
+    Closed,
+}
+
+// 停止 AsyncServer, 可以 clone 之后在其他 task 或者线程里使用
+// 服务已经停止之后再调用没有影响
+#[derive(Clone)]
+pub struct AsyncShutdownHandle {
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+impl AsyncShutdownHandle {
+    pub fn shutdown(&self) {
+        self.phase.send_if_modified(|phase| {
+            let running = *phase == Phase::Running;
+            if running {
+                *phase = Phase::Closing;
+            }
+            running
+        });
+    }
+}
+
+// 等到服务进入 target 阶段, AsyncServer 已经不存在时一直等待
+async fn reached(phase: &mut watch::Receiver<Phase>, target: Phase) {
+    if phase.wait_for(|phase| *phase >= target).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+impl AsyncServer {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<AsyncServer> {
+        ServerBuilder::default().bind_async(addr).await
+    }
+}
+
+impl ServerBuilder {
+    pub async fn bind_async(self, addr: impl ToSocketAddrs) -> io::Result<AsyncServer> {
+        // 异步的实现还不支持 tls
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tls is not supported by AsyncServer",
+            ));
+        }
+        // 分块处理需要在读 frame 的同时写回复, 异步的实现还不支持, 不能悄悄地整条读进内存
+        if self.config.stream_threshold.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stream_threshold is not supported by AsyncServer",
+            ));
+        }
+        handshake::check_response_headers(&self.config.extra_response_headers)?;
+        rate_limit::check(self.config.rate_limit.as_ref())?;
+        // 和同步版本一样通过 socket2 创建, backlog 等选项同样生效
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+        let listener = listener::bind(&addrs[..], &self.config)?;
+        listener.set_nonblocking(true)?;
+        Ok(AsyncServer {
+            listener: TcpListener::from_std(listener)?,
+            config: self.config,
+            handler: EchoHandler,
+            counters: Arc::default(),
+            phase: Arc::new(watch::channel(Phase::Running).0),
+        })
+    }
+}
+
+impl<H> AsyncServer<H> {
+    // 替换处理消息的 handler
+    pub fn with_handler<T: MessageHandler>(self, handler: T) -> AsyncServer<T> {
+        self.replace_handler(handler)
+    }
+
+    // 每个连接用 factory 创建一个新的 handler, 而不是 clone 同一个
+    pub fn with_handler_factory<T: HandlerFactory>(self, factory: T) -> AsyncServer<T> {
+        self.replace_handler(factory)
+    }
+
+    fn replace_handler<T>(self, handler: T) -> AsyncServer<T> {
+        AsyncServer {
+            listener: self.listener,
+            config: self.config,
+            handler,
+            counters: self.counters,
+            phase: self.phase,
+        }
+    }
+
+    // 和同步版本一样, 当前的统计数据
+    // run 会消耗 AsyncServer, 运行时的统计数据通过 metrics_path 获取
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    // 和同步版本一样, 停止接受新的连接, 并给所有连接发送 1001 (going away) 的 close 帧
+    // run 会等待客户端关闭连接, 超过 shutdown_grace_period 之后断开剩下的连接并返回
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    // run 会消耗 AsyncServer, 在其他 task 里通过 handle 停止服务
+    pub fn shutdown_handle(&self) -> AsyncShutdownHandle {
+        AsyncShutdownHandle {
+            phase: Arc::clone(&self.phase),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl<H: HandlerFactory> AsyncServer<H>
+where
+    H::Handler: Send + 'static,
+{
+    pub async fn run(self) -> io::Result<()> {
+        let handle = self.shutdown_handle();
+        let AsyncServer {
+            listener,
+            config,
+            handler,
+            counters,
+            phase,
+        } = self;
+        let config = Arc::new(config);
+        let limit = Arc::new(ConnectionLimit::default());
+        let mut shutdown = phase.subscribe();
+        // 连接的 task 都放在这里, 关闭服务时等待它们结束
+        let mut tasks = JoinSet::new();
+        let mut next_id = 0;
+        let result = loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // 已经结束的连接从 tasks 里移除
+                Some(_) = tasks.join_next() => continue,
+                () = reached(&mut shutdown, Phase::Closing) => break Ok(()),
+            };
+            // 和同步版本一样, accept 出错时等一会再重试, 不能结束整个服务
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => match listener::accept_backoff(&err) {
+                    Some(backoff) => {
+                        warn!("accept failed: {}", err);
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                    None => {
+                        error!("accept failed, stop serving: {}", err);
+                        handle.shutdown();
+                        break Err(err);
+                    }
+                },
+            };
+            next_id += 1;
+            let mut ctx = ConnectionContext {
+                id: next_id,
+                peer_addr: Some(peer_addr),
+                forwarded_for: None,
+                protocol: None,
+            };
+            let config = Arc::clone(&config);
+            let limit = Arc::clone(&limit);
+            let counters = Arc::clone(&counters);
+            let mut handler = handler.new_handler();
+            let mut shutdown = phase.subscribe();
+            let mut closed = phase.subscribe();
+            tasks.spawn(async move {
+                info!("{} connected", ctx);
+                let stats = Stats {
+                    counters,
+                    transferred: Arc::new(Transferred::default()),
+                };
+                let serving = serve(
+                    stream,
+                    &config,
+                    &limit,
+                    &mut handler,
+                    &stats,
+                    &mut shutdown,
+                    &mut ctx,
+                );
+                let result = tokio::select! {
+                    result = serving => result,
+                    // 超过 shutdown_grace_period 还没有关闭, 包括还在握手的连接
+                    () = reached(&mut closed, Phase::Closed) => Ok(CloseReason::Abnormal),
+                };
+                log_disconnect(&ctx, stats.transferred.snapshot(), Ok(result));
+            });
+        };
+        // 不再接受新的连接
+        drop(listener);
+
+        // 等待客户端回复 close 帧之后断开连接
+        info!("shutting down, {} connections still open", tasks.len());
+        let closing = async { while tasks.join_next().await.is_some() {} };
+        if time::timeout(config.shutdown_grace_period, closing)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} connections did not close in time, force closed",
+                tasks.len()
+            );
+            phase.send_replace(Phase::Closed);
+            while tasks.join_next().await.is_some() {}
+        }
+        result
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    config: &Config,
+    limit: &ConnectionLimit,
+    handler: &mut impl MessageHandler,
+    stats: &Stats,
+    shutdown: &mut watch::Receiver<Phase>,
+    ctx: &mut ConnectionContext,
+) -> Result<CloseReason, WsError> {
+    let counters = &*stats.counters;
+    Counters::incr(&counters.connections_total);
+    stream.set_nodelay(config.tcp_nodelay)?;
+    if let Some(keepalive) = &config.tcp_keepalive {
+        listener::set_keepalive(&stream, keepalive)?;
+    }
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(ReadTimeout {
+        inner: reader,
+        timeout: None,
+        sleep: None,
+    });
+    let mut writer = BufWriter::new(writer);
+    // 和同步版本一样, handshake_timeout 包括 PROXY 头和 http 握手
+    let started = Instant::now();
+
+    if config.proxy_protocol {
+        let header = read_proxy_header(&mut reader);
+        if let Some(peer_addr) = with_timeout(config.handshake_timeout, header).await? {
+            info!("{} proxied for {}", ctx, peer_addr);
+            ctx.peer_addr = Some(peer_addr);
+        }
+    }
+
+    // 先把请求头读到内存里, 再交给同步的 handshake 解析
+    let remaining = config
+        .handshake_timeout
+        .map(|timeout| timeout.saturating_sub(started.elapsed()));
+    let request = match remaining {
+        Some(timeout) => match tokio::time::timeout(
+            timeout,
+            read_upgrade_request(&mut reader, &mut writer, config, stats),
+        )
+        .await
+        {
+            Ok(request) => request?,
+            Err(_) => {
+                let err = HandshakeError::timed_out();
+                Counters::incr(&counters.handshake_failures);
+                if let Some(access_log) = &config.access_log {
+                    let mut entry = AccessEntry::new(ctx.peer_addr.map(|addr| addr.ip()), None);
+                    entry.status = Some(err.status_code());
+                    access_log.write(&entry);
+                }
+                let response = err.response();
+                writer.write_all(response.as_bytes()).await?;
+                writer.flush().await?;
+                stats.sent(response.len());
+                return Err(err.into());
+            }
+        },
+        None => read_upgrade_request(&mut reader, &mut writer, config, stats).await?,
+    };
+    // 和同步版本一样包括握手, 不包括 PROXY protocol 的头
+    stats.received(request.len());
+    let mut response = Vec::new();
+    let mut session = Session::new();
+    let handshake = session.handshake(|| {
+        handshake::accept(
+            &mut request.as_slice(),
+            &mut response,
+            config,
+            Some(counters),
+            Some(limit),
+            ctx.peer_addr.map(|addr| addr.ip()),
+            // CONNECT 已经在 read_upgrade_request 里处理过了
+            false,
+        )
+    });
+    writer.write_all(&response).await?;
+    writer.flush().await?;
+    stats.sent(response.len());
+    let (handshake, _limit) = match handshake {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            if let WsError::HandshakeFailed(_) = err {
+                Counters::incr(&counters.handshake_failures);
+            }
+            return Err(err);
+        }
+    };
+    if let Some(client) = handshake.forwarded_for {
+        info!("{} forwarded for {}", ctx, client);
+        ctx.forwarded_for = Some(client);
+    }
+    ctx.protocol.clone_from(&handshake.protocol);
+    let ctx = &*ctx;
+    // 出错和 panic 时都会减少活跃连接数
+    let _active = counters.open();
+    // 和同步版本一样, read_timeout 在握手之后才生效
+    reader.get_mut().timeout = config.read_timeout;
+
+    let mut sink = AsyncSink {
+        writer,
+        encoder: Encoder::new(
+            handshake.deflate.as_ref().map(Deflater::new),
+            config.deflate_min_size,
+        ),
+        closed: false,
+        close_sent: None,
+        pending: Pending::default(),
+        pong: None,
+        write_timeout: config.write_timeout,
+        stats: stats.clone(),
+    };
+    // handler 通过 Sender 发送的消息在这个 task 里写出去
+    let (queue, mut outbound) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let disconnect = Arc::new(Notify::new());
+    let sender = Sender::new_async(queue, Arc::clone(&disconnect));
+    handler.on_request(ctx, &handshake.request);
+    handler.on_connect(ctx, &sender);
+    let lifecycle = Lifecycle::new(handler, ctx, &stats.transferred);
+    let handler = &mut *lifecycle.handler;
+
+    let mut decoder = Decoder::new(config, handshake.deflate.as_ref());
+    let mut limiter = RateLimiter::new(config.rate_limit.as_ref());
+    let mut buffer = Vec::new();
+    let mut keepalive = Keepalive::new(config);
+    let expires = config
+        .max_connection_duration
+        .map(|duration| Instant::now() + duration);
+    // 所有的 return 都在这里面, 之后设置 on_disconnect 的 reason
+    let result = async {
+        loop {
+            // 收到的数据都处理完了, 等待之前先把缓冲的回复发出去
+            if reader.buffer().is_empty() {
+                sink.flush().await?;
+            }
+            // 回复, Sender 推送的消息和 ping 都在这个 task 里写出去, frame 不会交错
+            tokio::select! {
+                // fill_buf 可以安全地取消, 有数据之后再读取完整的 frame
+                result = reader.fill_buf() => {
+                    if let Err(err) = result {
+                        // 读超时需要先发送 1001 的 close 帧
+                        let err = WsError::from(err);
+                        if let Some(code) = err.close_code() {
+                            session.closing();
+                            sink.send(&close_message(code)).await?;
+                        }
+                        return Err(err);
+                    }
+                    keepalive.received();
+                }
+                Some(message) = outbound.recv() => {
+                    sink.send(&message).await?;
+                    continue;
+                }
+                _ = disconnect.notified() => return Err(WsError::ConnectionClosed),
+                _ = sleep_until(expires) => {
+                    session.closing();
+                    sink.send(&close_message(1001)).await?;
+                    info!("{} reached max_connection_duration, closed with 1001", ctx);
+                    return Ok(CloseReason::Timeout);
+                }
+                _ = keepalive.wait() => {
+                    if keepalive.ping_sent.is_some() {
+                        session.closing();
+                        sink.send(&close_message(1001)).await?;
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "pong timeout").into());
+                    }
+                    keepalive.ping_sent = Some(Instant::now());
+                    sink.send(&Message::Ping(Vec::new())).await?;
+                    continue;
+                }
+                // 和同步版本一样, 关闭服务时发送 1001, 继续读到客户端回复的 close 帧
+                () = reached(shutdown, Phase::Closing), if !sink.closed => {
+                    session.closing();
+                    sink.send(&close_message(1001)).await?;
+                    continue;
+                }
+            }
+
+            // 第一个字节已经到了, 剩下的部分需要在 frame_timeout 之内收完
+            let frame = read_frame(&mut reader, &decoder, &mut buffer, stats);
+            let frame = match config.frame_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, frame)
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+                None => frame.await,
+            };
+            let message = frame.and_then(|frame| decoder.push_frame(frame, &mut buffer));
+            let message = match message {
+                Ok(Some(message)) => config
+                    .echo_policy
+                    .check(&message)
+                    .map_err(WsError::from)
+                    .and_then(|()| limiter.acquire_message(&message))
+                    .map(|wait| (message, wait)),
+                Ok(None) => continue,
+                Err(err) => Err(err),
+            };
+            let message = match message {
+                Ok((message, wait)) => {
+                    // tokio 的 sleep 即使是 0 也要等到下一个时钟周期 (1ms), 不限流时每条消息都会被拖慢
+                    if !wait.is_zero() {
+                        tokio::time::sleep(wait).await;
+                    }
+                    message
+                }
+                Err(err) => {
+                    // 协议错误需要先发送 close 帧告知客户端原因
+                    if let Some(code) = err.close_code() {
+                        session.closing();
+                        sink.send(&close_message(code)).await?;
+                    }
+                    return Err(err);
+                }
+            };
+
+            if config.echo_policy.action(&message) == EchoAction::Drop {
+                continue;
+            }
+            match handle_message(message, handler, ctx) {
+                Reply::Close(code, reason) => {
+                    session.closing();
+                    let reason = match sink.close_sent {
+                        Some(sent) => CloseReason::ServerInitiated { code: sent },
+                        None => CloseReason::Normal { code, reason },
+                    };
+                    let close = Message::Close {
+                        code,
+                        reason: String::new(),
+                    };
+                    sink.send(&close).await?;
+                    return Ok(reason);
+                }
+                Reply::Pong(data) => sink.pong = Some(data),
+                Reply::Send(message) => {
+                    sink.send_buffered(&message, config.flush_policy).await?;
+                    if let Message::Text(_) | Message::Binary(_) = message {
+                        Counters::incr(&counters.messages_echoed);
+                    }
+                }
+                Reply::None => {}
+            }
+        }
+    }
+    .await;
+    session.closed();
+    let reason = match result {
+        Ok(reason) => reason,
+        Err(err) => {
+            if let WsError::ProtocolViolation(_) = err {
+                Counters::incr(&counters.protocol_errors);
+            }
+            CloseReason::from_error(&err)
+        }
+    };
+    lifecycle.finish(reason.clone());
+    Ok(reason)
+}
+
+// 和同步版本一样, 空闲的连接发送 ping, ping 之后超过 pong_timeout 没有收到数据时关闭连接
+struct Keepalive {
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Keepalive {
+    fn new(config: &Config) -> Self {
+        Keepalive {
+            ping_interval: config.ping_interval,
+            pong_timeout: config.pong_timeout,
+            last_received: Instant::now(),
+            ping_sent: None,
+        }
+    }
+
+    // 收到任何数据都说明连接还活着
+    fn received(&mut self) {
+        self.last_received = Instant::now();
+        self.ping_sent = None;
+    }
+
+    // 等到需要发送 ping 或者 pong 超时, 没有设置 ping_interval 时一直等待
+    async fn wait(&self) {
+        let deadline = match (self.ping_interval, self.ping_sent) {
+            (None, _) => None,
+            (Some(_), Some(ping_sent)) => Some(ping_sent + self.pong_timeout),
+            (Some(ping_interval), None) => Some(self.last_received + ping_interval),
+        };
+        sleep_until(deadline).await
+    }
+}
+
+// None 表示没有截止时间, 一直等待
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// 和同步版本的 Sink 一样, 发送过 close 帧或者写失败之后不再发送任何数据
+// 异步的连接不经过 Counted, 写出去的数据在这里计入统计
+struct AsyncSink<W> {
+    writer: W,
+    encoder: Encoder,
+    closed: bool,
+    // 和同步版本一样, 发送过的 close 帧的状态码
+    close_sent: Option<Option<u16>>,
+    pending: Pending,
+    // 和同步版本一样, 收到的数据处理完之后只回复最后一个 ping
+    pong: Option<Vec<u8>>,
+    write_timeout: Option<Duration>,
+    stats: Stats,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncSink<W> {
+    async fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.send_buffered(message, FlushPolicy::Immediate).await
+    }
+
+    async fn send_buffered(&mut self, message: &Message, policy: FlushPolicy) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let policy = match message {
+            Message::Close { code, .. } => {
+                self.closed = true;
+                self.close_sent = Some(*code);
+                FlushPolicy::Immediate
+            }
+            _ => policy,
+        };
+        let write = async {
+            let frame = self.encoder.encode(message);
+            self.writer.write_all(frame).await?;
+            self.stats.sent(frame.len());
+            if self.pending.push(policy) {
+                self.pending.clear();
+                self.writer.flush().await?;
+            }
+            Ok(())
+        };
+        let result = with_timeout(self.write_timeout, write).await;
+        // 写失败 (包括超时) 之后不再发送任何数据
+        if result.is_err() {
+            self.closed = true;
+        }
+        result
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if let Some(data) = self.pong.take() {
+            return self.send(&Message::Pong(data)).await;
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.pending.clear();
+        let result = with_timeout(self.write_timeout, self.writer.flush()).await;
+        if result.is_err() {
+            self.closed = true;
+        }
+        result
+    }
+}
+
+// 最多等待 timeout, 超时之后返回 TimedOut
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    io: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, io)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => io.await,
+    }
+}
+
+// 异步的连接不经过 Counted, 在解析请求和 frame 的地方计入服务端和这个连接的统计
+#[derive(Clone)]
+struct Stats {
+    counters: Arc<Counters>,
+    transferred: Arc<Transferred>,
+}
+
+impl Stats {
+    fn received(&self, size: usize) {
+        self.counters.received(size);
+        self.transferred.received(size);
+    }
+
+    fn sent(&self, size: usize) {
+        self.counters.sent(size);
+        self.transferred.sent(size);
+    }
+}
+
+// 和同步版本 socket 的读超时一样, 每次读等待数据的时间不能超过 timeout, 包括读了一半的 frame
+// 第一次返回 Pending 时开始计时, 读到数据之后停止, 被 select! 取消之后再读不会重新计时
+struct ReadTimeout<R> {
+    inner: R,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.sleep = None;
+            return Poll::Ready(result);
+        }
+        let Some(timeout) = this.timeout else {
+            return Poll::Pending;
+        };
+        let sleep = this
+            .sleep
+            .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.sleep = None;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// 读取请求行和头信息, 直到空行为止
+// 最多多读一个字节, 超过长度限制时交给 handshake 返回 431
+// 只读到空行为止, 后面已经到达的 frame 留在 reader 的缓冲区里
+// 和同步版本一样, 开启 connect_tunnel 时第一个请求可以是 CONNECT, 回复 200 之后读取真正的握手请求
+// 整个过程都在 handshake_timeout 之内
+async fn read_upgrade_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    config: &Config,
+    stats: &Stats,
+) -> io::Result<Vec<u8>> {
+    let request = read_request(reader, config).await?;
+    if !config.connect_tunnel || !handshake::is_connect(&request) {
+        return Ok(request);
+    }
+    writer.write_all(handshake::CONNECT_ESTABLISHED).await?;
+    writer.flush().await?;
+    stats.received(request.len());
+    stats.sent(handshake::CONNECT_ESTABLISHED.len());
+    read_request(reader, config).await
+}
+
+// 最多多读一个字节, 超过 max_total_header_bytes 时由 handshake 返回 431
+async fn read_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    config: &Config,
+) -> io::Result<Vec<u8>> {
+    let mut reader = reader.take(config.max_total_header_bytes as u64 + 1);
+    let mut request = Vec::new();
+    loop {
+        let start = request.len();
+        if reader.read_until(b'\n', &mut request).await? == 0 {
+            // 连接提前断开, 交给 handshake 返回 400
+            return Ok(request);
+        }
+        // 遇到空行说明头信息结束
+        // 和同步版本一样, 不是 \r\n 结尾的行 (包括只有 \n 的空行) 马上交给 handshake 返回 400, 不用等到超时
+        // 请求行是空行时也一样
+        let line = &request[start..];
+        if line == b"\r\n" || !line.ends_with(b"\r\n") {
+            return Ok(request);
+        }
+    }
+}
+
+// 和同步版本的 proxy_protocol::read_header 一样, 只是这里可以使用 reader 的缓冲区
+async fn read_proxy_header(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; proxy_protocol::PREFIX_LENGTH];
+    reader.read_exact(&mut header).await?;
+    match proxy_protocol::version(&header)? {
+        proxy_protocol::Version::V1 => {
+            let limit = (proxy_protocol::V1_MAX_LENGTH - header.len()) as u64;
+            (&mut *reader)
+                .take(limit)
+                .read_until(b'\n', &mut header)
+                .await?;
+            proxy_protocol::parse_v1(&header)
+        }
+        proxy_protocol::Version::V2 => {
+            header.resize(proxy_protocol::V2_HEADER_LENGTH, 0);
+            reader
+                .read_exact(&mut header[proxy_protocol::PREFIX_LENGTH..])
+                .await?;
+            let mut addresses = vec![0; proxy_protocol::v2_length(&header)?];
+            reader.read_exact(&mut addresses).await?;
+            proxy_protocol::parse_v2(&header, &addresses)
+        }
+    }
+}
+
+async fn read_frame(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    decoder: &Decoder,
+    buffer: &mut Vec<u8>,
+    stats: &Stats,
+) -> Result<Frame, WsError> {
+    // 在 frame 的边界上读不到数据, 说明客户端正常断开了连接
+    if reader.fill_buf().await?.is_empty() {
+        return Err(WsError::ConnectionClosed);
+    }
+    let mut header = [0; 14];
+    reader
+        .read_exact(&mut header[..2])
+        .await
+        .map_err(truncated)?;
+    let length = header_length([header[0], header[1]], decoder.allow_rsv1(), decoder.masked)?;
+    reader
+        .read_exact(&mut header[2..length])
+        .await
+        .map_err(truncated)?;
+    let header = parse_header(&header[..length], decoder.max_payload_length)?;
+    decoder.check_data_type(&header)?;
+
+    let mut payload_data = mem::take(buffer);
+    payload_data.clear();
+    payload_data.resize(header.payload_length, 0);
+    reader
+        .read_exact(&mut payload_data)
+        .await
+        .map_err(truncated)?;
+    stats.received(length + header.payload_length);
+
+    Ok(header.into_frame(payload_data))
+}
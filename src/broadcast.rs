@@ -0,0 +1,106 @@
+// 把每条消息转发给所有的连接, 例如聊天室
+use crate::{ConnectionContext, Message, MessageHandler, Sender};
+use log::warn;
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, TrySendError},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+// 每个连接最多排队等待发送的消息数量
+const DEFAULT_QUEUE_SIZE: usize = 64;
+
+// 所有的连接共用一个注册表, 每个连接有自己的队列和发送线程
+// 队列满了说明这个客户端接收得太慢, 直接断开它, 不会拖慢发送消息的连接
+#[derive(Clone)]
+pub struct BroadcastHandler {
+    members: Arc<Mutex<HashMap<u64, Member>>>,
+    queue_size: usize,
+    // 服务端为连接 clone 出来的 handler 才有 id
+    id: Option<u64>,
+}
+
+struct Member {
+    queue: mpsc::SyncSender<Message>,
+    sender: Sender,
+}
+
+impl BroadcastHandler {
+    pub fn new() -> Self {
+        BroadcastHandler::with_queue_size(DEFAULT_QUEUE_SIZE)
+    }
+
+    pub fn with_queue_size(queue_size: usize) -> Self {
+        BroadcastHandler {
+            members: Arc::default(),
+            queue_size: queue_size.max(1),
+            id: None,
+        }
+    }
+
+    // 当前加入的连接数量
+    pub fn connection_count(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Member>> {
+        self.members.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for BroadcastHandler {
+    fn default() -> Self {
+        BroadcastHandler::new()
+    }
+}
+
+impl MessageHandler for BroadcastHandler {
+    fn on_connect(&mut self, ctx: &ConnectionContext, sender: &Sender) {
+        let (queue, receiver) = mpsc::sync_channel::<Message>(self.queue_size);
+        // 写数据可能阻塞, 放在单独的线程里, 连接离开之后队列关闭, 线程退出
+        let delivery = sender.clone();
+        thread::spawn(move || {
+            for message in receiver {
+                if delivery.send(message).is_err() {
+                    delivery.disconnect();
+                    return;
+                }
+            }
+        });
+        self.lock().insert(
+            ctx.id,
+            Member {
+                queue,
+                sender: sender.clone(),
+            },
+        );
+        self.id = Some(ctx.id);
+    }
+
+    // 包括发送者自己, 所有的连接按相同的顺序收到消息
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        self.lock()
+            .retain(|id, member| match member.queue.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("#{} is too slow to receive broadcasts, disconnecting", id);
+                    member.sender.disconnect();
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        None
+    }
+}
+
+// 连接结束时 handler 被 drop, 离开注册表
+impl Drop for BroadcastHandler {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.lock().remove(&id);
+        }
+    }
+}
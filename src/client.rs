@@ -0,0 +1,174 @@
+// 阻塞的 websocket 客户端, 给测试和简单的工具使用, 可以连接任何 websocket 服务端
+// 只支持 ws://, 不协商扩展和子协议
+use crate::{
+    handshake::{compute_accept, MAX_HEADER_SIZE},
+    Config, Decoder, MaskKeys, Message, WsError,
+};
+use base64::{engine::general_purpose, Engine as _};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    decoder: Decoder,
+    keys: MaskKeys,
+    // 编码发送的 frame, 在多次发送之间复用
+    frame: Vec<u8>,
+}
+
+impl Client {
+    // url 的格式是 ws://host[:port][/path], 端口默认是 80
+    pub fn connect(url: &str) -> io::Result<Client> {
+        let (authority, path) = parse_url(url)?;
+        // 没有端口, 或者是 [::1] 这样的 ipv6 地址
+        let addr = if authority.ends_with(']') || !authority.contains(':') {
+            format!("{}:80", authority)
+        } else {
+            authority.to_string()
+        };
+        let stream = TcpStream::connect(addr)?;
+        // ws://host?query 的路径是 /?query
+        if path.starts_with('?') {
+            return Client::handshake(stream, authority, &format!("/{}", path));
+        }
+        Client::handshake(stream, authority, path)
+    }
+
+    // 在已经建立的 tcp 连接上握手, host 是 Host 头的值, path 是请求的路径
+    pub fn handshake(stream: TcpStream, host: &str, path: &str) -> io::Result<Client> {
+        let mut key = [0; 16];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| io::Error::other("failed to read system random"))?;
+        let key = general_purpose::STANDARD.encode(key);
+
+        let mut writer = stream.try_clone()?;
+        write!(
+            writer,
+            "GET {} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: {}\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        )?;
+        writer.flush()?;
+
+        // 服务端可能把第一个 frame 和响应一起发送, 之后继续使用这个 reader
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = read_response(&mut reader)?;
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(invalid(format!("unexpected response: {}", status)));
+        }
+        let header = |name| headers.get(name).map(String::as_str).unwrap_or("");
+        if !header("upgrade").eq_ignore_ascii_case("websocket") {
+            return Err(invalid("missing Upgrade: websocket".to_string()));
+        }
+        if header("sec-websocket-accept") != compute_accept(&key) {
+            return Err(invalid("invalid Sec-WebSocket-Accept".to_string()));
+        }
+        // 没有 offer 任何扩展, 服务端不能使用扩展
+        if !header("sec-websocket-extensions").is_empty() {
+            return Err(invalid("unexpected Sec-WebSocket-Extensions".to_string()));
+        }
+
+        Ok(Client {
+            reader,
+            writer,
+            decoder: Decoder::client(&Config::default()),
+            keys: MaskKeys::new(),
+            frame: Vec::new(),
+        })
+    }
+
+    // 发送一条消息, 每个 frame 使用新的 mask key
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        message.encode_masked_into(&mut self.keys, &mut self.frame);
+        self.writer.write_all(&self.frame)
+    }
+
+    // 读取下一条消息, 分片的消息拼接之后返回
+    // ping 和 close 也原样返回, 不会自动回复, 需要时由调用方发送 pong 或者 close
+    pub fn recv(&mut self) -> Result<Message, WsError> {
+        self.decoder.decode_message(&mut self.reader)
+    }
+
+    // 发送 close 帧, 然后等待服务端的 close 帧, 之前收到的消息被丢弃
+    pub fn close(&mut self, code: u16) -> Result<(), WsError> {
+        self.send(&Message::Close {
+            code: Some(code),
+            reason: String::new(),
+        })?;
+        loop {
+            match self.recv() {
+                Ok(Message::Close { .. }) | Err(WsError::ConnectionClosed) => return Ok(()),
+                Ok(_) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // 底层的 tcp 连接, 例如用来设置读超时
+    pub fn get_ref(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// 拆分成 host[:port] 和路径, 没有路径时是 /
+fn parse_url(url: &str) -> io::Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "only ws:// is supported"))?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing host"));
+    }
+    Ok((authority, path))
+}
+
+// 读取状态行和头信息, 头信息的名字转换成小写, 重复的头用逗号合并
+fn read_response(reader: &mut impl BufRead) -> io::Result<(String, BTreeMap<String, String>)> {
+    let mut remaining = MAX_HEADER_SIZE;
+    let mut read_line = || -> io::Result<String> {
+        let mut line = Vec::new();
+        let size = reader.take(remaining as u64).read_until(b'\n', &mut line)?;
+        remaining -= size;
+        if !line.ends_with(b"\r\n") {
+            return Err(invalid("incomplete handshake response".to_string()));
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).map_err(|_| invalid("invalid handshake response".to_string()))
+    };
+
+    let status = read_line()?;
+    let mut headers = BTreeMap::new();
+    loop {
+        let line = read_line()?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("invalid header: {}", line)))?;
+        headers
+            .entry(name.trim().to_ascii_lowercase())
+            .and_modify(|values: &mut String| {
+                values.push_str(", ");
+                values.push_str(value.trim());
+            })
+            .or_insert_with(|| value.trim().to_string());
+    }
+}
@@ -0,0 +1,1204 @@
+use crate::{
+    deflate::Deflater,
+    error::truncated,
+    handler::{Chunk, EchoAction, Lifecycle},
+    handshake,
+    listener::Stream,
+    message::{apply_mask, encode_frame_into, write_header, FrameHeader, Utf8Stream},
+    metrics::{Counted, Counters},
+    rate_limit::RateLimiter,
+    session::{Session, State},
+    CloseReason, Config, ConnectionContext, Decoder, Handshake, Message, MessageHandler,
+    ProtocolError, WsError,
+};
+use log::{debug, info};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Shutdown},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// 分块写回时每次读取的大小
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+// 回复的消息什么时候从缓冲区写到 socket
+// 不管是哪种策略, 收到的数据处理完, 需要等待客户端的新数据之前都会 flush, 回复不会一直留在缓冲区里
+// Sender 推送的消息, ping 和 close 帧总是马上 flush
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    // 每条回复之后 flush, 延迟最低
+    #[default]
+    Immediate,
+    // 积累 n 条回复之后 flush 一次, 客户端连续发送大量小消息时减少系统调用
+    Batched(usize),
+    // 最早的一条没有 flush 的回复超过这个时间之后 flush
+    Timed(Duration),
+}
+
+// 写进了缓冲区, 还没有 flush 的回复
+#[derive(Default)]
+pub(crate) struct Pending {
+    count: usize,
+    since: Option<Instant>,
+}
+
+impl Pending {
+    // 记录一条回复, 返回按照策略是否需要 flush
+    pub(crate) fn push(&mut self, policy: FlushPolicy) -> bool {
+        self.count += 1;
+        let since = *self.since.get_or_insert_with(Instant::now);
+        match policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::Batched(n) => self.count >= n,
+            FlushPolicy::Timed(interval) => since.elapsed() >= interval,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Pending::default();
+    }
+}
+
+// 处理一个连接: 先握手, 再收发消息
+// 握手成功之后不管连接怎么结束都返回 Ok, 其中是结束的原因, 握手完成之前出错时返回 Err
+// stream 是底层的 tcp 或 unix socket 连接, 用来在读数据的线程之外强制断开连接
+// reader 和 writer 由调用方包装, 调用方在连接断开之后还需要这个连接收发的字节数
+// deadline 是握手的截止时间, 由调用方在 PROXY 头和 tls 握手之前计算
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn serve(
+    stream: &Stream,
+    reader: Counted<impl Read>,
+    writer: Counted<impl Write + Send + 'static>,
+    deadline: Option<Instant>,
+    config: &Config,
+    handler: &mut impl MessageHandler,
+    connections: &Connections,
+    ctx: &mut ConnectionContext,
+) -> Result<CloseReason, WsError> {
+    let counters = &connections.counters;
+    Counters::incr(&counters.connections_total);
+    // 客户端可能把握手请求和 frame 一起发送, 握手时 BufReader 读进来的 frame
+    // 还在它的缓冲区里, 之后必须继续使用同一个 reader
+    let transferred = Arc::clone(reader.transferred());
+    let mut reader = BufReader::new(Deadline::new(reader, stream, deadline));
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::new();
+    let (handshake, _limit) = match session.handshake(|| {
+        handshake::accept(
+            &mut reader,
+            &mut writer,
+            config,
+            Some(counters),
+            Some(&connections.limit),
+            ctx.peer_addr.map(|addr| addr.ip()),
+            config.connect_tunnel,
+        )
+    }) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            if let WsError::HandshakeFailed(_) = err {
+                Counters::incr(&counters.handshake_failures);
+            }
+            return Err(err);
+        }
+    };
+    if let Some(client) = handshake.forwarded_for {
+        info!("{} forwarded for {}", ctx, client);
+        ctx.forwarded_for = Some(client);
+    }
+    ctx.protocol.clone_from(&handshake.protocol);
+    let ctx = &*ctx;
+    // 握手之后的截止时间是连接的最长存活时间, 和 read_timeout 同时生效
+    let deadline = reader.get_mut();
+    deadline.deadline = config
+        .max_connection_duration
+        .map(|duration| Instant::now() + duration);
+    deadline.idle_timeout = config.read_timeout;
+    deadline.frame_timeout = config.frame_timeout;
+    // 出错和 panic 时都会减少活跃连接数
+    let _active = counters.open();
+    // 超时之后直接关闭连接, 不会再继续解析读了一半的 frame
+    // read_exact 超时的时候已经读走了一部分数据, 不知道下一个 frame 从哪里开始
+    stream.set_read_timeout(config.read_timeout)?;
+    stream.set_write_timeout(config.write_timeout)?;
+    let connection = Arc::new(Connection {
+        sink: Sink::new(
+            writer,
+            Encoder::new(
+                handshake.deflate.as_ref().map(Deflater::new),
+                config.deflate_min_size,
+            ),
+        ),
+        stream: stream.try_clone()?,
+        activity: Mutex::new(Activity::new()),
+    });
+    let _registration = connections.register(ctx.id, Arc::clone(&connection));
+    handler.on_request(ctx, &handshake.request);
+    handler.on_connect(ctx, &Sender::new(Arc::clone(&connection)));
+    let lifecycle = Lifecycle::new(handler, ctx, &transferred);
+    let result = handle_connection(
+        &mut reader,
+        &mut session,
+        &connection,
+        config,
+        &handshake,
+        lifecycle.handler,
+        ctx,
+        counters,
+    );
+    session.closed();
+    let reason = match result {
+        Ok(reason) => reason,
+        Err(err) => {
+            if let WsError::ProtocolViolation(_) = err {
+                Counters::incr(&counters.protocol_errors);
+            }
+            // 服务端主动回收的连接和 pong 超时的连接, 已经发送了 1001 的 close 帧
+            let expired = reader.get_ref().expired;
+            if expired {
+                info!("{} reached max_connection_duration, closed with 1001", ctx);
+            }
+            // 其他线程可能还持有这个连接的 Sender, 出错时主动关闭 socket, 不等它们释放
+            connection.disconnect();
+            if expired || connection.lock_activity().pong_timed_out {
+                CloseReason::Timeout
+            } else {
+                CloseReason::from_error(&err)
+            }
+        }
+    };
+    lifecycle.finish(reason.clone());
+    Ok(reason)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_connection(
+    reader: &mut BufReader<Deadline<'_, impl Read>>,
+    session: &mut Session,
+    connection: &Connection,
+    config: &Config,
+    handshake: &Handshake,
+    handler: &mut impl MessageHandler,
+    ctx: &ConnectionContext,
+    counters: &Counters,
+) -> Result<CloseReason, WsError> {
+    debug_assert_eq!(session.state(), State::Open);
+    let sink = &connection.sink;
+    let mut decoder = Decoder::new(config, handshake.deflate.as_ref());
+    let mut transform = handler.streams_messages().then(Transform::default);
+    // 分块处理的消息不经过 echo_policy, 只有交给 handler 的消息类型才能分块处理
+    let policy = config.echo_policy;
+    let streaming = Streaming {
+        text: transform.is_some() && policy.text == EchoAction::Echo,
+        binary: (transform.is_some() || handler.echoes_binary())
+            && policy.binary == EchoAction::Echo,
+    };
+    let stream_threshold = config
+        .stream_threshold
+        .filter(|_| streaming.text || streaming.binary);
+    let mut limiter = RateLimiter::new(config.rate_limit.as_ref());
+    // 读 payload 用的内存, 发送回复之后回收回来给下一条消息使用
+    let mut buffer = Vec::new();
+    loop {
+        let message = read_message(
+            reader,
+            &mut decoder,
+            connection,
+            stream_threshold.map(|threshold| (threshold, streaming)),
+            &mut transform,
+            handler,
+            &mut limiter,
+            counters,
+            &mut buffer,
+        );
+        let message = message.and_then(|message| {
+            policy.check(&message)?;
+            let wait = limiter.acquire_message(&message)?;
+            thread::sleep(wait);
+            Ok(message)
+        });
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                // 协议错误需要先发送 close 帧告知客户端原因
+                if let Some(code) = err.close_code() {
+                    session.closing();
+                    sink.close(code)?;
+                }
+                return Err(err);
+            }
+        };
+        connection.lock_activity().received(&message);
+        if policy.action(&message) == EchoAction::Drop {
+            continue;
+        }
+
+        match handle_message(message, handler, ctx) {
+            // 服务端已经主动发送过 close 帧时, 这里是客户端的确认, 不会再回复
+            Reply::Close(code, reason) => {
+                session.closing();
+                let reason = match sink.close_sent() {
+                    Some(sent) => CloseReason::ServerInitiated { code: sent },
+                    None => CloseReason::Normal { code, reason },
+                };
+                sink.send(&Message::Close {
+                    code,
+                    reason: String::new(),
+                })?;
+                return Ok(reason);
+            }
+            Reply::Pong(data) => sink.pong(data),
+            Reply::Send(message) => {
+                sink.send_buffered(&message, config.flush_policy)?;
+                if let Message::Text(_) | Message::Binary(_) = message {
+                    Counters::incr(&counters.messages_echoed);
+                }
+                buffer = message.into_bytes();
+            }
+            Reply::None => {}
+        }
+    }
+}
+
+// 读取下一条需要处理的消息, 分块写回和分块处理的 frame 在这里直接处理掉
+#[allow(clippy::too_many_arguments)]
+fn read_message(
+    reader: &mut BufReader<Deadline<'_, impl Read>>,
+    decoder: &mut Decoder,
+    connection: &Connection,
+    stream_threshold: Option<(u64, Streaming)>,
+    transform: &mut Option<Transform>,
+    handler: &mut impl MessageHandler,
+    limiter: &mut RateLimiter,
+    counters: &Counters,
+    buffer: &mut Vec<u8>,
+) -> Result<Message, WsError> {
+    loop {
+        // 收到的数据都处理完了, 接下来的读操作会等待客户端, 先把缓冲的回复发出去
+        if reader.buffer().is_empty() {
+            connection.sink.flush()?;
+        }
+        // 第一个字节到达之后开始计算 frame_timeout, 等待下一个 frame 只受 read_timeout 限制
+        if reader.get_ref().frame_timeout.is_some() && !reader.fill_buf()?.is_empty() {
+            reader.get_mut().start_frame();
+        }
+        let header = decoder.read_header(reader)?;
+        match stream_threshold {
+            Some((threshold, streaming))
+                if decoder.stream_frame(
+                    &header,
+                    threshold,
+                    streaming.text,
+                    streaming.binary,
+                )? =>
+            {
+                // 分块处理的 frame 不会变成消息, 在这里计算限流
+                let wait = limiter.acquire(header.fin as u32, header.payload_length as u64)?;
+                thread::sleep(wait);
+                match transform {
+                    Some(transform) => {
+                        connection.transform_frame(&header, reader, handler, transform)?
+                    }
+                    None => connection.echo_frame(&header, reader)?,
+                }
+                reader.get_mut().end_frame()?;
+                if header.fin {
+                    Counters::incr(&counters.messages_echoed);
+                }
+            }
+            _ => {
+                let frame = header.read_frame(reader, buffer)?;
+                reader.get_mut().end_frame()?;
+                if let Some(message) = decoder.push_frame(frame, buffer)? {
+                    return Ok(message);
+                }
+            }
+        }
+    }
+}
+
+// 超过 stream_threshold 时哪些类型的消息分块处理
+#[derive(Clone, Copy)]
+struct Streaming {
+    text: bool,
+    binary: bool,
+}
+
+// 正在分块处理的消息, 一个连接同时只有一条
+#[derive(Default)]
+struct Transform {
+    text: bool,
+    utf8: Utf8Stream,
+    // 长度不固定时, 是否已经写过回复的第一个分片, 之后的分片是 continuation
+    started: bool,
+    // on_chunk 的输出, 在多次调用之间复用
+    out: Vec<u8>,
+    // 长度固定时, 已经写了头的回复 frame 还差多少字节没有写
+    unfinished: usize,
+}
+
+// 有截止时间的 reader, 每次读之前把 socket 的读超时设置成剩下的时间
+// 握手阶段的截止时间是 handshake_timeout, 之后是 max_connection_duration
+// 客户端一个字节一个字节地发送也不能超过截止时间
+// 读 frame 的时候还有 frame_timeout 的截止时间, 取两个截止时间中较早的
+pub(crate) struct Deadline<'a, R> {
+    inner: R,
+    stream: &'a Stream,
+    deadline: Option<Instant>,
+    // 握手之后每次读的超时时间 (read_timeout), 设置 socket 的超时时取两者中较短的
+    idle_timeout: Option<Duration>,
+    frame_timeout: Option<Duration>,
+    // 正在读的 frame 需要在这个时间之前收完
+    frame_deadline: Option<Instant>,
+    // 因为到了 max_connection_duration 而读失败, frame_timeout 不算在里面
+    expired: bool,
+}
+
+impl<'a, R> Deadline<'a, R> {
+    pub(crate) fn new(inner: R, stream: &'a Stream, deadline: Option<Instant>) -> Self {
+        Deadline {
+            inner,
+            stream,
+            deadline,
+            idle_timeout: None,
+            frame_timeout: None,
+            frame_deadline: None,
+            expired: false,
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.frame_deadline = self.frame_timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    // 没有连接的截止时间时不会每次读都设置 socket 的超时, 需要改回 read_timeout
+    fn end_frame(&mut self) -> io::Result<()> {
+        if self.frame_deadline.take().is_some() && self.deadline.is_none() {
+            self.stream.set_read_timeout(self.idle_timeout)?;
+        }
+        Ok(())
+    }
+
+    fn connection_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+impl<R: Read> Read for Deadline<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = match (self.deadline, self.frame_deadline) {
+            (Some(deadline), Some(frame_deadline)) => Some(deadline.min(frame_deadline)),
+            (deadline, frame_deadline) => deadline.or(frame_deadline),
+        };
+        let Some(deadline) = deadline else {
+            return self.inner.read(buf);
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            self.expired = self.connection_expired();
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let timeout = self
+            .idle_timeout
+            .map_or(remaining, |idle_timeout| idle_timeout.min(remaining));
+        self.stream.set_read_timeout(Some(timeout))?;
+        let result = self.inner.read(buf);
+        if let Err(err) = &result {
+            let timed_out = matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            );
+            self.expired = timed_out && self.connection_expired();
+        }
+        result
+    }
+}
+
+// 连接的发送端, 读消息的线程, 发送 ping 的线程和关闭服务的线程都会通过它写数据
+// 写操作在锁里完成, 不同线程发送的 frame 不会交错
+pub(crate) struct Sink {
+    inner: Mutex<SinkInner>,
+}
+
+struct SinkInner {
+    writer: Box<dyn Write + Send>,
+    encoder: Encoder,
+    // 已经发送过 close 帧, 之后不能再发送任何数据
+    closed: bool,
+    // 发送过的 close 帧的状态码, 用来区分是哪一方发起的关闭握手
+    close_sent: Option<Option<u16>>,
+    pending: Pending,
+    // 还没有发送的 pong, flush 时先写出去
+    pong: Option<Vec<u8>>,
+}
+
+impl Sink {
+    pub(crate) fn new(writer: impl Write + Send + 'static, encoder: Encoder) -> Self {
+        Sink {
+            inner: Mutex::new(SinkInner {
+                writer: Box::new(writer),
+                encoder,
+                closed: false,
+                close_sent: None,
+                pending: Pending::default(),
+                pong: None,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SinkInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 发送之后马上 flush, 之前缓冲的回复也一起写出去
+    pub(crate) fn send(&self, message: &Message) -> io::Result<()> {
+        self.send_buffered(message, FlushPolicy::Immediate)
+    }
+
+    // 按照 policy 决定是否马上 flush
+    pub(crate) fn send_buffered(&self, message: &Message, policy: FlushPolicy) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.closed {
+            return Ok(());
+        }
+        // close 帧之后不会再有数据, 必须马上发出去
+        let policy = match message {
+            Message::Close { code, .. } => {
+                inner.closed = true;
+                inner.close_sent = Some(*code);
+                FlushPolicy::Immediate
+            }
+            _ => policy,
+        };
+        let SinkInner {
+            writer,
+            encoder,
+            pending,
+            ..
+        } = &mut *inner;
+        let result = writer.write_all(encoder.encode(message)).and_then(|_| {
+            if pending.push(policy) {
+                pending.clear();
+                writer.flush()?;
+            }
+            Ok(())
+        });
+        // 写失败 (包括超时) 时 frame 可能只写了一半, 之后不能再写任何数据
+        if result.is_err() {
+            inner.closed = true;
+        }
+        result
+    }
+
+    // 收到 ping 时调用, 在下一次 flush 时回复, 之前的 ping 还没有回复时直接替换掉
+    // RFC 6455 5.5.3 允许只回复最近的一个 ping, 客户端一次发送大量 ping 时不会得到同样多的 pong
+    pub(crate) fn pong(&self, data: Vec<u8>) {
+        self.lock().pong = Some(data);
+    }
+
+    // 把缓冲的回复和还没有发送的 pong 写出去, 都没有时什么都不做
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.closed {
+            return Ok(());
+        }
+        if let Some(data) = inner.pong.take() {
+            drop(inner);
+            return self.send(&Message::Pong(data));
+        }
+        if inner.pending.is_empty() {
+            return Ok(());
+        }
+        inner.pending.clear();
+        let result = inner.writer.flush();
+        if result.is_err() {
+            inner.closed = true;
+        }
+        result
+    }
+
+    pub(crate) fn close(&self, code: u16) -> io::Result<()> {
+        self.send(&close_message(code))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    fn close_sent(&self) -> Option<Option<u16>> {
+        self.lock().close_sent
+    }
+}
+
+// 一个完成握手的连接
+pub(crate) struct Connection {
+    sink: Sink,
+    stream: Stream,
+    activity: Mutex<Activity>,
+}
+
+impl Connection {
+    fn lock_activity(&self) -> MutexGuard<'_, Activity> {
+        self.activity.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 把客户端的 frame 一块一块地原样写回, 内存占用和 payload 的长度无关
+    // 整个 frame 写完之前一直持有发送端的锁, 其他线程的 frame 不会插在中间
+    fn echo_frame(&self, header: &FrameHeader, reader: &mut impl BufRead) -> Result<(), WsError> {
+        let mut inner = self.sink.lock();
+        let result = self.echo_frame_locked(&mut inner, header, reader);
+        // 和 Sink::send 一样, 写了一半的 frame 之后不能再写数据
+        if let Err(WsError::Io(_)) = result {
+            inner.closed = true;
+        }
+        result
+    }
+
+    fn echo_frame_locked(
+        &self,
+        inner: &mut SinkInner,
+        header: &FrameHeader,
+        reader: &mut impl BufRead,
+    ) -> Result<(), WsError> {
+        let length = header.payload_length;
+        // 已经发送过 close 帧时只读取, 不写回
+        let write = !inner.closed;
+        if write {
+            let mut frame_header = Vec::with_capacity(10);
+            write_header(
+                &mut frame_header,
+                header.fin,
+                false,
+                header.opcode,
+                length as u64,
+            );
+            inner.writer.write_all(&frame_header)?;
+        }
+
+        let mut buffer = [0; STREAM_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < length {
+            let chunk = &mut buffer[..STREAM_CHUNK_SIZE.min(length - offset)];
+            reader.read_exact(chunk).map_err(truncated)?;
+            apply_mask(chunk, header.mask_key, offset);
+            if write {
+                inner.writer.write_all(chunk)?;
+            }
+            offset += chunk.len();
+            self.lock_activity().touch();
+        }
+
+        if write {
+            inner.pending.clear();
+            inner.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // 分块处理客户端的 frame, 每读到一块交给 handler 的 on_chunk, 输出马上写回
+    // 和 echo_frame 一样, 整个 frame 处理完之前一直持有发送端的锁
+    fn transform_frame(
+        &self,
+        header: &FrameHeader,
+        reader: &mut impl BufRead,
+        handler: &mut impl MessageHandler,
+        transform: &mut Transform,
+    ) -> Result<(), WsError> {
+        let mut inner = self.sink.lock();
+        let result = self.transform_frame_locked(&mut inner, header, reader, handler, transform);
+        match result {
+            Err(WsError::Io(_)) => inner.closed = true,
+            // 长度固定时回复的 frame 头已经写出去了, 出错时 (例如 utf8 不合法) 用 0 填满这个 frame,
+            // 之后才能发送 close 帧
+            Err(_) if transform.unfinished > 0 => {
+                let zeros = [0; STREAM_CHUNK_SIZE];
+                while transform.unfinished > 0 {
+                    let size = transform.unfinished.min(zeros.len());
+                    if inner.writer.write_all(&zeros[..size]).is_err() {
+                        inner.closed = true;
+                        break;
+                    }
+                    transform.unfinished -= size;
+                }
+                transform.unfinished = 0;
+            }
+            _ => {}
+        }
+        result
+    }
+
+    fn transform_frame_locked(
+        &self,
+        inner: &mut SinkInner,
+        header: &FrameHeader,
+        reader: &mut impl BufRead,
+        handler: &mut impl MessageHandler,
+        transform: &mut Transform,
+    ) -> Result<(), WsError> {
+        // 新的消息, stream_frame 保证上一条已经结束
+        if header.opcode != 0 {
+            transform.text = header.opcode == 1;
+            transform.utf8 = Utf8Stream::default();
+            transform.started = false;
+        }
+        let length = header.payload_length;
+        // 已经发送过 close 帧时只读取, 不写回
+        let write = !inner.closed;
+        let preserves_length = handler.preserves_length();
+        let mut frame_header = Vec::with_capacity(10);
+        if write && preserves_length {
+            write_header(
+                &mut frame_header,
+                header.fin,
+                false,
+                header.opcode,
+                length as u64,
+            );
+            inner.writer.write_all(&frame_header)?;
+            transform.unfinished = length;
+        }
+
+        let mut buffer = [0; STREAM_CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let chunk = &mut buffer[..STREAM_CHUNK_SIZE.min(length - offset)];
+            reader.read_exact(chunk).map_err(truncated)?;
+            apply_mask(chunk, header.mask_key, offset);
+            offset += chunk.len();
+            self.lock_activity().touch();
+
+            let last = header.fin && offset == length;
+            if transform.text {
+                transform.utf8.push(chunk)?;
+                if last {
+                    transform.utf8.finish()?;
+                }
+            }
+            // 空的中间分片没有需要处理的数据
+            if !chunk.is_empty() || last {
+                transform.out.clear();
+                let text = transform.text;
+                handler.on_chunk(
+                    Chunk {
+                        text,
+                        data: chunk,
+                        last,
+                    },
+                    &mut transform.out,
+                );
+                // handler 没有遵守 preserves_length, 属于服务端的错误, 用 1011 关闭
+                if preserves_length && transform.out.len() != chunk.len() {
+                    return Err(ProtocolError::new(
+                        1011,
+                        "on_chunk changed the length while preserves_length is true",
+                    )
+                    .into());
+                }
+                if write && preserves_length {
+                    inner.writer.write_all(&transform.out)?;
+                    transform.unfinished -= transform.out.len();
+                } else if write && (last || !transform.out.is_empty()) {
+                    let opcode = match (transform.started, text) {
+                        (true, _) => 0,
+                        (false, true) => 1,
+                        (false, false) => 2,
+                    };
+                    transform.started = true;
+                    frame_header.clear();
+                    write_header(
+                        &mut frame_header,
+                        last,
+                        false,
+                        opcode,
+                        transform.out.len() as u64,
+                    );
+                    inner.writer.write_all(&frame_header)?;
+                    inner.writer.write_all(&transform.out)?;
+                }
+            }
+            if offset == length {
+                break;
+            }
+        }
+
+        if write {
+            inner.pending.clear();
+            inner.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // 空闲的连接发送 ping, ping 超时没有收到 pong 时关闭连接
+    fn keepalive(&self, ping_interval: Duration, pong_timeout: Duration) {
+        let now = Instant::now();
+        let mut activity = self.lock_activity();
+        match activity.ping_sent {
+            Some(ping_sent) if now - ping_sent >= pong_timeout => {
+                activity.pong_timed_out = true;
+                drop(activity);
+                let _ = self.sink.close(1001);
+                // 对方多半已经断开, 不会回复 close 帧, 直接关闭 socket 让读数据的线程返回
+                self.disconnect();
+            }
+            None if now - activity.last_received >= ping_interval => {
+                activity.ping_sent = Some(now);
+                drop(activity);
+                let _ = self.send(&Message::Ping(Vec::new()));
+            }
+            _ => {}
+        }
+    }
+
+    // 在读数据的线程之外发送, 写失败时断开连接, 让读数据的线程返回
+    fn send(&self, message: &Message) -> io::Result<()> {
+        let result = self.sink.send(message);
+        if result.is_err() {
+            self.disconnect();
+        }
+        result
+    }
+
+    // 不需要拿到发送端的锁, 正在阻塞的读写操作会马上返回错误
+    fn disconnect(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// 在 handler 之外给连接发送消息, 可以 clone 之后交给其他线程
+// 和回复的消息经过同一个发送端, frame 不会交错
+#[derive(Clone)]
+pub struct Sender {
+    inner: SenderInner,
+}
+
+#[derive(Clone)]
+enum SenderInner {
+    Sync(Arc<Connection>),
+    // 异步的连接在 task 里写数据, 这里只是放进队列
+    #[cfg(feature = "tokio")]
+    Async {
+        queue: tokio::sync::mpsc::Sender<Message>,
+        disconnect: Arc<tokio::sync::Notify>,
+    },
+}
+
+impl Sender {
+    pub(crate) fn new(connection: Arc<Connection>) -> Self {
+        Sender {
+            inner: SenderInner::Sync(connection),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn new_async(
+        queue: tokio::sync::mpsc::Sender<Message>,
+        disconnect: Arc<tokio::sync::Notify>,
+    ) -> Self {
+        Sender {
+            inner: SenderInner::Async { queue, disconnect },
+        }
+    }
+
+    // Server 的连接直接写到 socket, 对方不接收数据时最多阻塞 write_timeout, 超时之后断开连接
+    // AsyncServer 的连接放进有长度限制的队列, 不会阻塞, 队列满了说明客户端接收得太慢, 直接断开连接
+    // 连接已经关闭, 发送过 close 帧或者因为队列满了被断开时返回 ConnectionClosed
+    pub fn send(&self, message: Message) -> Result<(), WsError> {
+        match &self.inner {
+            SenderInner::Sync(connection) => {
+                if connection.sink.is_closed() {
+                    return Err(WsError::ConnectionClosed);
+                }
+                Ok(connection.send(&message)?)
+            }
+            #[cfg(feature = "tokio")]
+            SenderInner::Async { queue, disconnect } => {
+                use tokio::sync::mpsc::error::TrySendError;
+                queue.try_send(message).map_err(|err| {
+                    if let TrySendError::Full(_) = err {
+                        disconnect.notify_one();
+                    }
+                    WsError::ConnectionClosed
+                })
+            }
+        }
+    }
+
+    // 发送 close 帧, 等待客户端确认之后连接结束
+    pub fn close(&self, code: u16) -> Result<(), WsError> {
+        self.send(close_message(code))
+    }
+
+    // 不发送 close 帧, 直接断开连接, 用来丢弃不再响应的客户端
+    pub fn disconnect(&self) {
+        match &self.inner {
+            SenderInner::Sync(connection) => connection.disconnect(),
+            #[cfg(feature = "tokio")]
+            SenderInner::Async { disconnect, .. } => disconnect.notify_one(),
+        }
+    }
+}
+
+// 连接上收发数据的时间
+struct Activity {
+    last_received: Instant,
+    last_pong: Option<Instant>,
+    // 已经发送, 还没有收到 pong 的 ping
+    ping_sent: Option<Instant>,
+    // 因为没有收到 pong 被断开
+    pong_timed_out: bool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            last_received: Instant::now(),
+            last_pong: None,
+            ping_sent: None,
+            pong_timed_out: false,
+        }
+    }
+
+    // ping 之后收到任何数据都说明连接还活着, 不用再等 pong, 空闲之后重新发送 ping
+    fn touch(&mut self) {
+        self.last_received = Instant::now();
+        self.ping_sent = None;
+    }
+
+    fn received(&mut self, message: &Message) {
+        self.touch();
+        if let Message::Pong(_) = message {
+            self.last_pong = Some(self.last_received);
+        }
+    }
+}
+
+// 握手成功, 还没有结束的连接数量, 用来检查 max_connections 和 max_connections_per_ip
+#[derive(Default)]
+pub(crate) struct ConnectionLimit {
+    active: AtomicUsize,
+    // 每个 ip 的连接数量, 减到 0 时删除, 只保存还有连接在线的 ip
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimit {
+    // 握手时在回复 101 之前占用一个名额, 满了时返回拒绝的原因
+    // 检查和加一在同一个锁里完成, 同时进行的握手和紧接着的下一个握手都不会超过上限
+    // 返回的 guard 释放时 (包括 panic) 减一, 没有设置 max_connections_per_ip 时不按 ip 记录
+    pub(crate) fn try_open(
+        &self,
+        ip: Option<IpAddr>,
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Result<LimitGuard<'_>, &'static str> {
+        let ip = max_connections_per_ip.and(ip);
+        let mut per_ip = self.lock_per_ip();
+        if max_connections.is_some_and(|max| self.active.load(Ordering::Relaxed) >= max) {
+            return Err("too many connections");
+        }
+        if let (Some(ip), Some(max)) = (ip, max_connections_per_ip) {
+            if per_ip.get(&ip).is_some_and(|&active| active >= max) {
+                return Err("too many connections from this address");
+            }
+        }
+        self.active.fetch_add(1, Ordering::Relaxed);
+        if let Some(ip) = ip {
+            *per_ip.entry(ip).or_default() += 1;
+        }
+        Ok(LimitGuard { limit: self, ip })
+    }
+
+    fn lock_per_ip(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.per_ip.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) struct LimitGuard<'a> {
+    limit: &'a ConnectionLimit,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for LimitGuard<'_> {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ip) = self.ip {
+            let mut per_ip = self.limit.lock_per_ip();
+            if let Some(active) = per_ip.get_mut(&ip) {
+                *active -= 1;
+                if *active == 0 {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+// 正在处理的连接, 关闭服务时用来通知所有的客户端
+#[derive(Default)]
+pub(crate) struct Connections {
+    closing: AtomicBool,
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<Connection>>>,
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) limit: ConnectionLimit,
+}
+
+impl Connections {
+    // 接受连接时分配 id, 从 1 开始
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn register(&self, id: u64, connection: Arc<Connection>) -> Registration<'_> {
+        self.lock().insert(id, Arc::clone(&connection));
+        // 握手期间服务开始关闭, 这个连接没有收到 close 帧
+        if self.is_closing() {
+            let _ = connection.sink.close(1001);
+        }
+        Registration {
+            connections: self,
+            id,
+        }
+    }
+
+    // 不再接受新连接, 给所有连接发送 close 帧, 返回当时的连接数量
+    pub(crate) fn close_all(&self, code: u16) -> usize {
+        self.closing.store(true, Ordering::SeqCst);
+        let connections = self.snapshot();
+        for connection in &connections {
+            let _ = connection.sink.close(code);
+        }
+        connections.len()
+    }
+
+    // 直接断开所有连接, 不发送 close 帧, 返回这些连接的 id
+    pub(crate) fn disconnect_all(&self) -> Vec<u64> {
+        let mut connections: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(id, connection)| (*id, Arc::clone(connection)))
+            .collect();
+        connections.sort_unstable_by_key(|(id, _)| *id);
+        connections
+            .into_iter()
+            .map(|(id, connection)| {
+                connection.disconnect();
+                id
+            })
+            .collect()
+    }
+
+    // 检查所有连接的 ping 和 pong
+    pub(crate) fn keepalive(&self, ping_interval: Duration, pong_timeout: Duration) {
+        for connection in self.snapshot() {
+            connection.keepalive(ping_interval, pong_timeout);
+        }
+    }
+
+    // 发送可能阻塞, 不能在持有锁的时候写数据, 先复制一份再逐个处理
+    fn snapshot(&self) -> Vec<Arc<Connection>> {
+        self.lock().values().cloned().collect()
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Arc<Connection>>> {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// 连接结束时 (包括 panic) 从 Connections 中移除
+pub(crate) struct Registration<'a> {
+    connections: &'a Connections,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+    }
+}
+
+// 对一条消息的响应
+pub(crate) enum Reply {
+    // 不需要回复
+    None,
+    // 发送一条消息
+    Send(Message),
+    // 回复 ping, 收到的数据处理完之后才发送, 期间收到的多个 ping 只回复最后一个
+    Pong(Vec<u8>),
+    // 用这个状态码回复 close 帧, 然后结束连接, None 时回复空的 close 帧
+    // 同时带着客户端 close 帧里的原因, 交给 on_disconnect
+    Close(Option<u16>, String),
+}
+
+// 同步和异步的连接共用的消息处理逻辑
+pub(crate) fn handle_message(
+    message: Message,
+    handler: &mut impl MessageHandler,
+    ctx: &ConnectionContext,
+) -> Reply {
+    // 只记录类型和长度, 不打印消息内容
+    debug!(
+        "{} received message opcode={} length={}",
+        ctx,
+        message.opcode(),
+        message.len()
+    );
+    match message {
+        // 收到 close 帧, 回复一个 close 帧完成关闭握手, 然后结束连接
+        // 客户端没有带状态码时回复的 close 帧也不带状态码
+        Message::Close { code, reason } => Reply::Close(code, reason),
+        // ping 需要回复携带相同数据的 pong
+        Message::Ping(data) => Reply::Pong(data),
+        // 客户端的 pong 直接忽略
+        Message::Pong(_) => Reply::None,
+        message => match handler.on_message(message) {
+            Some(reply) => Reply::Send(reply),
+            None => Reply::None,
+        },
+    }
+}
+
+// 编码发送的消息, 在多次编码之间复用内存
+pub(crate) struct Encoder {
+    deflater: Option<Deflater>,
+    // 短于这个长度的消息不压缩, rsv1 是 0
+    min_size: usize,
+    frame: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new(deflater: Option<Deflater>, min_size: usize) -> Self {
+        Encoder {
+            deflater,
+            min_size,
+            frame: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+
+    // 返回的 frame 在下一次编码之前有效
+    pub(crate) fn encode(&mut self, message: &Message) -> &[u8] {
+        match (message, &mut self.deflater) {
+            // 协商了压缩扩展时数据消息压缩之后发送, 并设置 rsv1
+            // 压缩的上下文不受影响, 客户端也不会把没有压缩的消息放进解压的窗口
+            (Message::Text(_) | Message::Binary(_), Some(deflater))
+                if message.len() >= self.min_size =>
+            {
+                deflater.deflate(&message.as_bytes(), &mut self.compressed);
+                encode_frame_into(message.opcode(), true, &self.compressed, &mut self.frame);
+            }
+            _ => message.encode_into(&mut self.frame),
+        }
+        &self.frame
+    }
+}
+
+pub(crate) fn close_message(code: u16) -> Message {
+    Message::Close {
+        code: Some(code),
+        reason: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // 测试里读出 Sink 写出的数据
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        // 取出写出的数据
+        fn take(&self) -> Vec<u8> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    fn sink() -> (Sink, Shared) {
+        let written = Shared::default();
+        (Sink::new(written.clone(), Encoder::new(None, 0)), written)
+    }
+
+    // 服务端发送的 frame 没有 mask, 和 Message::encode 的结果一样
+    fn encoded(messages: &[Message]) -> Vec<u8> {
+        messages.iter().flat_map(Message::encode).collect()
+    }
+
+    // 一次 flush 之前收到的大量 ping 只回复最后一个
+    #[test]
+    fn pongs_are_coalesced() {
+        let (sink, written) = sink();
+        for i in 0..1000u16 {
+            sink.pong(i.to_be_bytes().to_vec());
+        }
+        assert!(written.take().is_empty());
+        sink.flush().unwrap();
+        assert_eq!(
+            written.take(),
+            encoded(&[Message::Pong(999u16.to_be_bytes().to_vec())])
+        );
+        // 已经回复过, 下一次 flush 没有数据
+        sink.flush().unwrap();
+        assert!(written.take().is_empty());
+
+        sink.pong(b"again".to_vec());
+        sink.flush().unwrap();
+        assert_eq!(written.take(), encoded(&[Message::Pong(b"again".to_vec())]));
+    }
+
+    // 缓冲的回复在 pong 之前写出, 顺序和收到的消息一致
+    #[test]
+    fn pong_after_buffered_replies() {
+        let (sink, written) = sink();
+        let text = Message::Text("hello".into());
+        sink.send_buffered(&text, FlushPolicy::Batched(16)).unwrap();
+        sink.pong(b"ping".to_vec());
+        sink.flush().unwrap();
+        assert_eq!(
+            written.take(),
+            encoded(&[text, Message::Pong(b"ping".to_vec())])
+        );
+    }
+
+    // 发送 close 帧之后不再回复 pong
+    #[test]
+    fn no_pong_after_close() {
+        let (sink, written) = sink();
+        sink.pong(b"ping".to_vec());
+        sink.close(1000).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(written.take(), encoded(&[close_message(1000)]));
+    }
+}
@@ -0,0 +1,443 @@
+// permessage-deflate 扩展 (RFC 7692)
+use crate::{
+    extensions::{self, Extension, ExtensionHandler},
+    ProtocolError, WsError,
+};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+// 每条压缩消息末尾被去掉的 4 个字节
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// deflate 允许的最大窗口
+const MAX_WINDOW_BITS: u8 = 15;
+// zlib 的 raw deflate 不支持 8 位的窗口, 客户端要求 8 位时拒绝这个 offer
+const MIN_WINDOW_BITS: u8 = 9;
+
+// 协商出来的参数
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateParams {
+    // 服务端每条消息之后重置压缩器
+    pub server_no_context_takeover: bool,
+    // 客户端每条消息之后重置压缩器, 服务端对应的重置解压器
+    pub client_no_context_takeover: bool,
+    // 服务端压缩时使用的窗口大小 (以 2 为底的对数), 客户端的解压器只保留这么多历史数据
+    pub server_max_window_bits: u8,
+}
+
+impl Default for DeflateParams {
+    fn default() -> Self {
+        DeflateParams {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+impl DeflateParams {
+    // 在客户端的多个 offer 中选择第一个可以接受的, 返回参数和响应头的值
+    pub fn negotiate(header: &str) -> Option<(DeflateParams, String)> {
+        let response = extensions::parse(header)
+            .iter()
+            .filter(|offer| offer.name == PermessageDeflate.name())
+            .find_map(|offer| PermessageDeflate.accept(offer))?;
+        let response = Extension {
+            name: PermessageDeflate.name().to_string(),
+            params: response,
+        };
+        Some((
+            DeflateParams::from_response(&response),
+            response.to_string(),
+        ))
+    }
+
+    // 服务端接受的响应里带着协商出来的参数
+    pub(crate) fn from_response(response: &Extension) -> DeflateParams {
+        DeflateParams {
+            server_no_context_takeover: response.param("server_no_context_takeover").is_some(),
+            client_no_context_takeover: response.param("client_no_context_takeover").is_some(),
+            server_max_window_bits: response
+                .param("server_max_window_bits")
+                .flatten()
+                .and_then(parse_window_bits)
+                .unwrap_or(MAX_WINDOW_BITS),
+        }
+    }
+}
+
+// 内置的 permessage-deflate, 开启 Config::permessage_deflate 时参与协商
+pub(crate) struct PermessageDeflate;
+
+impl ExtensionHandler for PermessageDeflate {
+    fn name(&self) -> &str {
+        "permessage-deflate"
+    }
+
+    fn accept(&self, offer: &Extension) -> Option<Vec<(String, Option<String>)>> {
+        let mut response = Vec::new();
+        let mut seen = Vec::new();
+
+        for (name, value) in &offer.params {
+            let (name, value) = (name.as_str(), value.as_deref());
+            // 同一个参数出现多次, 拒绝这个 offer
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => {
+                    response.push((name.to_string(), None));
+                }
+                // 客户端的解压器只保留这么多历史数据, 压缩器必须使用不超过这个大小的窗口
+                ("server_max_window_bits", Some(bits)) => {
+                    if parse_window_bits(bits)? < MIN_WINDOW_BITS {
+                        return None;
+                    }
+                    response.push((name.to_string(), Some(bits.to_string())));
+                }
+                // 解压器使用 15 位的窗口, 可以处理客户端使用的任意窗口大小, 不需要回复
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) => {
+                    parse_window_bits(bits)?;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(response)
+    }
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    // 不允许前导 0 之类的写法
+    if bits.starts_with('0') {
+        return None;
+    }
+    bits.parse()
+        .ok()
+        .filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits))
+}
+
+// 解压客户端发来的消息
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover: params.client_no_context_takeover,
+        }
+    }
+
+    // 解压一条完整的消息, 解压后的长度超过 max_length 时返回 1009
+    pub fn inflate(&mut self, data: &[u8], max_length: u64) -> Result<Vec<u8>, WsError> {
+        let input = [data, &TRAILER].concat();
+        let mut output = Vec::with_capacity(data.len() * 2 + 64);
+        let mut consumed = 0;
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+            let total_in = self.decompress.total_in();
+            let output_length = output.len();
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| ProtocolError::new(1007, "invalid deflate data"))?;
+            consumed += (self.decompress.total_in() - total_in) as usize;
+
+            if output.len() as u64 > max_length {
+                return Err(ProtocolError::new(1009, "message too big").into());
+            }
+            // 客户端设置了 BFINAL, 压缩流已经结束, 之后的消息需要新的上下文
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(output);
+            }
+            // 输入都消费完并且输出还有空间, 说明已经解压完毕
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+            // 还有输入和输出空间却没有任何进展, 数据是损坏的
+            if consumed < input.len()
+                && output.len() == output_length
+                && output.len() < output.capacity()
+                && self.decompress.total_in() == total_in
+            {
+                return Err(ProtocolError::new(1007, "invalid deflate data").into());
+            }
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+// 压缩发给客户端的消息
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Deflater {
+            // DeflateParams 可以由调用方构造, 超出范围的值会让 flate2 panic
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params
+                    .server_max_window_bits
+                    .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS),
+            ),
+            no_context_takeover: params.server_no_context_takeover,
+        }
+    }
+
+    // 压缩的结果写到 output 中, output 原有的内容会被清空
+    pub fn deflate(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        output.clear();
+        output.reserve(data.len() / 2 + 64);
+        let mut consumed = 0;
+
+        loop {
+            if output.capacity() - output.len() < 64 {
+                output.reserve(output.capacity());
+            }
+            let total_in = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[consumed..], output, FlushCompress::Sync)
+                .expect("deflate never fails on in-memory data");
+            consumed += (self.compress.total_in() - total_in) as usize;
+
+            // sync flush 完成时输出不会填满缓冲区
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        // sync flush 的结尾固定是 00 00 ff ff, 按规范去掉
+        if output.ends_with(&TRAILER) {
+            output.truncate(output.len() - TRAILER.len());
+        }
+
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 重复很多的文本, 压缩之后应该远小于原来的长度
+    fn repetitive(length: usize) -> Vec<u8> {
+        b"echo echo echo, hello websocket! "
+            .iter()
+            .copied()
+            .cycle()
+            .take(length)
+            .collect()
+    }
+
+    // 按顺序压缩和解压每一条消息, 返回每条消息压缩后的长度
+    fn round_trip(params: &DeflateParams, messages: &[Vec<u8>]) -> Vec<usize> {
+        let mut deflater = Deflater::new(params);
+        let mut inflater = Inflater::new(params);
+        let mut compressed = Vec::new();
+        messages
+            .iter()
+            .map(|message| {
+                deflater.deflate(message, &mut compressed);
+                assert!(!compressed.ends_with(&TRAILER));
+                assert_eq!(&inflater.inflate(&compressed, u64::MAX).unwrap(), message);
+                compressed.len()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repetitive_payload_round_trips() {
+        let sizes = round_trip(&DeflateParams::default(), &[repetitive(64 * 1024)]);
+        assert!(sizes[0] < 64 * 1024 / 20, "{:?}", sizes);
+        // 空消息和很短的消息也一样
+        round_trip(&DeflateParams::default(), &[Vec::new(), b"a".to_vec()]);
+    }
+
+    // 默认保留上下文, 后面的相同消息可以引用前面的数据, 压缩后更短
+    #[test]
+    fn context_takeover_across_messages() {
+        let messages = vec![repetitive(4096); 3];
+        let sizes = round_trip(&DeflateParams::default(), &messages);
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+
+        // 两边都不保留上下文时每条消息单独压缩, 长度都一样
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            ..DeflateParams::default()
+        };
+        let sizes = round_trip(&params, &messages);
+        assert!(sizes.iter().all(|&size| size == sizes[0]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn invalid_data_is_1007() {
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        match inflater.inflate(&[0xff; 16], u64::MAX) {
+            Err(WsError::ProtocolViolation(err)) => assert_eq!(err.code, 1007),
+            _ => panic!("expected 1007"),
+        }
+    }
+
+    // 解压之后超过长度限制, 不会解压出完整的消息
+    #[test]
+    fn inflated_length_is_limited() {
+        let mut compressed = Vec::new();
+        Deflater::new(&DeflateParams::default()).deflate(&repetitive(1 << 20), &mut compressed);
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        match inflater.inflate(&compressed, 1024) {
+            Err(WsError::ProtocolViolation(err)) => assert_eq!(err.code, 1009),
+            _ => panic!("expected 1009"),
+        }
+    }
+
+    #[test]
+    fn negotiate_params() {
+        let (params, response) = DeflateParams::negotiate(
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+            server_max_window_bits=10; client_max_window_bits",
+        )
+        .unwrap();
+        assert!(params.server_no_context_takeover);
+        assert!(params.client_no_context_takeover);
+        assert_eq!(params.server_max_window_bits, 10);
+        // 没有值的 client_max_window_bits 不需要回复
+        assert_eq!(
+            response,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+            server_max_window_bits=10"
+        );
+
+        let (params, response) = DeflateParams::negotiate("permessage-deflate").unwrap();
+        assert!(!params.server_no_context_takeover && !params.client_no_context_takeover);
+        assert_eq!(params.server_max_window_bits, 15);
+        assert_eq!(response, "permessage-deflate");
+    }
+
+    // 不能接受的 offer 被跳过, 使用客户端的下一个 offer
+    #[test]
+    fn unacceptable_offers_fall_back() {
+        let declined = [
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; unknown_param",
+        ];
+        for offer in declined {
+            assert!(DeflateParams::negotiate(offer).is_none(), "{}", offer);
+            let fallback = format!("{}, permessage-deflate; client_no_context_takeover", offer);
+            let (params, response) = DeflateParams::negotiate(&fallback).unwrap();
+            assert!(params.client_no_context_takeover, "{}", offer);
+            assert_eq!(response, "permessage-deflate; client_no_context_takeover");
+        }
+    }
+
+    // server_no_context_takeover: 每条消息之后重置压缩器, 客户端用新的解压器也能解压每一条
+    #[test]
+    fn server_no_context_takeover_resets_deflater() {
+        let message = repetitive(4096);
+        let compress_twice = |params: &DeflateParams| {
+            let mut deflater = Deflater::new(params);
+            let mut first = Vec::new();
+            let mut second = Vec::new();
+            deflater.deflate(&message, &mut first);
+            deflater.deflate(&message, &mut second);
+            second
+        };
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            ..DeflateParams::default()
+        };
+        let second = compress_twice(&params);
+        let inflated = Inflater::new(&params).inflate(&second, u64::MAX).unwrap();
+        assert_eq!(inflated, message);
+        // 保留上下文时第二条消息引用了第一条的数据, 新的解压器不能得到原来的内容
+        let second = compress_twice(&DeflateParams::default());
+        let inflated = Inflater::new(&DeflateParams::default()).inflate(&second, u64::MAX);
+        assert!(inflated.map_or(true, |inflated| inflated != message));
+    }
+
+    // client_no_context_takeover: 客户端每条消息用新的压缩器, 服务端每条消息之后重置解压器
+    // 没有协商时客户端的后续消息依赖之前的数据, 解压器不能重置
+    #[test]
+    fn client_context_takeover_is_honored() {
+        let messages = [repetitive(4096), repetitive(3000), repetitive(4096)];
+        let params = DeflateParams {
+            client_no_context_takeover: true,
+            ..DeflateParams::default()
+        };
+        let mut inflater = Inflater::new(&params);
+        let mut compressed = Vec::new();
+        for message in &messages {
+            Deflater::new(&DeflateParams::default()).deflate(message, &mut compressed);
+            assert_eq!(&inflater.inflate(&compressed, u64::MAX).unwrap(), message);
+        }
+
+        let mut deflater = Deflater::new(&DeflateParams::default());
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        for message in &messages {
+            deflater.deflate(message, &mut compressed);
+            assert_eq!(&inflater.inflate(&compressed, u64::MAX).unwrap(), message);
+        }
+    }
+
+    // 压缩器的窗口不超过 server_max_window_bits, 超出窗口的重复数据不能引用
+    #[test]
+    fn server_max_window_bits_limits_window() {
+        // 没有规律的 4096 字节重复三次, 只有距离 4096 的重复可以压缩
+        let mut seed = 1u32;
+        let block: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let message = block.repeat(3);
+        let compressed_length = |bits: u8| {
+            let params = DeflateParams {
+                server_max_window_bits: bits,
+                ..DeflateParams::default()
+            };
+            let mut compressed = Vec::new();
+            Deflater::new(&params).deflate(&message, &mut compressed);
+            assert_eq!(
+                Inflater::new(&params)
+                    .inflate(&compressed, u64::MAX)
+                    .unwrap(),
+                message
+            );
+            compressed.len()
+        };
+        // 4096 字节的窗口 (12 位) 刚好够不到, 13 位以上可以引用前一块
+        for bits in MIN_WINDOW_BITS..=12 {
+            assert!(compressed_length(bits) > message.len() * 9 / 10, "{}", bits);
+        }
+        for bits in 13..=MAX_WINDOW_BITS {
+            assert!(compressed_length(bits) < message.len() / 2, "{}", bits);
+        }
+    }
+}
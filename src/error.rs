@@ -0,0 +1,85 @@
+use crate::{HandshakeError, ProtocolError};
+use std::{error::Error, fmt, io};
+
+// 库里所有操作返回的错误
+#[derive(Debug)]
+pub enum WsError {
+    // 读写连接失败
+    Io(io::Error),
+    // 握手请求不合法, 已经给客户端返回了错误的 http 响应
+    HandshakeFailed(HandshakeError),
+    // 客户端违反了协议, 需要用其中的状态码发送 close 帧
+    ProtocolViolation(ProtocolError),
+    // 客户端在 frame 的边界上断开了 tcp 连接
+    ConnectionClosed,
+    // 客户端在 frame 的中间 (头部或者 payload 没有读完) 断开了连接, 相当于 1006 (abnormal closure)
+    FrameTruncated,
+}
+
+impl WsError {
+    // 关闭连接时应该使用的 close 状态码
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            WsError::ProtocolViolation(err) => Some(err.code),
+            // 读超时, 客户端太久没有发送数据
+            WsError::Io(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Some(1001)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Io(err) => write!(f, "io error: {}", err),
+            WsError::HandshakeFailed(err) => err.fmt(f),
+            WsError::ProtocolViolation(err) => err.fmt(f),
+            WsError::ConnectionClosed => write!(f, "connection closed"),
+            WsError::FrameTruncated => write!(f, "connection closed in the middle of a frame"),
+        }
+    }
+}
+
+impl Error for WsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WsError::Io(err) => Some(err),
+            WsError::HandshakeFailed(err) => Some(err),
+            WsError::ProtocolViolation(err) => Some(err),
+            WsError::ConnectionClosed | WsError::FrameTruncated => None,
+        }
+    }
+}
+
+// 已经读到了 frame 的一部分之后遇到 EOF, 其他错误不变
+pub(crate) fn truncated(err: io::Error) -> WsError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => WsError::FrameTruncated,
+        _ => WsError::Io(err),
+    }
+}
+
+impl From<io::Error> for WsError {
+    fn from(err: io::Error) -> Self {
+        WsError::Io(err)
+    }
+}
+
+impl From<HandshakeError> for WsError {
+    fn from(err: HandshakeError) -> Self {
+        WsError::HandshakeFailed(err)
+    }
+}
+
+impl From<ProtocolError> for WsError {
+    fn from(err: ProtocolError) -> Self {
+        WsError::ProtocolViolation(err)
+    }
+}
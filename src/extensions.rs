@@ -0,0 +1,246 @@
+// Sec-WebSocket-Extensions 的协商 (RFC 6455 9.1)
+// 解析客户端提供的扩展列表, 交给注册的 handler 决定是否接受, 响应头里只包含接受的扩展
+use std::fmt;
+
+// 旧版 Safari 等客户端使用的逐帧压缩扩展, 和 permessage-deflate 一样占用 rsv1 但是压缩方式不兼容
+// 即使注册了同名的 handler 也不接受
+const LEGACY_DEFLATE: [&str; 2] = ["x-webkit-deflate-frame", "deflate-frame"];
+
+// 一个扩展和它的参数, 既用来表示客户端的 offer, 也用来表示服务端的响应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub name: String,
+    // 参数按出现的顺序排列, 没有值的参数是 None, 带引号的值已经去掉了引号
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl Extension {
+    // 第一个同名参数, 参数存在但没有值时返回 Some(None)
+    pub fn param(&self, name: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_deref())
+    }
+}
+
+// 响应头里的格式, 参数的值都是 token, 不需要加引号
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for (name, value) in &self.params {
+            match value {
+                Some(value) => write!(f, "; {}={}", name, value)?,
+                None => write!(f, "; {}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 服务端支持的一个扩展, 通过 ServerBuilder::extension 注册
+// 扩展只参与握手的协商, frame 的 rsv 位目前只有 permessage-deflate 可以使用
+pub trait ExtensionHandler: Send + Sync {
+    // 扩展的名字, 和 offer 的名字完全相同时才会调用 accept
+    fn name(&self) -> &str;
+
+    // 接受这个 offer 时返回响应里的参数, 返回 None 时继续看客户端的下一个同名 offer
+    fn accept(&self, offer: &Extension) -> Option<Vec<(String, Option<String>)>>;
+}
+
+// 解析客户端的 Sec-WebSocket-Extensions, 语法错误的 offer 被忽略
+pub(crate) fn parse(header: &str) -> Vec<Extension> {
+    split_outside_quotes(header, ',')
+        .into_iter()
+        .map(str::trim)
+        // http 的列表允许空的元素, 例如 "a, , b"
+        .filter(|offer| !offer.is_empty())
+        .filter_map(parse_offer)
+        .collect()
+}
+
+// 按客户端 offer 的顺序依次交给同名的 handler, 每个扩展最多接受一个 offer
+// 返回接受的扩展, 顺序和客户端的 offer 一致, 也就是响应头里的顺序
+pub(crate) fn negotiate(header: &str, handlers: &[&dyn ExtensionHandler]) -> Vec<Extension> {
+    let mut accepted: Vec<Extension> = Vec::new();
+    for offer in parse(header) {
+        if accepted
+            .iter()
+            .any(|extension| extension.name == offer.name)
+        {
+            continue;
+        }
+        if LEGACY_DEFLATE.contains(&offer.name.as_str()) {
+            log::debug!("declined legacy extension {}", offer.name);
+            continue;
+        }
+        let Some(handler) = handlers.iter().find(|handler| handler.name() == offer.name) else {
+            continue;
+        };
+        if let Some(params) = handler.accept(&offer) {
+            accepted.push(Extension {
+                name: offer.name,
+                params,
+            });
+        }
+    }
+    accepted
+}
+
+// 响应头的值, 多个扩展用逗号分隔
+pub(crate) fn to_header(extensions: &[Extension]) -> String {
+    extensions
+        .iter()
+        .map(Extension::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_offer(offer: &str) -> Option<Extension> {
+    let mut parts = split_outside_quotes(offer, ';').into_iter().map(str::trim);
+    let name = parts.next().filter(|name| is_token(name))?;
+    let mut params = Vec::new();
+    for param in parts {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim_end(), Some(parse_value(value.trim_start())?)),
+            None => (param, None),
+        };
+        if !is_token(name) {
+            return None;
+        }
+        params.push((name.to_string(), value));
+    }
+    Some(Extension {
+        name: name.to_string(),
+        params,
+    })
+}
+
+// 参数的值是 token 或者 quoted-string, 去掉引号之后也必须是 token
+fn parse_value(value: &str) -> Option<String> {
+    let value = match value.strip_prefix('"') {
+        Some(quoted) => unescape(quoted.strip_suffix('"')?)?,
+        None => value.to_string(),
+    };
+    is_token(&value).then_some(value)
+}
+
+// 去掉 quoted-string 里的反斜杠转义, 结尾是单独的反斜杠时返回 None
+fn unescape(quoted: &str) -> Option<String> {
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            '"' => return None,
+            c => value.push(c),
+        }
+    }
+    Some(value)
+}
+
+// 按分隔符切分, 引号里的分隔符不算
+fn split_outside_quotes(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+// http 的 token (RFC 7230 3.2.6)
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::PermessageDeflate;
+
+    // 接受任何 offer, 原样回复参数
+    struct AcceptAll(&'static str);
+
+    impl ExtensionHandler for AcceptAll {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn accept(&self, offer: &Extension) -> Option<Vec<(String, Option<String>)>> {
+            Some(offer.params.clone())
+        }
+    }
+
+    fn names(extensions: &[Extension]) -> Vec<&str> {
+        extensions
+            .iter()
+            .map(|extension| extension.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parse_offers() {
+        let offers = parse(
+            "permessage-deflate; client_max_window_bits, , \
+            x-webkit-deflate-frame; no_context_takeover, foo; bar=\"ba\\z\"",
+        );
+        assert_eq!(
+            names(&offers),
+            ["permessage-deflate", "x-webkit-deflate-frame", "foo"]
+        );
+        assert_eq!(offers[0].param("client_max_window_bits"), Some(None));
+        assert_eq!(offers[2].param("bar"), Some(Some("baz")));
+        // 语法错误的 offer 被忽略
+        assert!(parse("foo bar, foo; =1, foo; bar=\"a b\"").is_empty());
+    }
+
+    // 旧版的逐帧压缩扩展总是被拒绝, 不影响同时提供的 permessage-deflate
+    #[test]
+    fn legacy_deflate_is_declined() {
+        let deflate = PermessageDeflate;
+        let legacy: [&dyn ExtensionHandler; 2] = [
+            &AcceptAll("x-webkit-deflate-frame"),
+            &AcceptAll("deflate-frame"),
+        ];
+        let mut handlers: Vec<&dyn ExtensionHandler> = vec![&deflate];
+        handlers.extend(legacy);
+        for offer in [
+            "x-webkit-deflate-frame",
+            "deflate-frame; no_context_takeover",
+        ] {
+            assert!(negotiate(offer, &handlers).is_empty(), "{}", offer);
+        }
+        let accepted = negotiate(
+            "x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover",
+            &handlers,
+        );
+        assert_eq!(
+            to_header(&accepted),
+            "permessage-deflate; server_no_context_takeover"
+        );
+    }
+
+    // 没有 handler 的扩展被忽略, 每个扩展只接受第一个 offer, 顺序和客户端一致
+    #[test]
+    fn negotiate_in_offer_order() {
+        let handlers: [&dyn ExtensionHandler; 2] = [&AcceptAll("b"), &AcceptAll("a")];
+        let accepted = negotiate("unknown, a; x=1, b, a; x=2", &handlers);
+        assert_eq!(to_header(&accepted), "a; x=1, b");
+    }
+}
@@ -0,0 +1,98 @@
+// 反向代理在握手请求里通过 X-Forwarded-For 告诉服务端客户端的地址
+// 任何客户端都可以发送这个头, 只有连接的对端是信任的代理时才使用
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+// 一个 ip 网段, 例如 10.0.0.0/8 或者 fd00::/8
+// 不带前缀长度时只包含这一个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    // 前缀长度超过地址的位数时返回 None
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix_len <= bits).then_some(IpNetwork { addr, prefix_len })
+    }
+
+    // 监听双栈地址时 ipv4 的客户端是 ::ffff:a.b.c.d
+    // 所以统一转换成 ipv6 的地址比较, ipv4 的网段也能匹配这样的客户端
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let prefix_len = match self.addr {
+            IpAddr::V4(_) => self.prefix_len + 96,
+            IpAddr::V6(_) => self.prefix_len,
+        };
+        prefix_len == 0 || (to_ipv6(self.addr) ^ to_ipv6(ip)) >> (128 - prefix_len) == 0
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().into(),
+        IpAddr::V6(ip) => ip.into(),
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ParseNetworkError)?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| ParseNetworkError)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNetwork::new(addr, prefix_len).ok_or(ParseNetworkError)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseNetworkError;
+
+impl fmt::Display for ParseNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ip network")
+    }
+}
+
+impl Error for ParseNetworkError {}
+
+// 连接的对端是信任的代理时, 返回 X-Forwarded-For 最左边的地址, 也就是最初的客户端
+// 对端不是信任的代理, 没有这个头或者地址不合法时返回 None, 这时使用连接本身的地址
+pub(crate) fn forwarded_for(
+    peer: Option<IpAddr>,
+    headers: &BTreeMap<String, String>,
+    trusted_proxies: &[IpNetwork],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.iter().any(|network| network.contains(peer)) {
+        return None;
+    }
+    // 多个 X-Forwarded-For 头已经按顺序用逗号合并在一起
+    let client = headers.get("x-forwarded-for")?.split(',').next()?.trim();
+    // 有的代理会带上端口, 例如 203.0.113.7:51234 或者 [2001:db8::1]:51234
+    client
+        .parse::<IpAddr>()
+        .or_else(|_| client.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
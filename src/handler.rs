@@ -0,0 +1,322 @@
+use crate::{
+    metrics::Transferred, ConnectionStats, Message, ProtocolError, Request, Sender, WsError,
+};
+use log::debug;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    thread,
+};
+
+// 一个连接的信息, 日志里的每一行都带着 id 和客户端地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionContext {
+    // 服务端内唯一, 按接受连接的顺序递增
+    pub id: u64,
+    // unix socket 的客户端没有地址
+    pub peer_addr: Option<SocketAddr>,
+    // 信任的代理在 X-Forwarded-For 里告诉服务端的客户端地址, 握手之后才知道
+    pub forwarded_for: Option<IpAddr>,
+    // 握手时协商出来的子协议, 握手之后才知道
+    pub protocol: Option<String>,
+}
+
+impl ConnectionContext {
+    // 客户端的 ip, 经过信任的代理时是 X-Forwarded-For 里的地址
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded_for
+            .or_else(|| self.peer_addr.map(|peer_addr| peer_addr.ip()))
+    }
+}
+
+impl fmt::Display for ConnectionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.forwarded_for, self.peer_addr) {
+            (Some(client), Some(peer_addr)) => {
+                write!(f, "#{} {} via {}", self.id, client, peer_addr)
+            }
+            (_, Some(peer_addr)) => write!(f, "#{} {}", self.id, peer_addr),
+            (_, None) => write!(f, "#{} unix socket", self.id),
+        }
+    }
+}
+
+// 处理客户端发来的数据消息, 返回 None 表示不回复
+// handler 会为每个连接 clone 一份
+pub trait MessageHandler {
+    fn on_message(&mut self, msg: Message) -> Option<Message>;
+
+    // 握手成功之后, on_connect 之前调用, 可以根据请求的路径和查询参数决定之后怎么处理消息
+    // 例如 ws://host/room/42?name=a 的 request.path() 是 /room/42, request.query() 是 {name: a}
+    fn on_request(&mut self, _ctx: &ConnectionContext, _request: &Request) {}
+
+    // 握手成功之后, 处理消息之前调用, sender 可以保存下来主动给这个连接发送消息
+    fn on_connect(&mut self, _ctx: &ConnectionContext, _sender: &Sender) {}
+
+    // 调用过 on_connect 的连接断开时调用一次, 包括出错断开和 handler panic 的
+    // stats 是这个连接收发的字节数
+    fn on_disconnect(
+        &mut self,
+        _ctx: &ConnectionContext,
+        _reason: CloseReason,
+        _stats: ConnectionStats,
+    ) {
+    }
+
+    // 返回 true 表示 binary 消息总是原样返回
+    // 这时超过 stream_threshold 的 frame 不经过 on_message, 读到一块就写回一块
+    fn echoes_binary(&self) -> bool {
+        false
+    }
+
+    // 返回 true 时, 超过 stream_threshold 的 text 和 binary 消息不经过 on_message
+    // 读到一块 payload 就交给 on_chunk, 输出马上发送给客户端, 不在内存里拼接完整的消息
+    // 比 echoes_binary 优先, 两个都返回 true 时 binary 消息也交给 on_chunk
+    fn streams_messages(&self) -> bool {
+        false
+    }
+
+    // 分块处理的消息的一段 payload, 写进 out 的数据是回复的一部分, 可以不写
+    // out 在每次调用之前清空, 回复的类型 (text 或 binary) 和收到的消息一样
+    fn on_chunk(&mut self, _chunk: Chunk<'_>, _out: &mut Vec<u8>) {}
+
+    // 返回 true 表示 on_chunk 的输出总是和输入一样长 (例如逐字节变换)
+    // 这时回复和客户端的 frame 一一对应, 使用相同的长度, 输出长度不一致时用 1011 关闭连接
+    // 返回 false 时长度事先不知道, 每次有输出都写成回复的一个分片, 消息结束时写最后一个分片
+    fn preserves_length(&self) -> bool {
+        false
+    }
+}
+
+// 分块处理的消息里的一段 payload, 见 MessageHandler::on_chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    // text 消息的 utf8 已经检查过, 但是一个字符可能被截断在两段之间
+    pub text: bool,
+    pub data: &'a [u8],
+    // 这条消息的最后一段, 可能是空的
+    pub last: bool,
+}
+
+// 一种数据消息在交给 handler 之前怎么处理, 见 EchoPolicy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchoAction {
+    // 交给 handler, 默认的 EchoHandler 原样返回
+    #[default]
+    Echo,
+    // 读完之后丢弃, 不回复也不调用 handler
+    Drop,
+    // 用 1003 (unsupported data) 关闭连接
+    Close,
+}
+
+// 按消息类型决定 text 和 binary 消息怎么处理, 例如只回显 text, 收到 binary 时关闭连接
+// 控制帧不受影响
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EchoPolicy {
+    pub text: EchoAction,
+    pub binary: EchoAction,
+}
+
+impl EchoPolicy {
+    pub(crate) fn action(&self, message: &Message) -> EchoAction {
+        match message {
+            Message::Text(_) => self.text,
+            Message::Binary(_) => self.binary,
+            _ => EchoAction::Echo,
+        }
+    }
+
+    // 不接受的消息类型返回 1003, 其他消息 (包括丢弃的) 返回 Ok
+    pub(crate) fn check(&self, message: &Message) -> Result<(), ProtocolError> {
+        if self.action(message) == EchoAction::Close {
+            return Err(ProtocolError::new(1003, "unsupported data"));
+        }
+        Ok(())
+    }
+}
+
+// 连接为什么结束, 交给 on_disconnect, 也出现在断开连接的日志里
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    // 客户端发起并完成了关闭握手, 是客户端 close 帧里的状态码和原因
+    Normal { code: Option<u16>, reason: String },
+    // 服务端先发送了 close 帧 (关闭服务, handler 或者 Sender 关闭连接), 客户端回复之后结束
+    ServerInitiated { code: Option<u16> },
+    // 客户端违反了协议 (包括限流和消息太大), 服务端用这个状态码关闭了连接
+    ProtocolError { code: u16, reason: String },
+    // 读超时, pong 超时或者超过了 max_connection_duration, 服务端用 1001 关闭了连接
+    Timeout,
+    // 读写连接出错, 是错误的描述
+    Io(String),
+    // 没有关闭握手连接就断开了, 包括在 frame 的中间断开, 相当于 1006 (abnormal closure)
+    Abnormal,
+    // handler 在处理这个连接时 panic
+    Panicked,
+}
+
+impl CloseReason {
+    // 读写消息时出错结束的连接
+    pub(crate) fn from_error(err: &WsError) -> Self {
+        match err {
+            WsError::ProtocolViolation(err) => CloseReason::ProtocolError {
+                code: err.code,
+                reason: err.reason.to_string(),
+            },
+            WsError::ConnectionClosed | WsError::FrameTruncated => CloseReason::Abnormal,
+            // close_code 只对读写超时返回 1001
+            WsError::Io(_) if err.close_code().is_some() => CloseReason::Timeout,
+            WsError::Io(err) => CloseReason::Io(err.to_string()),
+            err => CloseReason::Io(err.to_string()),
+        }
+    }
+
+    // 完成了关闭握手, 服务端主动关闭时不需要检查客户端的状态码
+    pub fn is_normal(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::Normal { .. } | CloseReason::ServerInitiated { .. }
+        )
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Normal { code: None, .. } => f.write_str("closed by client"),
+            CloseReason::Normal {
+                code: Some(code),
+                reason,
+            } if reason.is_empty() => {
+                write!(f, "closed by client ({})", code)
+            }
+            CloseReason::Normal {
+                code: Some(code),
+                reason,
+            } => write!(f, "closed by client ({} {})", code, reason),
+            CloseReason::ServerInitiated { code: None } => f.write_str("closed by server"),
+            CloseReason::ServerInitiated { code: Some(code) } => {
+                write!(f, "closed by server ({})", code)
+            }
+            CloseReason::ProtocolError { code, reason } => {
+                write!(f, "protocol error {}: {}", code, reason)
+            }
+            CloseReason::Timeout => f.write_str("timed out"),
+            CloseReason::Io(err) => write!(f, "io error: {}", err),
+            CloseReason::Abnormal => f.write_str("closed without a close frame"),
+            CloseReason::Panicked => f.write_str("handler panicked"),
+        }
+    }
+}
+
+// on_connect 之后创建, 释放时调用 on_disconnect, 出错提前返回或者 panic 时也只会调用一次
+// panic 时 on_disconnect 在栈展开的过程中调用, 这时再 panic 会让进程直接退出
+pub(crate) struct Lifecycle<'a, H: MessageHandler> {
+    pub(crate) handler: &'a mut H,
+    ctx: &'a ConnectionContext,
+    transferred: &'a Transferred,
+    // 正常结束时由调用方设置
+    reason: Option<CloseReason>,
+}
+
+impl<'a, H: MessageHandler> Lifecycle<'a, H> {
+    pub(crate) fn new(
+        handler: &'a mut H,
+        ctx: &'a ConnectionContext,
+        transferred: &'a Transferred,
+    ) -> Self {
+        Lifecycle {
+            handler,
+            ctx,
+            transferred,
+            reason: None,
+        }
+    }
+
+    pub(crate) fn finish(mut self, reason: CloseReason) {
+        self.reason = Some(reason);
+    }
+}
+
+impl<H: MessageHandler> Drop for Lifecycle<'_, H> {
+    fn drop(&mut self) {
+        let reason = match self.reason.take() {
+            Some(reason) => reason,
+            None if thread::panicking() => CloseReason::Panicked,
+            // 异步的连接在 task 被取消时没有结果, 例如 runtime 关闭
+            None => CloseReason::Abnormal,
+        };
+        self.handler
+            .on_disconnect(self.ctx, reason, self.transferred.snapshot());
+    }
+}
+
+// 默认的处理方式, 原样返回收到的消息
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoHandler;
+
+impl MessageHandler for EchoHandler {
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        Some(msg)
+    }
+
+    fn echoes_binary(&self) -> bool {
+        true
+    }
+}
+
+// 为每个连接创建一个 handler, 连接之间不共享状态
+// 实现了 Clone 的 MessageHandler 都是 HandlerFactory, 每个连接 clone 一份
+// 每个连接需要重新初始化的状态 (而不是 clone 出来的) 可以自己实现这个 trait
+pub trait HandlerFactory {
+    type Handler: MessageHandler;
+
+    // 握手之前调用, 同步的 Server 在 worker 线程里调用, AsyncServer 在 accept 的 task 里调用
+    fn new_handler(&self) -> Self::Handler;
+}
+
+impl<H: MessageHandler + Clone> HandlerFactory for H {
+    type Handler = H;
+
+    fn new_handler(&self) -> H {
+        self.clone()
+    }
+}
+
+// 用闭包处理数据消息, 其他的回调都是默认的实现, 见 handler_fn
+#[derive(Clone, Copy)]
+pub struct FnHandler<F>(F);
+
+// 把 FnMut(Message) -> Option<Message> 的闭包当作 handler 使用
+// 闭包和 handler 一样为每个连接 clone 一份, 捕获的变量在连接之间不共享, 需要共享时捕获 Arc
+pub fn handler_fn<F: FnMut(Message) -> Option<Message>>(f: F) -> FnHandler<F> {
+    FnHandler(f)
+}
+
+impl<F: FnMut(Message) -> Option<Message>> MessageHandler for FnHandler<F> {
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        (self.0)(msg)
+    }
+}
+
+// 交换回复的类型, text 消息用 binary 返回, binary 消息用 text 返回
+// 用来测试客户端收到和发送的类型不一样时怎么处理
+// 收到的 text 消息仍然需要是合法的 UTF-8, 不是 UTF-8 的 binary 消息只能原样返回
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwapOpcodeHandler;
+
+impl MessageHandler for SwapOpcodeHandler {
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::Text(text) => Some(Message::Binary(text.into_bytes())),
+            Message::Binary(data) => Some(match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(err) => {
+                    debug!("binary message is not utf-8, echoed as binary");
+                    Message::Binary(err.into_bytes())
+                }
+            }),
+            msg => Some(msg),
+        }
+    }
+}
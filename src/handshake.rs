@@ -0,0 +1,1106 @@
+use crate::{
+    access_log::AccessEntry,
+    connection::{ConnectionLimit, LimitGuard},
+    deflate::{DeflateParams, PermessageDeflate},
+    extensions::{self, Extension, ExtensionHandler},
+    forwarded,
+    metrics::Counters,
+    Config, WsError,
+};
+use base64::{engine::general_purpose, Engine as _};
+use log::debug;
+use ring::digest;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read, Write},
+    net::IpAddr,
+};
+
+// 请求行和头信息加起来的最大字节数, Config::max_total_header_bytes 的默认值, 客户端读响应时也使用
+pub(crate) const MAX_HEADER_SIZE: usize = 16 * 1024;
+// 请求行和每一行头信息的最大字节数, Config::max_header_line_bytes 的默认值
+pub(crate) const MAX_HEADER_LINE: usize = 8 * 1024;
+// 头信息的最大数量, Config::max_header_count 的默认值
+pub(crate) const MAX_HEADERS: usize = 100;
+
+// 握手失败的原因, status 是返回给客户端的 http 状态
+#[derive(Debug)]
+pub struct HandshakeError {
+    pub status: &'static str,
+    pub reason: &'static str,
+}
+
+impl HandshakeError {
+    fn bad_request(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "400 Bad Request",
+            reason,
+        }
+    }
+
+    fn upgrade_required(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "426 Upgrade Required",
+            reason,
+        }
+    }
+
+    fn too_large(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "431 Request Header Fields Too Large",
+            reason,
+        }
+    }
+
+    fn uri_too_long(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "414 URI Too Long",
+            reason,
+        }
+    }
+
+    fn service_unavailable(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "503 Service Unavailable",
+            reason,
+        }
+    }
+
+    // 没有在 handshake_timeout 之内收到完整的请求
+    pub(crate) fn timed_out() -> Self {
+        HandshakeError {
+            status: "408 Request Timeout",
+            reason: "handshake timed out",
+        }
+    }
+
+    fn forbidden(reason: &'static str) -> Self {
+        HandshakeError {
+            status: "403 Forbidden",
+            reason,
+        }
+    }
+
+    // status 开头的三位数字
+    pub(crate) fn status_code(&self) -> u16 {
+        self.status[..3].parse().unwrap_or(0)
+    }
+
+    // 返回给客户端的错误响应
+    pub(crate) fn response(&self) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n\
+            {}\
+            Connection: close\r\n\
+            Content-Length: 0\r\n\r\n",
+            self.status,
+            self.response_headers()
+        )
+    }
+
+    // 错误响应需要额外携带的头信息
+    fn response_headers(&self) -> &'static str {
+        match self.status {
+            // 告诉客户端服务端支持的版本
+            "426 Upgrade Required" => "Sec-WebSocket-Version: 13\r\n",
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handshake failed ({}): {}", self.status, self.reason)
+    }
+}
+
+impl Error for HandshakeError {}
+
+// 握手请求
+pub struct Request {
+    // 请求行中的 target, 例如 /chat?room=1
+    pub target: String,
+    // 头信息, key 是小写的
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Request {
+    // target 中 ? 之前的部分, 例如 /room/42, 没有做百分号解码
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    // 解析 ? 之后的查询参数, 名字和值都做百分号解码, + 解码成空格
+    // 没有 = 的参数值是空字符串, 重复的参数只保留第一个
+    pub fn query(&self) -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        let Some((_, query)) = self.target.split_once('?') else {
+            return params;
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            params
+                .entry(percent_decode(name))
+                .or_insert_with(|| percent_decode(value));
+        }
+        params
+    }
+}
+
+// 不合法的百分号编码原样保留, 解码出来不是 utf8 的字节替换成 U+FFFD
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// 握手的结果
+pub struct Handshake {
+    pub request: Request,
+    // 协商出来的子协议
+    pub protocol: Option<String>,
+    // 协商出来的 permessage-deflate 参数
+    pub deflate: Option<DeflateParams>,
+    // 接受的所有扩展 (包括 permessage-deflate), 和响应头里的顺序一致
+    pub extensions: Vec<Extension>,
+    // 连接的对端是信任的代理时, X-Forwarded-For 里的客户端地址
+    pub forwarded_for: Option<IpAddr>,
+}
+
+// 握手
+// 回复了健康检查的请求时返回 ConnectionClosed, 调用方直接结束这个连接
+pub fn handshake(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    config: &Config,
+) -> Result<Handshake, WsError> {
+    accept(
+        reader,
+        writer,
+        config,
+        None,
+        None,
+        None,
+        config.connect_tunnel,
+    )
+    .map(|(handshake, _)| handshake)
+}
+
+// 服务端使用的握手, 有统计数据时还可以回复 metrics 的请求, peer 是访问日志里的客户端地址
+// limit 里的连接数达到 max_connections 或者客户端 ip 的连接数达到 max_connections_per_ip 时返回 503
+// 成功时同时返回在 limit 里占用的名额, 在回复 101 之前就已经计数
+// allow_connect 时第一个请求可以是 CONNECT host:port, 回复 200 之后读取同一个连接上的握手请求
+pub(crate) fn accept<'l>(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    config: &Config,
+    counters: Option<&Counters>,
+    limit: Option<&'l ConnectionLimit>,
+    peer: Option<IpAddr>,
+    allow_connect: bool,
+) -> Result<(Handshake, Option<LimitGuard<'l>>), WsError> {
+    let mut request = read_request(reader, config, allow_connect);
+    if let Ok((tunnel, true)) = &request {
+        debug!("CONNECT tunnel to {}", tunnel.target);
+        writer.write_all(CONNECT_ESTABLISHED)?;
+        writer.flush()?;
+        // 隧道里只能是 websocket 的握手, 不能再 CONNECT
+        request = read_request(reader, config, false);
+    }
+    let request = request.map(|(request, _)| request);
+    let forwarded_for = request.as_ref().ok().and_then(|request| {
+        forwarded::forwarded_for(peer, &request.headers, &config.trusted_proxies)
+    });
+    // 访问日志需要的字段在 request 被拿走之前复制出来
+    let entry = config
+        .access_log
+        .as_ref()
+        .map(|_| AccessEntry::new(forwarded_for.or(peer), request.as_ref().ok()));
+    let result = match request {
+        Ok(request) => {
+            let client = forwarded_for.or(peer);
+            respond(request, writer, config, counters, limit, client).map(|(handshake, slot)| {
+                let handshake = Handshake {
+                    forwarded_for,
+                    ..handshake
+                };
+                (handshake, slot)
+            })
+        }
+        Err(err) => reject(writer, err),
+    };
+    if let (Some(access_log), Some(mut entry)) = (&config.access_log, entry) {
+        entry.status = match &result {
+            Ok(_) => Some(101),
+            Err(WsError::HandshakeFailed(err)) => Some(err.status_code()),
+            // 回复了健康检查或者 metrics
+            Err(WsError::ConnectionClosed) => Some(200),
+            Err(_) => None,
+        };
+        access_log.write(&entry);
+    }
+    result
+}
+
+fn respond<'l>(
+    request: Request,
+    writer: &mut impl Write,
+    config: &Config,
+    counters: Option<&Counters>,
+    limit: Option<&'l ConnectionLimit>,
+    client: Option<IpAddr>,
+) -> Result<(Handshake, Option<LimitGuard<'l>>), WsError> {
+    // 健康检查, 测试页面和 metrics 的请求不是 websocket 握手, 返回 200 之后关闭连接
+    if let Some((content_type, body)) = plain_response(&request, config, counters) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+            Content-Type: {}\r\n\
+            Connection: close\r\n\
+            Content-Length: {}\r\n\r\n\
+            {}",
+            content_type,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes())?;
+        writer.flush()?;
+        return Err(WsError::ConnectionClosed);
+    }
+
+    // 负载均衡的健康检查不受影响, 只拒绝新的 websocket 连接
+    // 之后的检查失败时 slot 被释放
+    let slot = limit
+        .map(|limit| {
+            limit.try_open(
+                client,
+                config.max_connections,
+                config.max_connections_per_ip,
+            )
+        })
+        .transpose();
+    let slot = match slot {
+        Ok(slot) => slot,
+        Err(reason) => return reject(writer, HandshakeError::service_unavailable(reason)),
+    };
+
+    if let Err(err) = validate_upgrade(&request.headers) {
+        return reject(writer, err);
+    }
+
+    if let Err(err) = check_origin(&request.headers, &config.allowed_origins) {
+        return reject(writer, err);
+    }
+
+    let sec_websocket_key = match request.headers.get("sec-websocket-key") {
+        Some(sec_websocket_key) => sec_websocket_key,
+        None => {
+            return reject(
+                writer,
+                HandshakeError::bad_request("no header Sec-Websocket-Key"),
+            )
+        }
+    };
+
+    // key 是 16 个随机字节的 base64 编码
+    let key_length = general_purpose::STANDARD
+        .decode(sec_websocket_key)
+        .map(|key| key.len());
+    if key_length != Ok(16) {
+        return reject(
+            writer,
+            HandshakeError::bad_request("invalid Sec-WebSocket-Key"),
+        );
+    }
+
+    let sec_websocket_accept = compute_accept(sec_websocket_key);
+
+    let protocol = request
+        .headers
+        .get("sec-websocket-protocol")
+        .and_then(|offered| select_protocol(offered, &config.protocols))
+        .map(String::from);
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n",
+        sec_websocket_accept
+    );
+    // 没有匹配的子协议时不返回这个头信息
+    if let Some(protocol) = &protocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+    // bind 时已经检查过, 不会和上面的头冲突
+    for (name, value) in &config.extra_response_headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    // 内置的 permessage-deflate 排在注册的扩展前面, 不支持的扩展不会出现在响应里
+    let mut handlers: Vec<&dyn ExtensionHandler> =
+        config.extensions.iter().map(AsRef::as_ref).collect();
+    if config.permessage_deflate {
+        handlers.insert(0, &PermessageDeflate);
+    }
+    let extensions = request
+        .headers
+        .get("sec-websocket-extensions")
+        .map(|offered| extensions::negotiate(offered, &handlers))
+        .unwrap_or_default();
+    if !extensions.is_empty() {
+        response.push_str(&format!(
+            "Sec-WebSocket-Extensions: {}\r\n",
+            extensions::to_header(&extensions)
+        ));
+    }
+    let deflate = extensions
+        .iter()
+        .find(|extension| config.permessage_deflate && extension.name == PermessageDeflate.name())
+        .map(DeflateParams::from_response);
+    response.push_str("\r\n");
+
+    writer.write_all(response.as_bytes())?;
+
+    writer.flush()?;
+
+    let handshake = Handshake {
+        request,
+        protocol,
+        deflate,
+        extensions,
+        forwarded_for: None,
+    };
+    Ok((handshake, slot))
+}
+
+// 握手响应里由服务端生成的头, extra_response_headers 不能覆盖
+const RESERVED_RESPONSE_HEADERS: [&str; 7] = [
+    "upgrade",
+    "connection",
+    "sec-websocket-accept",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+    "content-length",
+    "transfer-encoding",
+];
+
+// 检查 extra_response_headers, 名字必须是 token, 值不能有换行之类的控制字符
+pub(crate) fn check_response_headers(headers: &[(String, String)]) -> io::Result<()> {
+    for (name, value) in headers {
+        let invalid = |reason| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid response header {:?}: {}", name, reason),
+            )
+        };
+        if !extensions::is_token(name) {
+            return Err(invalid("name is not a token"));
+        }
+        if RESERVED_RESPONSE_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(invalid("set by the handshake"));
+        }
+        if value.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(invalid("value contains control characters"));
+        }
+    }
+    Ok(())
+}
+
+// Sec-WebSocket-Key 对应的 Sec-WebSocket-Accept: key 拼接固定的 GUID 之后做 sha1, 再 base64 编码
+// 服务端握手和客户端检查响应都使用这个函数
+// 例如 RFC 6455 里的 dGhlIHNhbXBsZSBub25jZQ== 对应 s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
+pub fn compute_accept(key: &str) -> String {
+    const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        &[key.as_bytes(), GUID].concat(),
+    );
+    general_purpose::STANDARD.encode(hash.as_ref())
+}
+
+// 没有要求升级成 websocket 的请求访问健康检查或者 metrics 的路径时, 返回响应的内容
+// 返回 Content-Type 和内容
+fn plain_response(
+    request: &Request,
+    config: &Config,
+    counters: Option<&Counters>,
+) -> Option<(&'static str, String)> {
+    if has_token(&request.headers, "upgrade", "websocket") {
+        return None;
+    }
+    let path = request.path();
+    if config.health_path.as_deref() == Some(path) {
+        return Some(("text/plain", "ok\n".to_string()));
+    }
+    if config.serve_demo_page.as_deref() == Some(path) {
+        return Some(("text/html; charset=utf-8", DEMO_PAGE.to_string()));
+    }
+    match counters {
+        Some(counters) if config.metrics_path.as_deref() == Some(path) => {
+            Some(("text/plain", counters.snapshot().to_prometheus()))
+        }
+        _ => None,
+    }
+}
+
+// 测试用的页面, 连接回同一个地址, 发送输入框里的文本并显示收到的消息
+const DEMO_PAGE: &str = include_str!("demo.html");
+
+// 按服务端的优先级选择第一个客户端也支持的子协议
+fn select_protocol<'a>(offered: &str, supported: &'a [String]) -> Option<&'a str> {
+    let offered: Vec<&str> = offered.split(',').map(str::trim).collect();
+    supported
+        .iter()
+        .map(String::as_str)
+        .find(|protocol| offered.contains(protocol))
+}
+
+// 读取一行, 超过长度限制时不再继续读, 防止客户端一直发送数据耗尽内存
+// total 是已经读取的字节数, 一行太长时返回 line_too_long, 总长度超过限制时返回 431
+fn read_line(
+    reader: &mut impl BufRead,
+    buffer: &mut String,
+    total: &mut usize,
+    config: &Config,
+    line_too_long: HandshakeError,
+) -> Result<usize, HandshakeError> {
+    let remaining = config.max_total_header_bytes.saturating_sub(*total);
+    if remaining == 0 {
+        return Err(HandshakeError::too_large("request headers too large"));
+    }
+    let limit = config.max_header_line_bytes.min(remaining);
+    let size = reader
+        .take(limit as u64)
+        .read_line(buffer)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HandshakeError::timed_out(),
+            _ => HandshakeError::bad_request("invalid header line"),
+        })?;
+    // 读满了限制的长度还没有遇到换行
+    if size == limit && !buffer.ends_with('\n') {
+        if limit == config.max_header_line_bytes {
+            return Err(line_too_long);
+        }
+        return Err(HandshakeError::too_large("request headers too large"));
+    }
+    *total += size;
+    Ok(size)
+}
+
+// 回复 CONNECT 请求, 之后的数据都属于隧道
+pub(crate) const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+// 异步版本先把请求读到内存里, 用这个函数判断是不是 CONNECT 请求
+// 请求行不合法时返回 false, 之后交给 accept 拒绝
+#[cfg(feature = "tokio")]
+pub(crate) fn is_connect(request: &[u8]) -> bool {
+    let line = match request.iter().position(|&b| b == b'\n') {
+        Some(end) => &request[..=end],
+        None => return false,
+    };
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| parse_request_line(line, true).ok())
+        .is_some_and(|(connect, _)| connect)
+}
+
+// 读取请求行和头信息, 同时返回是不是 CONNECT 请求
+// 请求行太长返回 414, 头信息的一行太长, 数量太多或者总长度太长返回 431, 原因各不相同
+fn read_request(
+    reader: &mut impl BufRead,
+    config: &Config,
+    allow_connect: bool,
+) -> Result<(Request, bool), HandshakeError> {
+    let mut buffer = String::new();
+    let mut total = 0;
+    let size = read_line(
+        reader,
+        &mut buffer,
+        &mut total,
+        config,
+        HandshakeError::uri_too_long("request line too long"),
+    )?;
+    if size == 0 {
+        return Err(HandshakeError::bad_request("missing request line"));
+    }
+    // 读取 http 请求行
+    let request_line: &str = &buffer[0..size];
+    let (connect, target) = parse_request_line(request_line, allow_connect)?;
+    let target = target.to_string();
+    buffer.truncate(0);
+
+    let mut headers = BTreeMap::<String, String>::new();
+    let mut count = 0;
+
+    loop {
+        let size = read_line(
+            reader,
+            &mut buffer,
+            &mut total,
+            config,
+            HandshakeError::too_large("request header line too long"),
+        )?;
+        if size == 0 {
+            // 头信息还没结束连接就断开了
+            return Err(HandshakeError::bad_request("truncated headers"));
+        }
+        // 读取每一个头信息
+        let header_line: &str = &buffer[0..size];
+        // 头信息完结
+        if header_line == "\r\n" {
+            break;
+        }
+
+        let header_line = header_line
+            .strip_suffix("\r\n")
+            .ok_or(HandshakeError::bad_request("malformed header line"))?;
+        // 空格或者 tab 开头的行是上一个头信息的续行 (obs-fold), RFC 7230 已经废弃
+        // 代理和服务端对续行的理解可能不一样, 可以用来走私请求, 直接拒绝
+        if header_line.starts_with([' ', '\t']) {
+            return Err(HandshakeError::bad_request("obsolete line folding"));
+        }
+
+        // 同名的头信息合并成一个, 按行数计算
+        count += 1;
+        if count > config.max_header_count {
+            return Err(HandshakeError::too_large("too many request headers"));
+        }
+        // 同名的头信息用逗号拼接, 例如分成多行发送的 Sec-WebSocket-Extensions
+        if let Some((k, v)) = header_line.split_once(':') {
+            // 值的前后可以有空格和 tab (RFC 7230 的 OWS), 不属于值本身
+            // 只去掉这两种字符, 其他的空白字符留在值里, 例如 key 里的空白会让 base64 解码失败
+            let v = v.trim_matches([' ', '\t']);
+            headers
+                .entry(k.to_lowercase())
+                .and_modify(|value| {
+                    value.push_str(", ");
+                    value.push_str(v);
+                })
+                .or_insert_with(|| v.into());
+        };
+
+        buffer.truncate(0);
+    }
+
+    Ok((Request { target, headers }, connect))
+}
+
+// 检查升级 websocket 必需的头信息
+fn validate_upgrade(headers: &BTreeMap<String, String>) -> Result<(), HandshakeError> {
+    if !has_token(headers, "upgrade", "websocket") {
+        return Err(HandshakeError::bad_request("Upgrade must be websocket"));
+    }
+    if !has_token(headers, "connection", "upgrade") {
+        return Err(HandshakeError::bad_request("Connection must be Upgrade"));
+    }
+
+    match headers.get("sec-websocket-version") {
+        Some(version) if version.trim_end() == "13" => Ok(()),
+        Some(_) => Err(HandshakeError::upgrade_required(
+            "unsupported Sec-WebSocket-Version",
+        )),
+        None => Err(HandshakeError::bad_request(
+            "no header Sec-WebSocket-Version",
+        )),
+    }
+}
+
+// Connection 和 Upgrade 都是逗号分隔的列表, 例如 firefox 发送的 Connection: keep-alive, Upgrade
+// 需要其中一项 (不区分大小写) 等于 token, 不能比较整个值或者查找子串
+fn has_token(headers: &BTreeMap<String, String>, name: &str, token: &str) -> bool {
+    headers.get(name).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+// 浏览器会带上发起连接的页面的 Origin, 防止其他网站的页面连接到服务端
+fn check_origin(
+    headers: &BTreeMap<String, String>,
+    allowed_origins: &[String],
+) -> Result<(), HandshakeError> {
+    if allowed_origins.is_empty() {
+        return Ok(());
+    }
+    match headers.get("origin") {
+        // 不是浏览器发起的请求
+        None => Ok(()),
+        Some(origin)
+            if allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin.trim_end())) =>
+        {
+            Ok(())
+        }
+        Some(_) => Err(HandshakeError::forbidden("origin not allowed")),
+    }
+}
+
+// 请求行的格式是 `GET target HTTP/1.1`, 返回其中的 target
+// 返回是不是 CONNECT 请求和请求行中的 target, allow_connect 时才接受 CONNECT
+fn parse_request_line(
+    request_line: &str,
+    allow_connect: bool,
+) -> Result<(bool, &str), HandshakeError> {
+    let request_line = request_line
+        .strip_suffix("\r\n")
+        .ok_or(HandshakeError::bad_request("malformed request line"))?;
+    // 客户端连接之后马上发送了空行, 没有请求行
+    if request_line.is_empty() {
+        return Err(HandshakeError::bad_request("empty request line"));
+    }
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(HandshakeError::bad_request("malformed request line"));
+    };
+
+    // websocket 握手必须是 GET 请求, CONNECT 的 target 是 host:port
+    let connect = allow_connect && method == "CONNECT";
+    if method != "GET" && !connect {
+        return Err(HandshakeError::bad_request("method must be GET"));
+    }
+    if target.is_empty() {
+        return Err(HandshakeError::bad_request("missing request target"));
+    }
+    if connect && (target.starts_with('/') || !target.contains(':')) {
+        return Err(HandshakeError::bad_request(
+            "CONNECT target must be host:port",
+        ));
+    }
+    // 只接受 /path?query 的形式, http://host/path 只用于代理, host:port 只用于 CONNECT
+    if !connect && !target.starts_with('/') {
+        return Err(HandshakeError::bad_request(
+            "request target must be an absolute path",
+        ));
+    }
+    // http 版本至少是 1.1
+    let (major, minor) = version
+        .strip_prefix("HTTP/")
+        .and_then(|version| version.split_once('.'))
+        .and_then(|(major, minor)| Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?)))
+        .ok_or(HandshakeError::bad_request("malformed http version"))?;
+    if (major, minor) < (1, 1) {
+        return Err(HandshakeError::bad_request(
+            "http version must be at least 1.1",
+        ));
+    }
+
+    Ok((connect, target))
+}
+
+// 返回错误的 http 响应, 然后结束这个连接
+fn reject<T>(writer: &mut impl Write, err: HandshakeError) -> Result<T, WsError> {
+    writer.write_all(err.response().as_bytes())?;
+    writer.flush()?;
+    Err(err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 除了 key 的头以外, 一个合法的握手请求需要的头信息
+    const UPGRADE: &str = "Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Version: 13\r\n";
+
+    // 握手的结果和写给客户端的响应
+    fn respond_to(request: &str, config: &Config) -> (Result<Handshake, WsError>, String) {
+        let mut response = Vec::new();
+        let result = handshake(&mut request.as_bytes(), &mut response, config);
+        (result, String::from_utf8(response).unwrap())
+    }
+
+    // 合法的握手请求, 加上 headers 里的头信息
+    fn request(headers: &str) -> String {
+        format!(
+            "GET /chat HTTP/1.1\r\n{}Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            UPGRADE, headers
+        )
+    }
+
+    fn response_header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response.lines().find_map(|line| {
+            let (key, value) = line.split_once(": ")?;
+            key.eq_ignore_ascii_case(name).then_some(value)
+        })
+    }
+
+    fn with_protocols(protocols: &[&str]) -> Config {
+        Config {
+            protocols: protocols
+                .iter()
+                .map(|protocol| protocol.to_string())
+                .collect(),
+            ..Config::default()
+        }
+    }
+
+    // 按服务端的优先级选择, 不是客户端发送的顺序
+    #[test]
+    fn subprotocol_uses_server_preference() {
+        let config = with_protocols(&["v2.chat", "chat"]);
+        let offers = [
+            "Sec-WebSocket-Protocol: chat, v2.chat\r\n",
+            // 分成多行发送的头信息合并之后再选择
+            "Sec-WebSocket-Protocol: chat\r\nSec-WebSocket-Protocol: v2.chat\r\n",
+        ];
+        for offer in offers {
+            let (result, response) = respond_to(&request(offer), &config);
+            assert_eq!(result.unwrap().protocol.as_deref(), Some("v2.chat"));
+            assert_eq!(
+                response_header(&response, "Sec-WebSocket-Protocol"),
+                Some("v2.chat")
+            );
+        }
+    }
+
+    // 客户端提供 chat, superchat, 服务端只支持 superchat
+    #[test]
+    fn subprotocol_selects_the_supported_offer() {
+        let config = with_protocols(&["superchat"]);
+        let (result, response) = respond_to(
+            &request("Sec-WebSocket-Protocol: chat, superchat\r\n"),
+            &config,
+        );
+        assert_eq!(result.unwrap().protocol.as_deref(), Some("superchat"));
+        assert_eq!(
+            response_header(&response, "Sec-WebSocket-Protocol"),
+            Some("superchat")
+        );
+    }
+
+    // 没有共同支持的子协议时仍然完成握手, 响应里没有这个头
+    #[test]
+    fn subprotocol_without_match() {
+        let requests = [request("Sec-WebSocket-Protocol: mqtt\r\n"), request("")];
+        for config in [with_protocols(&["chat"]), Config::default()] {
+            for request in &requests {
+                let (result, response) = respond_to(request, &config);
+                assert_eq!(result.unwrap().protocol, None);
+                assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+                assert_eq!(response_header(&response, "Sec-WebSocket-Protocol"), None);
+            }
+        }
+    }
+
+    fn status(request: &str, config: &Config) -> String {
+        let (_, response) = respond_to(request, config);
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn origin_not_allowed_is_403() {
+        let config = Config {
+            allowed_origins: vec!["https://example.com".into()],
+            ..Config::default()
+        };
+        let allowed = [
+            "Origin: https://example.com\r\n",
+            // 不区分大小写
+            "Origin: HTTPS://EXAMPLE.COM\r\n",
+            // 没有 Origin 的不是浏览器发起的请求
+            "",
+        ];
+        for origin in allowed {
+            assert_eq!(
+                status(&request(origin), &config),
+                "HTTP/1.1 101 Switching Protocols",
+                "{}",
+                origin
+            );
+        }
+        let forbidden = [
+            "Origin: https://evil.example\r\n",
+            "Origin: https://example.com.evil.example\r\n",
+            "Origin: null\r\n",
+        ];
+        for origin in forbidden {
+            assert_eq!(
+                status(&request(origin), &config),
+                "HTTP/1.1 403 Forbidden",
+                "{}",
+                origin
+            );
+        }
+        // 没有设置 allowed_origins 时不检查
+        let origin = request("Origin: https://evil.example\r\n");
+        assert!(status(&origin, &Config::default()).contains(" 101 "));
+    }
+
+    // 握手失败的原因
+    fn rejected(request: &str, config: &Config) -> (String, &'static str) {
+        match respond_to(request, config) {
+            (Err(WsError::HandshakeFailed(err)), response) => {
+                assert!(response.starts_with(&format!("HTTP/1.1 {}", err.status)));
+                (err.status.to_string(), err.reason)
+            }
+            (_, response) => panic!("expected a rejected handshake, got {}", response),
+        }
+    }
+
+    #[test]
+    fn oversized_requests() {
+        let config = Config {
+            max_header_line_bytes: 64,
+            max_header_count: 8,
+            max_total_header_bytes: 256,
+            ..Config::default()
+        };
+        assert!(status(&request(""), &config).contains(" 101 "));
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n{}\r\n", "a".repeat(64), UPGRADE);
+        assert_eq!(
+            rejected(&long_target, &config),
+            ("414 URI Too Long".into(), "request line too long")
+        );
+        let long_line = request(&format!("X-Long: {}\r\n", "a".repeat(64)));
+        assert_eq!(
+            rejected(&long_line, &config),
+            (
+                "431 Request Header Fields Too Large".into(),
+                "request header line too long"
+            )
+        );
+        let many = request(&"X-A: 1\r\n".repeat(4));
+        assert_eq!(
+            rejected(&many, &config),
+            (
+                "431 Request Header Fields Too Large".into(),
+                "too many request headers"
+            )
+        );
+        // 每一行都不长, 加起来超过了总长度
+        let total = request(&format!("X-Total: {}\r\n", "a".repeat(40)).repeat(3));
+        assert_eq!(
+            rejected(&total, &config),
+            (
+                "431 Request Header Fields Too Large".into(),
+                "request headers too large"
+            )
+        );
+    }
+
+    // 用 connection 替换 UPGRADE 里的 Connection 头
+    fn with_connection(connection: &str) -> String {
+        request("").replace("Connection: Upgrade\r\n", connection)
+    }
+
+    // Connection 是逗号分隔的列表, 其中一项等于 upgrade 就可以, 不能只包含子串
+    #[test]
+    fn connection_token_list() {
+        let config = Config::default();
+        let accepted = [
+            "Connection: keep-alive, Upgrade\r\n",
+            "Connection: upgrade\r\n",
+            "Connection: keep-alive,upgrade , close\r\n",
+            // 分成多行发送的同名头信息合并之后再检查
+            "Connection: keep-alive\r\nConnection: Upgrade\r\n",
+        ];
+        for connection in accepted {
+            let response = status(&with_connection(connection), &config);
+            assert!(response.contains(" 101 "), "{:?}: {}", connection, response);
+        }
+        let invalid = [
+            "Connection: Upgraded\r\n",
+            "Connection: keep-alive\r\n",
+            "Connection: keep-alive Upgrade\r\n",
+            "",
+        ];
+        for connection in invalid {
+            assert_eq!(
+                rejected(&with_connection(connection), &config),
+                ("400 Bad Request".into(), "Connection must be Upgrade"),
+                "{:?}",
+                connection
+            );
+        }
+    }
+
+    fn accept_header(response: &str) -> Option<&str> {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Accept: "))
+    }
+
+    #[test]
+    fn compute_accept_rfc_example() {
+        assert_eq!(
+            compute_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    // 头信息的名字不区分大小写, 值前后的空格和 tab 不属于 key
+    #[test]
+    fn key_header_variants() {
+        let keys = [
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+            "sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==",
+            "SEC-WEBSOCKET-KEY:dGhlIHNhbXBsZSBub25jZQ==",
+            "Sec-WebSocket-Key: \t dGhlIHNhbXBsZSBub25jZQ== \t",
+        ];
+        for key in keys {
+            let request = format!("GET / HTTP/1.1\r\n{}{}\r\n\r\n", UPGRADE, key);
+            let (result, response) = respond_to(&request, &Config::default());
+            assert!(result.is_ok(), "{}: {}", key, response);
+            assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+            assert_eq!(
+                accept_header(&response),
+                Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_key_is_400() {
+        // 不是 16 个字节, key 中间的空白也不是 key 的一部分
+        for key in ["c2hvcnQ=", "dGhlIHNhbXBs ZSBub25jZQ==", ""] {
+            let request = format!(
+                "GET / HTTP/1.1\r\n{}Sec-WebSocket-Key: {}\r\n\r\n",
+                UPGRADE, key
+            );
+            let (result, response) = respond_to(&request, &Config::default());
+            assert!(result.is_err(), "{}", key);
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        }
+    }
+
+    // 空格或者 tab 开头的续行直接拒绝, 不能拼接到上一个头信息
+    #[test]
+    fn obs_fold_is_400() {
+        let config = Config::default();
+        for fold in [" ", "\t", "  \t"] {
+            let folded = request(&format!("X-Folded: a\r\n{}b\r\n", fold));
+            assert_eq!(
+                rejected(&folded, &config),
+                ("400 Bad Request".into(), "obsolete line folding"),
+                "{:?}",
+                fold
+            );
+        }
+        // 续行紧跟在必需的头信息之后也一样
+        let folded = request("").replace("Upgrade: websocket\r\n", "Upgrade:\r\n websocket\r\n");
+        assert_eq!(
+            rejected(&folded, &config),
+            ("400 Bad Request".into(), "obsolete line folding")
+        );
+        // 值中间的空格和 tab 不是续行
+        let inner = request("X-Inner: a \t b\r\n");
+        assert!(status(&inner, &config).contains(" 101 "));
+    }
+
+    // x-webkit-deflate-frame 不出现在 101 的响应里, 握手照常完成
+    #[test]
+    fn legacy_deflate_not_in_101() {
+        let config = Config::default();
+        let legacy = request("Sec-WebSocket-Extensions: x-webkit-deflate-frame\r\n");
+        let (result, response) = respond_to(&legacy, &config);
+        let handshake = result.unwrap();
+        assert!(handshake.deflate.is_none() && handshake.extensions.is_empty());
+        assert_eq!(response_header(&response, "Sec-WebSocket-Extensions"), None);
+
+        let both = request(
+            "Sec-WebSocket-Extensions: x-webkit-deflate-frame; no_context_takeover, \
+            permessage-deflate\r\n",
+        );
+        let (result, response) = respond_to(&both, &config);
+        assert!(result.unwrap().deflate.is_some());
+        assert_eq!(
+            response_header(&response, "Sec-WebSocket-Extensions"),
+            Some("permessage-deflate")
+        );
+    }
+
+    fn with_extra_headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // extra_response_headers 按顺序出现在 101 的响应里, 失败的响应里没有
+    #[test]
+    fn extra_response_headers_in_101() {
+        let config = Config {
+            extra_response_headers: with_extra_headers(&[
+                ("X-Server-Id", "echo-1"),
+                ("Cache-Control", "no-store"),
+            ]),
+            ..Config::default()
+        };
+        let (result, response) = respond_to(&request(""), &config);
+        assert!(result.is_ok(), "{}", response);
+        assert_eq!(response_header(&response, "X-Server-Id"), Some("echo-1"));
+        assert_eq!(
+            response_header(&response, "Cache-Control"),
+            Some("no-store")
+        );
+        assert!(response.find("X-Server-Id").unwrap() < response.find("Cache-Control").unwrap());
+
+        let (result, response) =
+            respond_to("GET /chat HTTP/1.1\r\nHost: localhost\r\n\r\n", &config);
+        assert!(result.is_err());
+        assert_eq!(response_header(&response, "X-Server-Id"), None);
+    }
+
+    #[test]
+    fn check_response_headers_rejects_invalid() {
+        let valid = with_extra_headers(&[("X-Server-Id", "echo-1"), ("X-Tab", "a\tb")]);
+        assert!(check_response_headers(&valid).is_ok());
+        let invalid = [
+            // 握手生成的头, 不区分大小写
+            ("Upgrade", "h2c"),
+            ("connection", "close"),
+            ("SEC-WEBSOCKET-ACCEPT", "x"),
+            ("Sec-WebSocket-Protocol", "chat"),
+            ("Sec-WebSocket-Extensions", "permessage-deflate"),
+            ("Content-Length", "0"),
+            ("Transfer-Encoding", "chunked"),
+            // 名字不是 token
+            ("", "x"),
+            ("X Server", "x"),
+            ("X-Server:", "x"),
+            ("X-Server\r\nX-Injected", "x"),
+            // 值里的换行可以注入新的头信息
+            ("X-Server-Id", "a\r\nX-Injected: b"),
+            ("X-Server-Id", "a\nb"),
+            ("X-Server-Id", "a\0b"),
+        ];
+        for (name, value) in invalid {
+            let err = check_response_headers(&with_extra_headers(&[("X-Ok", "ok"), (name, value)]))
+                .expect_err(name);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+    }
+}
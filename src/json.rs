@@ -0,0 +1,86 @@
+// 调试 JSON over WebSocket 的接口时使用, text 消息按 JSON 解析之后格式化再返回
+// binary 消息原样返回
+use crate::{Message, MessageHandler};
+use serde_json::Value;
+
+// text 消息不是合法的 JSON 时怎么处理
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidJson {
+    // 用 1007 (invalid frame payload data) 关闭连接
+    #[default]
+    Close,
+    // 回复 {"error": "..."}, 连接继续
+    Error,
+    // 原样返回
+    Echo,
+}
+
+// 默认缩进两个空格输出, compact 时输出成一行
+// 对象的 key 按字典序排列, 相同的 JSON 值总是得到相同的文本
+#[derive(Debug, Clone, Copy)]
+pub struct JsonHandler {
+    pretty: bool,
+    invalid: InvalidJson,
+}
+
+impl JsonHandler {
+    pub fn new() -> Self {
+        JsonHandler {
+            pretty: true,
+            invalid: InvalidJson::Close,
+        }
+    }
+
+    // 是否缩进输出, 默认 true
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    pub fn on_invalid(mut self, invalid: InvalidJson) -> Self {
+        self.invalid = invalid;
+        self
+    }
+
+    fn format(&self, value: &Value) -> String {
+        // 序列化 Value 不会失败
+        if self.pretty {
+            serde_json::to_string_pretty(value).unwrap()
+        } else {
+            serde_json::to_string(value).unwrap()
+        }
+    }
+}
+
+impl Default for JsonHandler {
+    fn default() -> Self {
+        JsonHandler::new()
+    }
+}
+
+impl MessageHandler for JsonHandler {
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        let Message::Text(text) = msg else {
+            return Some(msg);
+        };
+        let err = match serde_json::from_str::<Value>(&text) {
+            Ok(value) => return Some(Message::Text(self.format(&value))),
+            Err(err) => err,
+        };
+        match self.invalid {
+            InvalidJson::Close => Some(Message::Close {
+                code: Some(1007),
+                reason: "invalid JSON".to_string(),
+            }),
+            InvalidJson::Error => {
+                let error = serde_json::json!({ "error": format!("invalid JSON: {}", err) });
+                Some(Message::Text(self.format(&error)))
+            }
+            InvalidJson::Echo => Some(Message::Text(text)),
+        }
+    }
+
+    fn echoes_binary(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,187 @@
+mod access_log;
+#[cfg(feature = "tokio")]
+mod async_server;
+mod broadcast;
+mod client;
+mod connection;
+mod deflate;
+mod error;
+mod extensions;
+mod forwarded;
+mod handler;
+mod handshake;
+#[cfg(feature = "json")]
+mod json;
+mod listener;
+mod mask;
+mod message;
+mod metrics;
+mod proxy_protocol;
+mod rate_limit;
+mod server;
+mod session;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use access_log::AccessLog;
+#[cfg(feature = "tokio")]
+pub use async_server::{AsyncServer, AsyncShutdownHandle};
+pub use broadcast::BroadcastHandler;
+pub use client::Client;
+pub use connection::{FlushPolicy, Sender};
+pub use deflate::DeflateParams;
+pub use error::WsError;
+pub use extensions::{Extension, ExtensionHandler};
+pub use forwarded::{IpNetwork, ParseNetworkError};
+pub use handler::{
+    handler_fn, Chunk, CloseReason, ConnectionContext, EchoAction, EchoHandler, EchoPolicy,
+    FnHandler, HandlerFactory, MessageHandler, SwapOpcodeHandler,
+};
+pub use handshake::{compute_accept, handshake, Handshake, HandshakeError, Request};
+#[cfg(feature = "json")]
+pub use json::{InvalidJson, JsonHandler};
+pub use listener::TcpKeepalive;
+pub use mask::MaskKeys;
+pub use message::{decode_message, DataType, Decoder, Frame, Frames, Message, ProtocolError};
+pub use metrics::{ConnectionStats, Metrics};
+pub use rate_limit::{RateLimit, RateLimitAction};
+pub use server::{Server, ServerBuilder, ShutdownHandle};
+
+use std::{sync::Arc, thread, time::Duration};
+
+// 服务端配置
+pub struct Config {
+    // 单个 frame 允许的最大 payload 长度
+    pub max_payload_length: u64,
+    // 分片拼接之后整条消息允许的最大长度
+    pub max_message_length: u64,
+    // 一条消息最多由多少个 frame 组成, 超过时用 1009 关闭连接
+    // 防止客户端一直发送很小的分片, 总长度没有超过 max_message_length 但是消息永远不结束
+    pub max_fragments: usize,
+    // 服务端只能处理 text 或者只能处理 binary 时, 收到另一种消息用 1003 关闭连接, None 表示都可以
+    pub data_type: Option<DataType>,
+    // 握手请求最多有多少行头信息, 超过时返回 431, 默认 100
+    pub max_header_count: usize,
+    // 请求行和每一行头信息的最大字节数, 请求行超过时返回 414, 头信息超过时返回 431, 默认 8 KiB
+    pub max_header_line_bytes: usize,
+    // 请求行和头信息加起来的最大字节数, 超过时返回 431, 默认 16 KiB
+    pub max_total_header_bytes: usize,
+    // 接受 websocket 握手之前的 CONNECT host:port 请求, 回复 200 之后在同一个连接上握手
+    // 用于经过只支持 CONNECT 的代理的客户端, 默认关闭
+    pub connect_tunnel: bool,
+    // 支持的子协议, 按优先级排列
+    pub protocols: Vec<String>,
+    // 是否支持 permessage-deflate 压缩扩展
+    pub permessage_deflate: bool,
+    // 协商了 permessage-deflate 时, payload 短于这个长度的消息不压缩直接发送, 0 表示总是压缩
+    pub deflate_min_size: usize,
+    // 处理连接的 worker 线程数量, 也是同时处理的最大连接数
+    pub worker_count: usize,
+    // 同时在线的连接数量上限, 达到上限之后新的握手返回 503, None 表示不限制
+    pub max_connections: Option<usize>,
+    // 同一个客户端 ip 同时在线的连接数量上限, 经过信任的代理时按 X-Forwarded-For 的地址计算
+    pub max_connections_per_ip: Option<usize>,
+    // 连接空闲超过这个时间时发送 ping, None 表示不发送
+    pub ping_interval: Option<Duration>,
+    // 等待 pong 的时间, 超时之后关闭连接
+    pub pong_timeout: Duration,
+    // 握手之后读数据的超时时间, 超时之后用 1001 关闭连接
+    pub read_timeout: Option<Duration>,
+    // 一个 frame 的第一个字节到达之后, 需要在这个时间之内收完整个 frame, None 表示不限制
+    pub frame_timeout: Option<Duration>,
+    // handler 原样返回 binary 消息时, 超过这个长度的 frame 直接分块写回, 不读到内存里
+    pub stream_threshold: Option<u64>,
+    // 监听 ipv6 地址时是否只接受 ipv6 的连接, 默认同时接受 ipv4 和 ipv6
+    pub ipv6_only: bool,
+    // 是否关闭 Nagle 算法, 小的 frame 不用等待合并就发送出去
+    pub tcp_nodelay: bool,
+    // 接受的 tcp 连接开启 SO_KEEPALIVE, 由内核检测已经断开的对端, None 表示不开启
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    // 监听之前设置 SO_REUSEADDR, 服务重启之后可以马上 bind 同一个地址
+    pub reuse_address: bool,
+    // 监听之前设置 SO_REUSEPORT, 可以启动多个进程监听同一个端口 (只在 unix 上生效)
+    pub reuse_port: bool,
+    // listen 的等待队列长度, 还没有被 accept 的连接超过这个数量时新的连接会被丢弃
+    pub backlog: i32,
+    // 连接的最开始是负载均衡发送的 PROXY protocol 头 (v1 或者 v2), 日志和访问日志使用其中的客户端地址
+    // 开启之后没有这个头的连接会被直接关闭
+    pub proxy_protocol: bool,
+    // 信任的反向代理, 连接的对端在这些网段里时使用 X-Forwarded-For 里的客户端地址, 为空时不使用
+    pub trusted_proxies: Vec<IpNetwork>,
+    // 不带升级头信息的 GET 请求访问这个路径时返回 200, 用作健康检查, None 表示不开启
+    pub health_path: Option<String>,
+    // 不带升级头信息的 GET 请求访问这个路径时返回测试页面, 页面连接回同一个地址, None 表示不开启
+    pub serve_demo_page: Option<String>,
+    // 允许的 Origin, 例如 https://example.com, 为空时不检查
+    pub allowed_origins: Vec<String>,
+    // 每个连接收消息的频率限制, None 表示不限制
+    pub rate_limit: Option<RateLimit>,
+    // 不带升级头信息的 GET 请求访问这个路径时返回 prometheus 格式的统计数据, None 表示不开启
+    pub metrics_path: Option<String>,
+    // 从接受连接到收完握手请求的最长时间, 超时之后返回 408 并关闭连接
+    pub handshake_timeout: Option<Duration>,
+    // 回复的消息什么时候 flush, 默认每条回复之后马上 flush
+    pub flush_policy: FlushPolicy,
+    // text 和 binary 消息是交给 handler, 丢弃还是关闭连接, 默认都交给 handler
+    pub echo_policy: EchoPolicy,
+    // 一次写操作最长阻塞的时间, 客户端不再接收数据时断开连接, None 表示一直等待
+    pub write_timeout: Option<Duration>,
+    // 连接建立超过这个时间之后用 1001 关闭, 不管是否有数据, 可以让客户端重新连接到其他的服务端
+    pub max_connection_duration: Option<Duration>,
+    // 关闭服务时等待客户端回复 close 帧的最长时间, 超过之后直接断开剩下的连接
+    pub shutdown_grace_period: Duration,
+    // 按顺序追加到 101 响应里的头, 不能是 Upgrade 之类由握手生成的头, bind 时检查
+    pub extra_response_headers: Vec<(String, String)>,
+    // 注册的扩展, 握手时按客户端 offer 的顺序协商
+    pub extensions: Vec<Arc<dyn ExtensionHandler>>,
+    // 握手的访问日志, None 表示不记录
+    pub access_log: Option<AccessLog>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_payload_length: 16 * 1024 * 1024,
+            max_message_length: 64 * 1024 * 1024,
+            max_fragments: 1024,
+            data_type: None,
+            extra_response_headers: Vec::new(),
+            connect_tunnel: false,
+            max_header_count: handshake::MAX_HEADERS,
+            max_header_line_bytes: handshake::MAX_HEADER_LINE,
+            max_total_header_bytes: handshake::MAX_HEADER_SIZE,
+            protocols: Vec::new(),
+            permessage_deflate: true,
+            deflate_min_size: 0,
+            worker_count: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_connections: None,
+            max_connections_per_ip: None,
+            ping_interval: None,
+            pong_timeout: Duration::from_secs(10),
+            read_timeout: None,
+            frame_timeout: None,
+            stream_threshold: None,
+            ipv6_only: false,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 128,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            health_path: Some("/healthz".to_string()),
+            serve_demo_page: None,
+            allowed_origins: Vec::new(),
+            rate_limit: None,
+            metrics_path: None,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            flush_policy: FlushPolicy::Immediate,
+            echo_policy: EchoPolicy::default(),
+            write_timeout: Some(Duration::from_secs(30)),
+            max_connection_duration: None,
+            shutdown_grace_period: Duration::from_secs(5),
+            extensions: Vec::new(),
+            access_log: None,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// 创建监听的 socket, 在 bind 之前设置 std 没有提供的选项
+use crate::Config;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+use std::{
+    io::{self, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    time::Duration,
+};
+
+// fd 或者内存用完时马上重试 accept 还是会失败, 等一会让其他连接有机会关闭
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// accept 失败之后重试之前需要等待的时间, None 表示 listener 不能再使用
+pub(crate) fn accept_backoff(err: &io::Error) -> Option<Duration> {
+    match err.kind() {
+        // 客户端在 accept 之前断开了连接, 和 listener 本身无关
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => Some(Duration::ZERO),
+        // EINVAL: socket 已经不在监听状态
+        io::ErrorKind::InvalidInput => None,
+        // EMFILE, ENFILE, ENOBUFS, ENOMEM 等资源不足的错误
+        _ => Some(ACCEPT_BACKOFF),
+    }
+}
+
+// TCP keepalive: 连接空闲 idle 之后内核开始发送探测包, 每隔 interval 发送一次,
+// 连续 retries 次没有回应时断开连接, 读写马上返回错误
+// interval 和 retries 是 None 时使用系统的默认值 (linux 上是 75 秒和 9 次)
+// windows 不支持设置 retries (固定是 10 次), 其他不支持的平台上只设置 idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    pub fn new(idle: Duration) -> Self {
+        TcpKeepalive {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+}
+
+// 给 accept 的连接开启 SO_KEEPALIVE, 同步和异步的 TcpStream 都可以使用
+pub(crate) fn set_keepalive<'s, S>(stream: &'s S, keepalive: &TcpKeepalive) -> io::Result<()>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let params = match keepalive.interval {
+        Some(interval) => params.with_interval(interval),
+        None => params,
+    };
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+    ))]
+    let params = match keepalive.retries {
+        Some(retries) => params.with_retries(retries),
+        None => params,
+    };
+    SockRef::from(stream).set_tcp_keepalive(&params)
+}
+
+// 和 TcpListener::bind 一样, 依次尝试解析出来的每个地址, 返回第一个成功的
+pub(crate) fn bind(addr: impl ToSocketAddrs, config: &Config) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_addr(addr, config) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_addr(addr: SocketAddr, config: &Config) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // 重启时不会因为 TIME_WAIT 的连接 bind 失败
+    // windows 上 SO_REUSEADDR 允许抢占正在使用的端口, 和 std 一样不设置
+    #[cfg(not(windows))]
+    socket.set_reuse_address(config.reuse_address)?;
+    // 多个进程监听同一个端口, 由内核分配连接
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        // 默认关闭 IPV6_V6ONLY, 监听 [::] 时 ipv4 的客户端也可以连接
+        // 不支持双栈的平台上设置会失败, 这时只监听 ipv6
+        let _ = socket.set_only_v6(config.ipv6_only);
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+    Ok(socket.into())
+}
+
+// 监听 unix socket, 清理上次运行留下的 socket 文件
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path, config: &Config) -> io::Result<Listener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        // 不是 socket 的文件不能删
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        // 还能连接上说明有其他的服务正在使用, 否则是没有清理的旧文件
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(config.backlog)?;
+    Ok(Listener::Unix(UnixSocket {
+        listener: UnixListener::from(socket),
+        path: path.to_path_buf(),
+    }))
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+// unix socket 的文件在服务结束时删除
+#[cfg(unix)]
+pub(crate) struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Listener {
+    // unix socket 的客户端没有地址
+    pub(crate) fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let (stream, _) = socket.listener.accept()?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix socket has no socket address",
+            )),
+        }
+    }
+
+    // 连接一次自己, 让阻塞在 accept 上的线程返回
+    // unix socket 的文件这时就删除, 不需要等到 drop
+    pub(crate) fn shutdown(&self) {
+        match self {
+            Listener::Tcp(listener) => {
+                if let Ok(addr) = listener.local_addr() {
+                    let _ = TcpStream::connect(wake_addr(addr));
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let _ = UnixStream::connect(&socket.path);
+                let _ = fs::remove_file(&socket.path);
+            }
+        }
+    }
+}
+
+// 监听在 0.0.0.0 或 [::] 上时通过回环地址连接
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    addr
+}
+
+// 接受的连接, 和 TcpStream 一样可以通过引用读写
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    // unix socket 没有 Nagle 算法, 不需要设置
+    pub(crate) fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    // unix socket 的对端在同一台机器上, 不需要 keepalive
+    pub(crate) fn set_keepalive(&self, keepalive: &TcpKeepalive) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => set_keepalive(stream, keepalive),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -1,193 +1,153 @@
-use base64::{engine::general_purpose, Engine as _};
-use ring::digest;
-use std::{
-    collections::BTreeMap,
-    error::Error,
-    io::{self, BufRead, BufReader, BufWriter, Write},
-    net::TcpListener,
-};
+use log::{error, info};
+use std::{env, error::Error, net::SocketAddr, process};
+use ws_server::{AccessLog, IpNetwork, MessageHandler, Server};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind("0.0.0.0:8080")?;
-
-    while let Ok((stream, _)) = listener.accept() {
-        let mut reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-        handshake(&mut reader, &mut writer)?;
-        handle_connection(&mut reader, &mut writer)?;
-    }
-
-    Ok(())
-}
+const DEFAULT_ADDR: &str = "0.0.0.0:8080";
 
-fn handle_connection(
-    reader: &mut impl BufRead,
-    writer: &mut impl Write,
-) -> Result<(), Box<dyn Error>> {
-    while let Ok(message) = decode_message(reader) {
-        writer.write_all(&message.encode())?;
-        writer.flush()?;
+fn main() -> Result<(), Box<dyn Error>> {
+    // 日志级别通过 RUST_LOG 控制, 默认是 info
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // 监听地址: 命令行参数 > 环境变量 WS_ECHO_ADDR > 默认值, 多个 tcp 地址用逗号分隔
+    let addr = env::args()
+        .nth(1)
+        .or_else(|| env::var("WS_ECHO_ADDR").ok())
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let mut builder = Server::builder();
+    // 同时在线的连接数量上限, 超过之后新的连接收到 503
+    if let Ok(max_connections) = env::var("WS_MAX_CONNECTIONS") {
+        match max_connections.parse() {
+            Ok(max_connections) => builder = builder.max_connections(max_connections),
+            Err(err) => {
+                error!("invalid WS_MAX_CONNECTIONS {:?}: {}", max_connections, err);
+                process::exit(1);
+            }
+        }
     }
-    Ok(())
-}
-
-// 握手
-fn handshake(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
-    let mut buffer = String::new();
-    let size = reader.read_line(&mut buffer)?;
-    // 读取 http 请求行
-    let request_line: &str = &buffer[0..size];
-    let _ = request_line;
-    buffer.truncate(0);
-
-    let mut headers = BTreeMap::<String, String>::new();
-
-    loop {
-        let size = reader.read_line(&mut buffer)?;
-        // 读取每一个头信息
-        let header_line: &str = &buffer[0..size];
-        // 头信息完结
-        if header_line == "\r\n" {
-            break;
+    if let Ok(max_connections_per_ip) = env::var("WS_MAX_CONNECTIONS_PER_IP") {
+        match max_connections_per_ip.parse() {
+            Ok(max_connections_per_ip) => {
+                builder = builder.max_connections_per_ip(max_connections_per_ip)
+            }
+            Err(err) => {
+                error!(
+                    "invalid WS_MAX_CONNECTIONS_PER_IP {:?}: {}",
+                    max_connections_per_ip, err
+                );
+                process::exit(1);
+            }
         }
-
-        let header_line = &header_line[0..(size - 2)];
-
-        if let Some((k, v)) = header_line.split_once(':') {
-            headers.insert(k.to_lowercase(), v.trim_start().into());
+    }
+    // 逗号分隔的 Origin 列表, 设置之后只接受这些页面发起的连接
+    if let Ok(origins) = env::var("WS_ALLOWED_ORIGINS") {
+        builder = builder.allowed_origins(origins.split(',').map(str::trim));
+    }
+    // 设置之后在这个路径上提供 prometheus 格式的统计数据
+    if let Ok(metrics_path) = env::var("WS_METRICS_PATH") {
+        builder = builder.metrics_path(metrics_path);
+    }
+    // 浏览器打开这个路径时返回测试页面, 例如 WS_DEMO_PAGE=/
+    if let Ok(demo_page) = env::var("WS_DEMO_PAGE") {
+        builder = builder.serve_demo_page(demo_page);
+    }
+    // 握手的访问日志: stderr, log (跟着 RUST_LOG) 或者文件路径
+    if let Ok(access_log) = env::var("WS_ACCESS_LOG") {
+        let access_log = match access_log.as_str() {
+            "stderr" => AccessLog::Stderr,
+            "log" => AccessLog::Log,
+            path => match AccessLog::file(path) {
+                Ok(access_log) => access_log,
+                Err(err) => {
+                    error!("failed to open access log {}: {}", path, err);
+                    process::exit(1);
+                }
+            },
         };
-
-        buffer.truncate(0);
+        builder = builder.access_log(access_log);
     }
-
-    let sec_websocket_key = headers.get("sec-websocket-key").ok_or(io::Error::new(
-        io::ErrorKind::ConnectionRefused,
-        "no header Sec-Websocket-Key",
-    ))?;
-
-    const UUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-
-    // sha1 加 base64
-    let concat_str = [sec_websocket_key.as_bytes(), UUID].concat();
-    let hash_result = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &concat_str);
-    let sec_websocket_accept = general_purpose::STANDARD.encode(hash_result.as_ref());
-
-    let response = format!(
-        "HTTP/1.1 101 Switching Protocols\r\n\
-        Upgrade: websocket\r\n\
-        Connection: Upgrade\r\n\
-        Sec-WebSocket-Accept: {}\r\n\r\n",
-        sec_websocket_accept
-    );
-
-    writer.write_all(response.as_bytes())?;
-
-    writer.flush()?;
-
-    Ok(())
-}
-
-enum Message {
-    Text(String),
-    Binary(Vec<u8>),
-}
-
-impl Message {
-    fn as_bytes(&self) -> &[u8] {
-        match &self {
-            Message::Binary(data) => data,
-            Message::Text(data) => data.as_bytes(),
+    // 逗号分隔的网段, 例如 10.0.0.0/8,192.168.1.10, 这些反向代理发送的 X-Forwarded-For 是可信的
+    if let Ok(trusted_proxies) = env::var("WS_TRUSTED_PROXIES") {
+        let trusted_proxies: Result<Vec<IpNetwork>, _> = trusted_proxies
+            .split(',')
+            .map(|network| network.trim().parse())
+            .collect();
+        match trusted_proxies {
+            Ok(trusted_proxies) => builder = builder.trusted_proxies(trusted_proxies),
+            Err(err) => {
+                error!("invalid WS_TRUSTED_PROXIES: {}", err);
+                process::exit(1);
+            }
         }
     }
-
-    fn opcode(&self) -> u8 {
-        match &self {
-            Message::Binary(_) => 2,
-            Message::Text(_) => 1,
-        }
+    // 部署在 haproxy 或者 aws nlb 之后, 连接的最开始是 PROXY protocol 的头
+    if env::var("WS_PROXY_PROTOCOL").is_ok_and(|value| value == "1" || value == "true") {
+        builder = builder.proxy_protocol(true);
     }
-
-    fn encode(&self) -> Vec<u8> {
-        let payload_data = self.as_bytes();
-        let payload_length = payload_data.len() as u64;
-
-        // 初始的长度是 2个 字节 fin,rsv1...payload_length
-        let mut total_frame_length = 2;
-
-        if payload_length > 125 {
-            // 扩展payload_length
-            if payload_length > u16::MAX as u64 {
-                total_frame_length += 8;
-            } else {
-                total_frame_length += 2;
+    // 客户端经过只支持 CONNECT 的代理时, 握手之前先发送 CONNECT host:port
+    if env::var("WS_CONNECT_TUNNEL").is_ok_and(|value| value == "1" || value == "true") {
+        builder = builder.connect_tunnel(true);
+    }
+    // 同时设置了证书和私钥的路径时使用 wss://
+    #[cfg(feature = "tls")]
+    let builder = match (env::var("WS_TLS_CERT"), env::var("WS_TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => builder.with_tls(cert_path, key_path),
+        _ => builder,
+    };
+    // 包含 / 的地址是 unix socket 的路径
+    #[cfg(unix)]
+    if addr.contains('/') {
+        let server = match builder.bind_unix(&addr) {
+            Ok(server) => server,
+            Err(err) => {
+                error!("failed to bind {}: {}", addr, err);
+                process::exit(1);
             }
+        };
+        info!("listening on {}", addr);
+        return run(server);
+    }
+    let addrs: Vec<SocketAddr> = match addr.split(',').map(|addr| addr.trim().parse()).collect() {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            error!("invalid address {:?}: {}", addr, err);
+            process::exit(1);
         }
-
-        total_frame_length += payload_length;
-
-        let mut frame: Vec<u8> = Vec::with_capacity(total_frame_length as usize);
-
-        let opcode = self.opcode();
-        frame.push(0b1000_0000); // fin 是 1
-        frame[0] |= opcode;
-
-        if payload_length <= 125 {
-            frame.push(payload_length as u8);
-        } else if payload_length > u16::MAX as u64 {
-            frame.push(127);
-            frame.extend_from_slice(&payload_length.to_be_bytes());
-        } else {
-            frame.push(126);
-            frame.extend_from_slice(&(payload_length as u16).to_be_bytes());
+    };
+    let server = match builder.bind_all(&addrs) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to bind {}: {}", addr, err);
+            process::exit(1);
         }
-
-        // 服务端不需要 mask, 直接拼接数据
-        frame.extend_from_slice(payload_data);
-
-        frame
+    };
+    for addr in server.local_addrs()? {
+        info!("listening on {}", addr);
     }
+    run(server)
 }
 
-fn decode_message(reader: &mut impl BufRead) -> Result<Message, Box<dyn Error>> {
-    let mut buffer = [0; 2];
-    // 先获取前面两个字节
-    reader.read_exact(&mut buffer)?;
-
-    // 不考虑 fin 不为 1 的情况, 一次读取一个 frame 然后拼接成 message
-    let opcode = buffer[0] & 0b1111;
-    let mask = buffer[1] >> 7;
-    if mask != 1 {
-        // 客户端发来的消息必须是掩码的
-        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "mask require").into());
+fn run(server: Server) -> Result<(), Box<dyn Error>> {
+    // 格式化回复 JSON: pretty 或者 compact, 不合法的 JSON 用 1007 关闭连接
+    #[cfg(feature = "json")]
+    if let Ok(json) = env::var("WS_JSON") {
+        let handler = ws_server::JsonHandler::new().pretty(json != "compact");
+        return serve(server.with_handler(handler));
     }
-
-    let mut payload_length = (buffer[1] & 0b0111_1111) as u64;
-
-    if payload_length == 126 {
-        reader.read_exact(&mut buffer)?;
-        payload_length = u16::from_be_bytes(buffer) as u64;
-    } else if payload_length == 127 {
-        let mut buffer = [0; 8];
-        reader.read_exact(&mut buffer)?;
-        payload_length = u64::from_be_bytes(buffer);
+    // 测试客户端时交换回复的类型, text 用 binary 返回, binary 用 text 返回
+    if env::var("WS_SWAP_OPCODE").is_ok_and(|value| value == "1" || value == "true") {
+        return serve(server.with_handler(ws_server::SwapOpcodeHandler));
     }
+    serve(server)
+}
 
-    let mut mask_key = [0; 4];
-    reader.read_exact(&mut mask_key)?;
-
-    let mut payload_data: Vec<u8> = vec![0; payload_length as usize];
-    reader.read_exact(&mut payload_data)?;
+fn serve<H: MessageHandler + Clone + Send + 'static>(
+    server: Server<H>,
+) -> Result<(), Box<dyn Error>> {
+    // ctrl-c 和 SIGTERM 时关闭服务, run 等待连接关闭之后返回
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown())?;
 
-    // 还原原始的 payload_data
-    (0..payload_data.len()).for_each(|i| {
-        let j = i % 4;
-        let cur_mask_key = mask_key[j];
-        payload_data[i] ^= cur_mask_key;
-    });
+    server.run()?;
 
-    Ok(if opcode == 1 {
-        Message::Text(String::from_utf8_lossy(&payload_data).to_string())
-    } else {
-        Message::Binary(payload_data)
-    })
+    Ok(())
 }
@@ -0,0 +1,53 @@
+// 客户端发送的 frame 需要 mask, 这里生成 mask key
+use ring::rand::{SecureRandom, SystemRandom};
+
+// mask key 的来源, 默认使用操作系统的随机数
+// from_seed 使用固定的种子, 每次生成同样的 key 序列, 测试的输出是确定的
+pub struct MaskKeys {
+    source: Source,
+}
+
+enum Source {
+    System(SystemRandom),
+    // splitmix64 的状态, 可以预测, 只适合测试
+    Seeded(u64),
+}
+
+impl MaskKeys {
+    pub fn new() -> Self {
+        MaskKeys {
+            source: Source::System(SystemRandom::new()),
+        }
+    }
+
+    pub fn from_seed(seed: u64) -> Self {
+        MaskKeys {
+            source: Source::Seeded(seed),
+        }
+    }
+
+    pub fn next_key(&mut self) -> [u8; 4] {
+        match &mut self.source {
+            Source::System(random) => {
+                let mut key = [0; 4];
+                // 读取系统随机数失败说明系统出了问题, 没有办法继续
+                random.fill(&mut key).expect("failed to read system random");
+                key
+            }
+            Source::Seeded(state) => {
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                (z as u32).to_be_bytes()
+            }
+        }
+    }
+}
+
+impl Default for MaskKeys {
+    fn default() -> Self {
+        MaskKeys::new()
+    }
+}
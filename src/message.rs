@@ -0,0 +1,1334 @@
+use crate::{
+    deflate::{DeflateParams, Inflater},
+    error::truncated,
+    Config, MaskKeys, WsError,
+};
+use std::{borrow::Cow, error::Error, fmt, io::BufRead, mem, str};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Close { code: Option<u16>, reason: String },
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+// 只能处理一种数据消息的服务端, 见 Config::data_type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Text,
+    Binary,
+}
+
+// 违反协议时的错误, code 是关闭连接时使用的 close 状态码
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+impl ProtocolError {
+    pub(crate) fn new(code: u16, reason: &'static str) -> Self {
+        ProtocolError { code, reason }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol error {}: {}", self.code, self.reason)
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl Message {
+    pub(crate) fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match &self {
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => {
+                Cow::Borrowed(data)
+            }
+            Message::Text(data) => Cow::Borrowed(data.as_bytes()),
+            // close 帧的 payload 是 2 字节的状态码加上 utf8 的 reason
+            Message::Close { code, reason } => match code {
+                Some(code) => Cow::Owned([&code.to_be_bytes()[..], reason.as_bytes()].concat()),
+                None => Cow::Borrowed(&[]),
+            },
+        }
+    }
+
+    pub(crate) fn opcode(&self) -> u8 {
+        match &self {
+            Message::Binary(_) => 2,
+            Message::Text(_) => 1,
+            Message::Close { .. } => 8,
+            Message::Ping(_) => 9,
+            Message::Pong(_) => 10,
+        }
+    }
+
+    // payload 的字节数, close 帧包括 2 字节的状态码
+    pub fn len(&self) -> usize {
+        match self {
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+            Message::Text(data) => data.len(),
+            Message::Close { code, reason } => match code {
+                Some(_) => 2 + reason.len(),
+                None => 0,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, Message::Text(_))
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Message::Binary(_))
+    }
+
+    // 编码成服务端发送的 frame
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::new();
+        self.encode_into(&mut frame);
+        frame
+    }
+
+    // 编码到 frame 中, frame 原有的内容会被清空, 可以在多次编码之间复用
+    pub fn encode_into(&self, frame: &mut Vec<u8>) {
+        encode_frame_into(self.opcode(), false, &self.as_bytes(), frame)
+    }
+
+    // 编码成客户端发送的 frame, 每次使用一个新的 mask key, 可以用来测试服务端
+    pub fn encode_masked(&self, keys: &mut MaskKeys) -> Vec<u8> {
+        let mut frame = Vec::new();
+        self.encode_masked_into(keys, &mut frame);
+        frame
+    }
+
+    pub fn encode_masked_into(&self, keys: &mut MaskKeys, frame: &mut Vec<u8>) {
+        let payload_data = self.as_bytes();
+        let payload_length = payload_data.len() as u64;
+        let mask_key = keys.next_key();
+        frame.clear();
+        frame.reserve(header_size(payload_length) + 4 + payload_data.len());
+        write_header(frame, true, false, self.opcode(), payload_length);
+        // 第二个字节的最高位是 mask 标志, mask key 跟在长度后面
+        frame[1] |= 0b1000_0000;
+        frame.extend_from_slice(&mask_key);
+        let start = frame.len();
+        frame.extend_from_slice(&payload_data);
+        apply_mask(&mut frame[start..], mask_key, 0);
+    }
+
+    // 取出 payload, 不需要复制数据
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+            Message::Text(data) => data.into_bytes(),
+            message @ Message::Close { .. } => message.as_bytes().into_owned(),
+        }
+    }
+}
+
+impl From<String> for Message {
+    fn from(data: String) -> Self {
+        Message::Text(data)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+// 编码一个完整的 frame, rsv1 表示 payload 是压缩过的
+pub(crate) fn encode_frame_into(opcode: u8, rsv1: bool, payload_data: &[u8], frame: &mut Vec<u8>) {
+    let payload_length = payload_data.len() as u64;
+    frame.clear();
+    frame.reserve(header_size(payload_length) + payload_data.len());
+    write_header(frame, true, rsv1, opcode, payload_length);
+
+    // 服务端不需要 mask, 直接拼接数据
+    frame.extend_from_slice(payload_data);
+}
+
+fn header_size(payload_length: u64) -> usize {
+    // 初始的长度是 2个 字节 fin,rsv1...payload_length
+    match payload_length {
+        0..=125 => 2,
+        // 扩展payload_length
+        126..=0xffff => 2 + 2,
+        _ => 2 + 8,
+    }
+}
+
+// 写入服务端发送的 frame 的头部, 服务端的 frame 没有 mask key
+pub(crate) fn write_header(
+    frame: &mut Vec<u8>,
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload_length: u64,
+) {
+    let mut first = opcode;
+    if fin {
+        first |= 0b1000_0000;
+    }
+    if rsv1 {
+        first |= 0b0100_0000;
+    }
+    frame.push(first);
+
+    if payload_length <= 125 {
+        frame.push(payload_length as u8);
+    } else if payload_length > u16::MAX as u64 {
+        frame.push(127);
+        frame.extend_from_slice(&payload_length.to_be_bytes());
+    } else {
+        frame.push(126);
+        frame.extend_from_slice(&(payload_length as u16).to_be_bytes());
+    }
+}
+
+// 一个 websocket frame, payload 已经还原了掩码
+// Frames 逐个返回原始的 frame, Decoder 把同样的 frame 拼接成消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    fin: bool,
+    // rsv1, rsv2, rsv3 三个位, 含义由协商的扩展决定
+    rsv: u8,
+    opcode: u8,
+    masked: bool,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    // 是否是消息的最后一个分片
+    pub fn is_final(&self) -> bool {
+        self.fin
+    }
+
+    pub fn rsv1(&self) -> bool {
+        self.rsv & 0b100 != 0
+    }
+
+    pub fn rsv2(&self) -> bool {
+        self.rsv & 0b010 != 0
+    }
+
+    pub fn rsv3(&self) -> bool {
+        self.rsv & 0b001 != 0
+    }
+
+    // 客户端发送的 frame 是掩码的, 服务端发送的不是
+    pub fn is_masked(&self) -> bool {
+        self.masked
+    }
+
+    // payload 的字节数
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+// 从 frame 头部解析出来的信息
+pub(crate) struct FrameHeader {
+    pub(crate) fin: bool,
+    pub(crate) rsv: u8,
+    pub(crate) opcode: u8,
+    pub(crate) masked: bool,
+    // 没有掩码时是 0, 异或之后数据不变
+    pub(crate) mask_key: [u8; 4],
+    pub(crate) payload_length: usize,
+}
+
+impl FrameHeader {
+    pub(crate) fn rsv1(&self) -> bool {
+        self.rsv & 0b100 != 0
+    }
+}
+
+// 下面几个函数只处理内存中的字节, 同步和异步的读取共用这部分逻辑
+
+// 检查 frame 的前两个字节, 返回完整头部 (包括扩展长度和 mask key) 的长度
+// allow_rsv1 表示协商了使用 rsv1 的扩展
+// masked 表示 frame 必须是掩码的 (服务端收到的 frame), false 时必须不是 (客户端收到的 frame)
+pub(crate) fn header_length(
+    buffer: [u8; 2],
+    allow_rsv1: bool,
+    masked: bool,
+) -> Result<usize, WsError> {
+    let rsv = (buffer[0] >> 4) & 0b111;
+    let opcode = buffer[0] & 0b1111;
+
+    // 没有协商扩展时 rsv1, rsv2, rsv3 都必须是 0
+    let allowed_rsv = if allow_rsv1 { 0b100 } else { 0 };
+    if rsv & !allowed_rsv != 0 {
+        return Err(ProtocolError::new(1002, "reserved bits must be zero").into());
+    }
+
+    // 0x3-0x7 和 0xB-0xF 是保留的 opcode
+    if !matches!(opcode, 0 | 1 | 2 | 8 | 9 | 10) {
+        return Err(ProtocolError::new(1002, "reserved opcode").into());
+    }
+
+    let mask = buffer[1] >> 7 == 1;
+    if masked && !mask {
+        // 客户端发来的消息必须是掩码的
+        return Err(ProtocolError::new(1002, "frame must be masked").into());
+    }
+    if !masked && mask {
+        // 服务端发来的消息不能是掩码的
+        return Err(ProtocolError::new(1002, "frame must not be masked").into());
+    }
+
+    Ok(raw_header_length(buffer))
+}
+
+// 不做任何检查, 只根据长度和 mask 标志计算完整头部的长度
+fn raw_header_length(buffer: [u8; 2]) -> usize {
+    let mask_length = if buffer[1] >> 7 == 1 { 4 } else { 0 };
+    let length = match buffer[1] & 0b0111_1111 {
+        126 => 2 + 2,
+        127 => 2 + 8,
+        _ => 2,
+    };
+    length + mask_length
+}
+
+// 解析完整的 frame 头部并检查控制帧, header 的长度必须是 header_length 返回的长度
+pub(crate) fn parse_header(header: &[u8], max_payload_length: u64) -> Result<FrameHeader, WsError> {
+    // 长度必须使用最短的编码, 例如不能用 2 字节的扩展长度表示 100, 8 字节的长度最高位必须是 0
+    let minimal = match header[1] & 0b0111_1111 {
+        126 => u16::from_be_bytes([header[2], header[3]]) > 125,
+        127 => {
+            let length = u64::from_be_bytes(header[2..10].try_into().unwrap());
+            length > u16::MAX as u64 && length >> 63 == 0
+        }
+        _ => true,
+    };
+    if !minimal {
+        return Err(ProtocolError::new(1002, "non-minimal payload length").into());
+    }
+    let header = parse_raw_header(header, max_payload_length)?;
+    // 控制帧不能分片, payload 不能超过 125 字节
+    if header.opcode >= 8 && !header.fin {
+        return Err(ProtocolError::new(1002, "fragmented control frame").into());
+    }
+    if header.opcode >= 8 && header.payload_length > 125 {
+        return Err(ProtocolError::new(1002, "control frame too long").into());
+    }
+    Ok(header)
+}
+
+// 只检查 payload 的长度, rsv, opcode 和 mask 原样保留
+fn parse_raw_header(header: &[u8], max_payload_length: u64) -> Result<FrameHeader, WsError> {
+    let fin = header[0] >> 7 == 1;
+    let rsv = (header[0] >> 4) & 0b111;
+    let opcode = header[0] & 0b1111;
+    let masked = header[1] >> 7 == 1;
+
+    let (payload_length, rest) = match header[1] & 0b0111_1111 {
+        126 => (
+            u16::from_be_bytes([header[2], header[3]]) as u64,
+            &header[4..],
+        ),
+        127 => {
+            let mut buffer = [0; 8];
+            buffer.copy_from_slice(&header[2..10]);
+            (u64::from_be_bytes(buffer), &header[10..])
+        }
+        payload_length => (payload_length as u64, &header[2..]),
+    };
+
+    // 在分配内存之前检查长度, 防止恶意的超大长度导致 OOM
+    if payload_length > max_payload_length {
+        return Err(ProtocolError::new(1009, "message too big").into());
+    }
+    // 32 位平台上 u64 转 usize 可能截断, 截断之后 read_exact 读的比声明的少,
+    // 剩下的 payload 会被当成下一个 frame 解析, 所以在转换之前用 1009 拒绝
+    let payload_length =
+        usize::try_from(payload_length).map_err(|_| ProtocolError::new(1009, "message too big"))?;
+
+    let mut mask_key = [0; 4];
+    if masked {
+        mask_key.copy_from_slice(rest);
+    }
+
+    Ok(FrameHeader {
+        fin,
+        rsv,
+        opcode,
+        masked,
+        mask_key,
+        payload_length,
+    })
+}
+
+impl FrameHeader {
+    // 从 reader 中读取 payload, 组成完整的 frame
+    // payload 读到 buffer 里, 返回的 frame 拿走 buffer 的内存
+    pub(crate) fn read_frame(
+        self,
+        reader: &mut impl BufRead,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Frame, WsError> {
+        let mut payload_data = mem::take(buffer);
+        payload_data.clear();
+        payload_data.resize(self.payload_length, 0);
+        reader.read_exact(&mut payload_data).map_err(truncated)?;
+        Ok(self.into_frame(payload_data))
+    }
+
+    // 用读到的 payload 组成 frame, 同时还原被掩码的数据
+    pub(crate) fn into_frame(self, mut payload_data: Vec<u8>) -> Frame {
+        if self.masked {
+            apply_mask(&mut payload_data, self.mask_key, 0);
+        }
+
+        Frame {
+            fin: self.fin,
+            rsv: self.rsv,
+            opcode: self.opcode,
+            masked: self.masked,
+            payload: payload_data,
+        }
+    }
+}
+
+// 还原被掩码的数据, offset 是 data 在整个 payload 中的位置
+// 每次处理 8 个字节, 比逐个字节处理快很多, 结果完全一样
+pub(crate) fn apply_mask(data: &mut [u8], mask_key: [u8; 4], offset: usize) {
+    // 从 offset 开始的 key, 之后每 4 个字节重复一次
+    let mut key = mask_key;
+    key.rotate_left(offset % 4);
+    let [a, b, c, d] = key;
+    let word_key = u64::from_ne_bytes([a, b, c, d, a, b, c, d]);
+
+    let mut words = data.chunks_exact_mut(8);
+    for word in &mut words {
+        let masked = u64::from_ne_bytes(word.try_into().unwrap()) ^ word_key;
+        word.copy_from_slice(&masked.to_ne_bytes());
+    }
+    // 剩下的不到 8 个字节也是从 key 的开头对齐的
+    for (byte, key) in words.into_remainder().iter_mut().zip(key.iter().cycle()) {
+        *byte ^= key;
+    }
+}
+
+// 读取并解析 frame 的头部, payload 留在 reader 里
+fn read_header(
+    reader: &mut impl BufRead,
+    max_payload_length: u64,
+    allow_rsv1: bool,
+    masked: bool,
+) -> Result<FrameHeader, WsError> {
+    // 在 frame 的边界上读不到数据, 说明客户端正常断开了连接
+    if reader.fill_buf()?.is_empty() {
+        return Err(WsError::ConnectionClosed);
+    }
+    let mut buffer = [0; 14];
+    // 先获取前面两个字节
+    reader.read_exact(&mut buffer[..2]).map_err(truncated)?;
+    let length = header_length([buffer[0], buffer[1]], allow_rsv1, masked)?;
+    reader
+        .read_exact(&mut buffer[2..length])
+        .map_err(truncated)?;
+    parse_header(&buffer[..length], max_payload_length)
+}
+
+// 和 read_header 一样, 但是不按服务端的要求检查
+fn read_raw_header(
+    reader: &mut impl BufRead,
+    max_payload_length: u64,
+) -> Result<FrameHeader, WsError> {
+    if reader.fill_buf()?.is_empty() {
+        return Err(WsError::ConnectionClosed);
+    }
+    let mut buffer = [0; 14];
+    reader.read_exact(&mut buffer[..2]).map_err(truncated)?;
+    let length = raw_header_length([buffer[0], buffer[1]]);
+    reader
+        .read_exact(&mut buffer[2..length])
+        .map_err(truncated)?;
+    parse_raw_header(&buffer[..length], max_payload_length)
+}
+
+// 逐个读取原始的 frame, 不拼接分片, 也不检查 rsv, opcode 和 mask, 用来调试协议或者实现自定义的扩展
+// 只限制 payload 的长度, 在 frame 的边界上读到 EOF 时结束, 出错之后也不再继续读取
+pub struct Frames<'a, R> {
+    reader: &'a mut R,
+    max_payload_length: u64,
+    done: bool,
+}
+
+impl<'a, R: BufRead> Frames<'a, R> {
+    pub fn new(reader: &'a mut R, config: &Config) -> Self {
+        Frames {
+            reader,
+            max_payload_length: config.max_payload_length,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Frames<'_, R> {
+    type Item = Result<Frame, WsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let frame = read_raw_header(self.reader, self.max_payload_length)
+            .and_then(|header| header.read_frame(self.reader, &mut Vec::new()));
+        match frame {
+            Ok(frame) => Some(Ok(frame)),
+            Err(WsError::ConnectionClosed) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// 每个连接一个 Decoder, 保存分片消息拼接的中间状态
+pub struct Decoder {
+    pub(crate) max_payload_length: u64,
+    // 分片拼接之后 (以及解压之后) 整条消息的最大长度
+    max_message_length: u64,
+    max_fragments: usize,
+    // 当前消息已经收到的 frame 数量, 包括分块写回的
+    fragments: usize,
+    // 未完成的分片消息: 首帧的 opcode, 是否压缩, 已拼接的数据
+    fragment: Option<(u8, bool, Vec<u8>)>,
+    // 协商了 permessage-deflate 时用来解压消息
+    inflater: Option<Inflater>,
+    // 正在分块写回的分片消息, 已经写回的长度
+    streaming: Option<u64>,
+    // 服务端收到的 frame 必须是掩码的, 客户端收到的不能是
+    pub(crate) masked: bool,
+    // 只接受一种数据消息, None 表示两种都接受
+    data_type: Option<DataType>,
+}
+
+impl Decoder {
+    pub fn new(config: &Config, deflate: Option<&DeflateParams>) -> Self {
+        Decoder {
+            max_payload_length: config.max_payload_length,
+            max_message_length: config.max_message_length,
+            max_fragments: config.max_fragments,
+            fragments: 0,
+            fragment: None,
+            inflater: deflate.map(Inflater::new),
+            streaming: None,
+            masked: true,
+            data_type: config.data_type,
+        }
+    }
+
+    // 客户端使用, 解码服务端发来的没有掩码的 frame
+    pub(crate) fn client(config: &Config) -> Self {
+        Decoder {
+            masked: false,
+            ..Decoder::new(config, None)
+        }
+    }
+
+    pub fn decode_message(&mut self, reader: &mut impl BufRead) -> Result<Message, WsError> {
+        self.decode_message_into(reader, &mut Vec::new())
+    }
+
+    // payload 读到 buffer 里, 返回的消息会拿走 buffer 的内存
+    // 用完之后把消息的内存放回 buffer (例如 Message::into_bytes), 下一条消息就不需要重新分配
+    pub fn decode_message_into(
+        &mut self,
+        reader: &mut impl BufRead,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Message, WsError> {
+        loop {
+            let frame = self.read_header(reader)?.read_frame(reader, buffer)?;
+            if let Some(message) = self.push_frame(frame, buffer)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    pub(crate) fn allow_rsv1(&self) -> bool {
+        self.inflater.is_some()
+    }
+
+    pub(crate) fn read_header(&self, reader: &mut impl BufRead) -> Result<FrameHeader, WsError> {
+        let header = read_header(
+            reader,
+            self.max_payload_length,
+            self.allow_rsv1(),
+            self.masked,
+        )?;
+        self.check_data_type(&header)?;
+        Ok(header)
+    }
+
+    // 不接受的类型在第一个 frame 的头到达时就用 1003 关闭, 不需要读取 payload
+    pub(crate) fn check_data_type(&self, header: &FrameHeader) -> Result<(), ProtocolError> {
+        match (self.data_type, header.opcode) {
+            (Some(DataType::Text), 2) => Err(ProtocolError::new(
+                1003,
+                "binary messages are not supported",
+            )),
+            (Some(DataType::Binary), 1) => {
+                Err(ProtocolError::new(1003, "text messages are not supported"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 判断这个 frame 是否直接分块处理, 不经过 push_frame
+    // 超过 threshold 的 binary frame 开始一条分块处理的消息, 这条消息之后的分片也都分块处理
+    // text 和 binary 是哪些类型的消息可以分块处理, text 由调用方分块检查 utf8
+    pub(crate) fn stream_frame(
+        &mut self,
+        header: &FrameHeader,
+        threshold: u64,
+        text: bool,
+        binary: bool,
+    ) -> Result<bool, WsError> {
+        let streamed = match (header.opcode, self.streaming) {
+            (0, Some(streamed)) => streamed,
+            // 写回了一部分的消息还没有结束, 不能开始新的消息
+            (1 | 2, Some(_)) => {
+                return Err(ProtocolError::new(1002, "expected continuation frame").into())
+            }
+            // 压缩的消息需要完整解压, 不能分块处理
+            (opcode @ (1 | 2), None)
+                if (if opcode == 1 { text } else { binary })
+                    && !header.rsv1()
+                    && self.fragment.is_none()
+                    && header.payload_length as u64 > threshold =>
+            {
+                0
+            }
+            _ => return Ok(false),
+        };
+        if header.rsv1() {
+            return Err(ProtocolError::new(1002, "unexpected rsv1").into());
+        }
+
+        let streamed = streamed + header.payload_length as u64;
+        if streamed > self.max_message_length {
+            return Err(ProtocolError::new(1009, "message too big").into());
+        }
+        self.count_fragment(header.fin)?;
+        self.streaming = if header.fin { None } else { Some(streamed) };
+        Ok(true)
+    }
+
+    // 处理读到的一个 frame, 消息还没有拼接完整时返回 None
+    // 拼接或者解压之后不再需要的内存放回 buffer
+    pub(crate) fn push_frame(
+        &mut self,
+        frame: Frame,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<Message>, WsError> {
+        // rsv1 只能出现在数据消息的第一个 frame 上
+        if frame.rsv1() && (frame.opcode == 0 || frame.opcode >= 8) {
+            return Err(ProtocolError::new(1002, "unexpected rsv1").into());
+        }
+
+        // 控制帧可以插在分片之间, 直接返回
+        if frame.opcode >= 8 {
+            return into_message(frame.opcode, frame.payload).map(Some);
+        }
+
+        let (opcode, compressed, payload_data) = match (frame.opcode, self.fragment.take()) {
+            (0, Some((opcode, compressed, mut payload_data))) => {
+                // 拼接之前检查, 大量很小的分片也不能拼出超过限制的消息
+                self.check_message_length(payload_data.len() + frame.payload.len())?;
+                payload_data.extend_from_slice(&frame.payload);
+                *buffer = frame.payload;
+                (opcode, compressed, payload_data)
+            }
+            (0, None) => {
+                return Err(ProtocolError::new(1002, "unexpected continuation frame").into())
+            }
+            // 分片的消息还没有结束, 只能插入控制帧, 不能开始新的消息
+            (_, Some(_)) => {
+                return Err(ProtocolError::new(1002, "expected continuation frame").into())
+            }
+            (opcode, None) => {
+                self.check_message_length(frame.payload.len())?;
+                (opcode, frame.rsv1(), frame.payload)
+            }
+        };
+
+        self.count_fragment(frame.fin)?;
+        if !frame.fin {
+            self.fragment = Some((opcode, compressed, payload_data));
+            return Ok(None);
+        }
+
+        let payload_data = match &mut self.inflater {
+            Some(inflater) if compressed => {
+                let inflated = inflater.inflate(&payload_data, self.max_message_length)?;
+                *buffer = payload_data;
+                inflated
+            }
+            _ => payload_data,
+        };
+
+        into_message(opcode, payload_data).map(Some)
+    }
+
+    // 记录当前消息的一个 frame, 消息结束时重新计数
+    fn count_fragment(&mut self, fin: bool) -> Result<(), WsError> {
+        self.fragments += 1;
+        if self.fragments > self.max_fragments {
+            return Err(ProtocolError::new(1009, "too many fragments").into());
+        }
+        if fin {
+            self.fragments = 0;
+        }
+        Ok(())
+    }
+
+    fn check_message_length(&self, length: usize) -> Result<(), WsError> {
+        if length as u64 > self.max_message_length {
+            return Err(ProtocolError::new(1009, "message too big").into());
+        }
+        Ok(())
+    }
+}
+
+// 解码一条消息, 不跨调用保存分片状态, 适合测试和一次性的解析
+pub fn decode_message(reader: &mut impl BufRead) -> Result<Message, WsError> {
+    Decoder::new(&Config::default(), None).decode_message(reader)
+}
+
+fn into_message(opcode: u8, payload_data: Vec<u8>) -> Result<Message, WsError> {
+    Ok(match opcode {
+        // 文本必须是合法的 utf8, 否则用 1007 关闭连接
+        1 => Message::Text(String::from_utf8(payload_data).map_err(|_| invalid_utf8())?),
+        8 => {
+            // close 帧的 payload 可以为空, 不为空时前两个字节是状态码
+            if payload_data.len() == 1 {
+                return Err(ProtocolError::new(1002, "invalid close frame").into());
+            }
+            let (code, reason) = if payload_data.len() >= 2 {
+                let code = u16::from_be_bytes([payload_data[0], payload_data[1]]);
+                if !valid_close_code(code) {
+                    return Err(ProtocolError::new(1002, "invalid close code").into());
+                }
+                let reason =
+                    String::from_utf8(payload_data[2..].to_vec()).map_err(|_| invalid_utf8())?;
+                (Some(code), reason)
+            } else {
+                (None, String::new())
+            };
+            Message::Close { code, reason }
+        }
+        9 => Message::Ping(payload_data),
+        10 => Message::Pong(payload_data),
+        2 => Message::Binary(payload_data),
+        opcode => unreachable!("opcode {} is rejected by header_length", opcode),
+    })
+}
+
+// 1004, 1005, 1006 和 1015 是保留的, 不能出现在 close 帧里, 其他没有分配的状态码也不接受
+// 3000-4999 给库和应用使用
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// 分块检查 utf8, 一个字符可能被截断在两块之间, 只保留被截断的最多 3 个字节
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Stream {
+    incomplete: Vec<u8>,
+}
+
+impl Utf8Stream {
+    pub(crate) fn push(&mut self, mut data: &[u8]) -> Result<(), ProtocolError> {
+        if !self.incomplete.is_empty() {
+            // 先补全上一块末尾的字符, 最多还需要 3 个字节
+            let previous = self.incomplete.len();
+            self.incomplete
+                .extend_from_slice(&data[..data.len().min(3)]);
+            let valid = match str::from_utf8(&self.incomplete) {
+                Ok(_) => self.incomplete.len(),
+                Err(err) if err.valid_up_to() >= previous => err.valid_up_to(),
+                // 这一块太短, 字符还是不完整
+                Err(err) if err.error_len().is_none() => return Ok(()),
+                Err(_) => return Err(invalid_utf8()),
+            };
+            data = &data[valid - previous..];
+            self.incomplete.clear();
+        }
+        match str::from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(err) if err.error_len().is_none() => {
+                self.incomplete
+                    .extend_from_slice(&data[err.valid_up_to()..]);
+                Ok(())
+            }
+            Err(_) => Err(invalid_utf8()),
+        }
+    }
+
+    // 消息结束时不能留下不完整的字符
+    pub(crate) fn finish(&mut self) -> Result<(), ProtocolError> {
+        if !self.incomplete.is_empty() {
+            return Err(invalid_utf8());
+        }
+        Ok(())
+    }
+}
+
+fn invalid_utf8() -> ProtocolError {
+    ProtocolError::new(1007, "invalid utf-8 payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // 长度编码的边界: 7 位, 16 位和 64 位的长度
+    const LENGTHS: [usize; 7] = [0, 1, 125, 126, 127, 65535, 65536];
+
+    // 服务端收到的 frame 有掩码, 客户端收到的没有
+    fn decoder(masked: bool) -> Decoder {
+        let config = Config::default();
+        match masked {
+            true => Decoder::new(&config, None),
+            false => Decoder::client(&config),
+        }
+    }
+
+    fn encode(message: &Message, masked: bool, seed: u64) -> Vec<u8> {
+        match masked {
+            true => message.encode_masked(&mut MaskKeys::from_seed(seed)),
+            false => message.encode(),
+        }
+    }
+
+    // 违反协议时关闭连接使用的状态码
+    fn error_code<T>(result: Result<T, WsError>) -> u16 {
+        match result {
+            Err(WsError::ProtocolViolation(err)) => err.code,
+            Err(err) => panic!("expected a protocol error, got {}", err),
+            Ok(_) => panic!("expected a protocol error"),
+        }
+    }
+
+    fn data_message() -> impl Strategy<Value = Message> {
+        (prop::sample::select(&LENGTHS[..]), any::<bool>()).prop_flat_map(|(length, text)| {
+            prop::collection::vec(any::<u8>(), length).prop_map(move |data| match text {
+                // 只用 ascii, 长度和字节数一样
+                true => Message::Text(data.iter().map(|&byte| (byte & 0x7f) as char).collect()),
+                false => Message::Binary(data),
+            })
+        })
+    }
+
+    fn control_message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..=125).prop_map(Message::Ping),
+            prop::collection::vec(any::<u8>(), 0..=125).prop_map(Message::Pong),
+            (3000..=4999u16, "[a-z ]{0,20}").prop_map(|(code, reason)| Message::Close {
+                code: Some(code),
+                reason,
+            }),
+            Just(Message::Close {
+                code: None,
+                reason: String::new(),
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn data_message_round_trips(message in data_message(), masked: bool, seed: u64) {
+            let frame = encode(&message, masked, seed);
+            // 长度使用最短的编码
+            let length = message.len();
+            let header = match length {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            } + if masked { 4 } else { 0 };
+            prop_assert_eq!(frame.len(), header + length);
+            let decoded = decoder(masked).decode_message(&mut frame.as_slice()).unwrap();
+            prop_assert_eq!(decoded, message);
+        }
+
+        #[test]
+        fn unicode_text_round_trips(text: String, masked: bool, seed: u64) {
+            let message = Message::Text(text);
+            let frame = encode(&message, masked, seed);
+            let decoded = decoder(masked).decode_message(&mut frame.as_slice()).unwrap();
+            prop_assert_eq!(decoded, message);
+        }
+
+        #[test]
+        fn control_message_round_trips(message in control_message(), masked: bool, seed: u64) {
+            let frame = encode(&message, masked, seed);
+            let decoded = decoder(masked).decode_message(&mut frame.as_slice()).unwrap();
+            prop_assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn boundary_lengths_round_trip() {
+        for length in LENGTHS {
+            for masked in [false, true] {
+                let message = Message::Binary((0..length).map(|i| i as u8).collect());
+                let frame = encode(&message, masked, length as u64);
+                let decoded = decoder(masked).decode_message(&mut frame.as_slice());
+                assert_eq!(
+                    decoded.unwrap(),
+                    message,
+                    "length {} masked {}",
+                    length,
+                    masked
+                );
+            }
+        }
+    }
+
+    // 8 字节长度的未掩码 binary frame 头
+    fn long_header(length: u64) -> Vec<u8> {
+        let mut header = vec![0x82, 127];
+        header.extend_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn length_above_max_payload_length_is_1009() {
+        let header = long_header(1 << 40);
+        assert_eq!(error_code(parse_header(&header, 1 << 20)), 1009);
+        // payload 还没有到达, 读完头就拒绝, 不需要分配内存
+        let result = Decoder::client(&Config::default()).decode_message(&mut header.as_slice());
+        assert_eq!(error_code(result), 1009);
+        assert!(parse_header(&long_header(1 << 20), 1 << 20).is_ok());
+    }
+
+    // 64 位平台上 8 字节的长度 (最高位必须是 0) 不会超过 usize::MAX
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn length_above_usize_max_is_1009() {
+        let header = long_header(u32::MAX as u64 + 1);
+        assert_eq!(error_code(parse_header(&header, u64::MAX)), 1009);
+    }
+
+    // 客户端发送的 frame, fin 和 opcode 可以任意组合
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask_key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask_key);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask_key[i % 4]),
+        );
+        frame
+    }
+
+    fn decode(frames: &[u8]) -> Result<Message, WsError> {
+        Decoder::new(&Config::default(), None).decode_message(&mut &frames[..])
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_1002() {
+        for opcode in [8, 9, 10] {
+            assert_eq!(error_code(decode(&masked_frame(false, opcode, b""))), 1002);
+        }
+    }
+
+    #[test]
+    fn control_frame_longer_than_125_is_1002() {
+        for opcode in [9, 10] {
+            assert_eq!(
+                error_code(decode(&masked_frame(true, opcode, &[0; 126]))),
+                1002
+            );
+            assert!(decode(&masked_frame(true, opcode, &[0; 125])).is_ok());
+        }
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.resize(126, b'a');
+        assert_eq!(error_code(decode(&masked_frame(true, 8, &close))), 1002);
+    }
+
+    // 协商了 permessage-deflate 时, 设置了 rsv1 的消息解压之后交给调用方, 没有设置的原样返回
+    #[test]
+    fn compressed_frames_are_inflated() {
+        let params = DeflateParams::default();
+        let text = "hello hello hello hello".repeat(100);
+        let mut deflater = crate::deflate::Deflater::new(&params);
+        let mut compressed = Vec::new();
+        deflater.deflate(text.as_bytes(), &mut compressed);
+        let mut frames = masked_frame(true, 1, &compressed);
+        frames[0] |= 0b0100_0000;
+        frames.extend(masked_frame(true, 2, &[1, 2, 3]));
+        deflater.deflate(text.as_bytes(), &mut compressed);
+        let mut second = masked_frame(true, 1, &compressed);
+        second[0] |= 0b0100_0000;
+        frames.extend(second);
+
+        let mut decoder = Decoder::new(&Config::default(), Some(&params));
+        let mut reader = &frames[..];
+        assert_eq!(
+            decoder.decode_message(&mut reader).unwrap(),
+            Message::Text(text.clone())
+        );
+        assert_eq!(
+            decoder.decode_message(&mut reader).unwrap(),
+            Message::Binary(vec![1, 2, 3])
+        );
+        assert_eq!(
+            decoder.decode_message(&mut reader).unwrap(),
+            Message::Text(text)
+        );
+
+        // 没有协商时 rsv1 是 1002
+        let mut compressed_frame = masked_frame(true, 1, b"hi");
+        compressed_frame[0] |= 0b0100_0000;
+        assert_eq!(error_code(decode(&compressed_frame)), 1002);
+    }
+
+    // 每个分片都没有超过 max_payload_length, 拼接之后超过 max_message_length
+    #[test]
+    fn reassembled_message_above_max_message_length_is_1009() {
+        let config = Config {
+            max_payload_length: 10,
+            max_message_length: 20,
+            ..Config::default()
+        };
+        let mut frames = masked_frame(false, 2, &[1; 10]);
+        frames.extend(masked_frame(true, 0, &[2; 10]));
+        let message = Decoder::new(&config, None).decode_message(&mut frames.as_slice());
+        assert_eq!(message.unwrap().len(), 20);
+
+        let mut frames = masked_frame(false, 2, &[1; 10]);
+        frames.extend(masked_frame(false, 0, &[2; 10]));
+        frames.extend(masked_frame(true, 0, &[3; 1]));
+        let result = Decoder::new(&config, None).decode_message(&mut frames.as_slice());
+        assert_eq!(error_code(result), 1009);
+    }
+
+    // 很多 1 字节的分片, 看到越过 max_message_length 的那一个分片的头就返回 1009
+    // 它的 payload 和后面的分片都留在 reader 里, 不会被读取
+    #[test]
+    fn continuation_flood_above_max_message_length_is_1009() {
+        let config = Config {
+            max_message_length: 1000,
+            // 不让分片数量的限制先生效
+            max_fragments: 10_000,
+            ..Config::default()
+        };
+        let mut frames = masked_frame(false, 2, &[0]);
+        for _ in 0..999 {
+            frames.extend(masked_frame(false, 0, &[1]));
+        }
+        // 加上这一个刚好是 1001 字节
+        let crossing = masked_frame(false, 0, &[2]);
+        frames.extend(&crossing);
+        let rest: Vec<u8> = (0..100)
+            .flat_map(|_| masked_frame(false, 0, &[3]))
+            .collect();
+        frames.extend(&rest);
+        frames.extend(masked_frame(true, 0, &[4]));
+
+        let mut reader = frames.as_slice();
+        let result = Decoder::new(&config, None).decode_message(&mut reader);
+        assert_eq!(error_code(result), 1009);
+        assert_eq!(reader.len(), rest.len() + crossing.len());
+
+        // 刚好 1000 字节的消息可以接受
+        let mut frames = masked_frame(false, 2, &[0]);
+        for _ in 0..998 {
+            frames.extend(masked_frame(false, 0, &[1]));
+        }
+        frames.extend(masked_frame(true, 0, &[2]));
+        let message = Decoder::new(&config, None).decode_message(&mut frames.as_slice());
+        assert_eq!(message.unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn valid_close_codes() {
+        for code in [1000, 1001, 1002, 1003, 1007, 1011, 1014, 3000, 4000, 4999] {
+            assert!(valid_close_code(code), "{}", code);
+        }
+        // 1004-1006 和 1015 是保留的, 1016-2999 和 5000 以上没有分配
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, u16::MAX] {
+            assert!(!valid_close_code(code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn reserved_close_code_is_1002() {
+        for code in [1005u16, 1006, 1015] {
+            let frame = masked_frame(true, 8, &code.to_be_bytes());
+            assert_eq!(error_code(decode(&frame)), 1002, "{}", code);
+        }
+        // 只有一个字节的 payload 不是合法的 close 帧
+        assert_eq!(error_code(decode(&masked_frame(true, 8, &[3]))), 1002);
+    }
+
+    #[test]
+    fn zero_length_frames() {
+        assert_eq!(
+            decode(&masked_frame(true, 1, b"")).unwrap(),
+            Message::Text(String::new())
+        );
+        assert_eq!(
+            decode(&masked_frame(true, 2, b"")).unwrap(),
+            Message::Binary(Vec::new())
+        );
+        assert_eq!(
+            decode(&masked_frame(true, 9, b"")).unwrap(),
+            Message::Ping(Vec::new())
+        );
+        assert_eq!(
+            decode(&masked_frame(true, 8, b"")).unwrap(),
+            Message::Close {
+                code: None,
+                reason: String::new(),
+            }
+        );
+        // 空的分片也是合法的, 包括第一个和最后一个
+        let mut frames = masked_frame(false, 2, b"");
+        frames.extend(masked_frame(false, 0, b"ab"));
+        frames.extend(masked_frame(false, 0, b""));
+        frames.extend(masked_frame(true, 0, b""));
+        assert_eq!(decode(&frames).unwrap(), Message::Binary(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn data_frame_inside_fragmented_message_is_1002() {
+        let mut frames = masked_frame(false, 1, b"frag");
+        frames.extend(masked_frame(true, 2, b"new message"));
+        assert_eq!(error_code(decode(&frames)), 1002);
+        // 没有开始的分片消息
+        assert_eq!(error_code(decode(&masked_frame(true, 0, b"orphan"))), 1002);
+    }
+
+    // 控制帧可以插在分片之间, 先于分片消息返回
+    #[test]
+    fn control_frame_inside_fragmented_message() {
+        let mut frames = masked_frame(false, 1, b"frag");
+        frames.extend(masked_frame(true, 9, b"ping"));
+        frames.extend(masked_frame(true, 0, b"mented"));
+        let mut reader = frames.as_slice();
+        let mut decoder = Decoder::new(&Config::default(), None);
+        let ping = decoder.decode_message(&mut reader).unwrap();
+        assert_eq!(ping, Message::Ping(b"ping".to_vec()));
+        let text = decoder.decode_message(&mut reader).unwrap();
+        assert_eq!(text, Message::Text("fragmented".into()));
+    }
+
+    #[test]
+    fn too_many_fragments_is_1009() {
+        let config = Config {
+            max_fragments: 3,
+            ..Config::default()
+        };
+        let mut frames = masked_frame(false, 2, b"a");
+        frames.extend(masked_frame(false, 0, b"b"));
+        let mut allowed = frames.clone();
+        allowed.extend(masked_frame(true, 0, b"c"));
+        let message = Decoder::new(&config, None).decode_message(&mut allowed.as_slice());
+        assert_eq!(message.unwrap(), Message::Binary(b"abc".to_vec()));
+
+        frames.extend(masked_frame(false, 0, b"c"));
+        frames.extend(masked_frame(true, 0, b"d"));
+        let result = Decoder::new(&config, None).decode_message(&mut frames.as_slice());
+        assert_eq!(error_code(result), 1009);
+
+        // 每条消息重新计数
+        let mut decoder = Decoder::new(&config, None);
+        for _ in 0..3 {
+            let message = decoder.decode_message(&mut allowed.as_slice());
+            assert_eq!(message.unwrap(), Message::Binary(b"abc".to_vec()));
+        }
+    }
+
+    // 逐个字节异或的实现, 和 RFC 6455 5.3 的定义一样
+    fn mask_bytewise(data: &mut [u8], mask_key: [u8; 4], offset: usize) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask_key[(offset + i) % 4];
+        }
+    }
+
+    #[test]
+    fn apply_mask_matches_bytewise() {
+        let mask_key = [0x37, 0xfa, 0x21, 0x3d];
+        // 8 字节的整块, 剩下的部分和两者都有的长度
+        for length in 0..=17 {
+            for offset in 0..8 {
+                let data: Vec<u8> = (0..length as u8).map(|i| i.wrapping_mul(31)).collect();
+                let mut expected = data.clone();
+                mask_bytewise(&mut expected, mask_key, offset);
+                let mut masked = data;
+                apply_mask(&mut masked, mask_key, offset);
+                assert_eq!(masked, expected, "length {} offset {}", length, offset);
+            }
+        }
+    }
+
+    proptest! {
+        // 随机的 key, payload 和 offset, slice 的起点也不一定按 8 字节对齐
+        #[test]
+        fn apply_mask_matches_bytewise_random(
+            mask_key: [u8; 4],
+            data in prop::collection::vec(any::<u8>(), 0..256),
+            offset in 0..usize::MAX / 2,
+            start in 0..8usize,
+        ) {
+            let start = start.min(data.len());
+            let mut expected = data.clone();
+            mask_bytewise(&mut expected[start..], mask_key, offset);
+            let mut masked = data;
+            apply_mask(&mut masked[start..], mask_key, offset);
+            prop_assert_eq!(masked, expected);
+        }
+    }
+
+    // 分块还原时每一块的 offset 是它在 payload 中的位置
+    #[test]
+    fn apply_mask_in_chunks() {
+        let mask_key = [1, 2, 3, 4];
+        let data: Vec<u8> = (0..=255).collect();
+        let mut expected = data.clone();
+        apply_mask(&mut expected, mask_key, 0);
+        for chunk_size in 1..=17 {
+            let mut masked = data.clone();
+            for (i, chunk) in masked.chunks_mut(chunk_size).enumerate() {
+                apply_mask(chunk, mask_key, i * chunk_size);
+            }
+            assert_eq!(masked, expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    // 在 frame 的边界上 EOF 是正常断开, 在头或者 payload 的中间是 FrameTruncated
+    #[test]
+    fn eof_inside_frame_is_truncated() {
+        assert!(matches!(decode(b""), Err(WsError::ConnectionClosed)));
+        let frame = masked_frame(true, 2, &[7; 200]);
+        // 第一个字节, 扩展长度的中间, mask key 的中间, payload 的中间
+        for end in [1, 3, 6, 20, frame.len() - 1] {
+            let result = decode(&frame[..end]);
+            assert!(
+                matches!(result, Err(WsError::FrameTruncated)),
+                "{} bytes: {:?}",
+                end,
+                result
+            );
+        }
+        assert!(decode(&frame).is_ok());
+    }
+
+    #[test]
+    fn non_minimal_length_is_1002() {
+        // 2 字节的长度表示 125, 8 字节的长度表示 65535, 8 字节的长度最高位是 1
+        let headers: [&[u8]; 3] = [
+            &[0x82, 126, 0, 125],
+            &[0x82, 127, 0, 0, 0, 0, 0, 0, 0xff, 0xff],
+            &[0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        for header in headers {
+            assert_eq!(error_code(parse_header(header, u64::MAX)), 1002);
+        }
+    }
+
+    #[test]
+    fn minimal_length_is_accepted() {
+        let headers: [(&[u8], usize); 4] = [
+            (&[0x82, 125], 125),
+            (&[0x82, 126, 0, 126], 126),
+            (&[0x82, 126, 0xff, 0xff], 65535),
+            (&[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0], 65536),
+        ];
+        for (header, length) in headers {
+            let Ok(header) = parse_header(header, u64::MAX) else {
+                panic!("length {} rejected", length);
+            };
+            assert_eq!(header.payload_length, length);
+        }
+    }
+
+    fn data_type_decoder(data_type: DataType) -> Decoder {
+        let config = Config {
+            data_type: Some(data_type),
+            ..Config::default()
+        };
+        Decoder::new(&config, None)
+    }
+
+    // 只有 frame 的头 (2 字节和 mask key) 也返回 1003, 不需要等 payload 到达
+    #[test]
+    fn unsupported_data_type_is_1003() {
+        let binary = masked_frame(true, 2, &[1, 2, 3]);
+        let result = data_type_decoder(DataType::Text).decode_message(&mut &binary[..6]);
+        assert_eq!(error_code(result), 1003);
+        let text = masked_frame(false, 1, b"hello");
+        let result = data_type_decoder(DataType::Binary).decode_message(&mut &text[..]);
+        assert_eq!(error_code(result), 1003);
+    }
+
+    // 接受的类型, 分片的后续 frame 和控制 frame 都照常处理
+    #[test]
+    fn supported_data_type_passes() {
+        let mut frames = masked_frame(false, 1, b"hel");
+        frames.extend(masked_frame(true, 9, b"ping"));
+        frames.extend(masked_frame(true, 0, b"lo"));
+        let mut decoder = data_type_decoder(DataType::Text);
+        let mut reader = &frames[..];
+        assert_eq!(
+            decoder.decode_message(&mut reader).unwrap(),
+            Message::Ping(b"ping".to_vec())
+        );
+        assert_eq!(
+            decoder.decode_message(&mut reader).unwrap(),
+            Message::Text("hello".into())
+        );
+        let binary = masked_frame(true, 2, &[1, 2, 3]);
+        assert_eq!(
+            data_type_decoder(DataType::Binary)
+                .decode_message(&mut &binary[..])
+                .unwrap(),
+            Message::Binary(vec![1, 2, 3])
+        );
+    }
+}
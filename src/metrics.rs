@@ -0,0 +1,216 @@
+// 服务端的统计数据, 都是原子计数器, 收发数据时不需要加锁
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// metrics() 返回的某一时刻的统计数据
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    // 接受过的连接数量, 包括握手失败的
+    pub connections_total: u64,
+    // 完成握手, 还没有断开的连接数量
+    pub connections_active: u64,
+    // handler 回复的 text 和 binary 消息数量
+    pub messages_echoed: u64,
+    // 从连接读到的字节数, 包括握手和 frame 头
+    pub bytes_received: u64,
+    // 写到连接的字节数
+    pub bytes_sent: u64,
+    pub handshake_failures: u64,
+    // 因为违反协议被关闭的连接数量
+    pub protocol_errors: u64,
+}
+
+// 一个连接收发的字节数, 和 Metrics 一样包括握手和 frame 头
+// 连接断开时交给 MessageHandler::on_disconnect, 并且出现在断开连接的日志里
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl Metrics {
+    // prometheus 的文本格式
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "ws_connections_total",
+                "counter",
+                "Accepted connections.",
+                self.connections_total,
+            ),
+            (
+                "ws_connections_active",
+                "gauge",
+                "Open websocket connections.",
+                self.connections_active,
+            ),
+            (
+                "ws_messages_echoed_total",
+                "counter",
+                "Data messages sent by the handler.",
+                self.messages_echoed,
+            ),
+            (
+                "ws_received_bytes_total",
+                "counter",
+                "Bytes read from connections.",
+                self.bytes_received,
+            ),
+            (
+                "ws_sent_bytes_total",
+                "counter",
+                "Bytes written to connections.",
+                self.bytes_sent,
+            ),
+            (
+                "ws_handshake_failures_total",
+                "counter",
+                "Rejected handshakes.",
+                self.handshake_failures,
+            ),
+            (
+                "ws_protocol_errors_total",
+                "counter",
+                "Connections closed for protocol violations.",
+                self.protocol_errors,
+            ),
+        ];
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = write!(
+                output,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        output
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    pub(crate) messages_echoed: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    pub(crate) handshake_failures: AtomicU64,
+    pub(crate) protocol_errors: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            messages_echoed: self.messages_echoed.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            protocol_errors: self.protocol_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, size: usize) {
+        self.bytes_received
+            .fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, size: usize) {
+        self.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    // 握手成功之后调用, guard 被 drop 时 (包括 panic) 减少活跃连接数
+    pub(crate) fn open(&self) -> ActiveGuard<'_> {
+        Counters::incr(&self.connections_active);
+        ActiveGuard { counters: self }
+    }
+}
+
+pub(crate) struct ActiveGuard<'a> {
+    counters: &'a Counters,
+}
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.counters
+            .connections_active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// 一个连接的字节数, Sender 可能在其他线程里写数据, 所以也是原子计数器
+#[derive(Default)]
+pub(crate) struct Transferred {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Transferred {
+    pub(crate) fn received(&self, size: usize) {
+        self.received.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, size: usize) {
+        self.sent.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_received: self.received.load(Ordering::Relaxed),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 包装连接的读写两端, 统计收发的字节数, 同时计入服务端和这个连接的统计
+pub(crate) struct Counted<T> {
+    inner: T,
+    counters: Arc<Counters>,
+    transferred: Arc<Transferred>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, counters: &Arc<Counters>, transferred: &Arc<Transferred>) -> Self {
+        Counted {
+            inner,
+            counters: Arc::clone(counters),
+            transferred: Arc::clone(transferred),
+        }
+    }
+
+    pub(crate) fn transferred(&self) -> &Arc<Transferred> {
+        &self.transferred
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.counters.received(size);
+        self.transferred.received(size);
+        Ok(size)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.counters.sent(size);
+        self.transferred.sent(size);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
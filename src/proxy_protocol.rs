@@ -0,0 +1,269 @@
+// PROXY protocol (haproxy 的 proxy-protocol.txt), 负载均衡在连接的最开始发送客户端的真实地址
+// 支持文本格式的 v1 和二进制格式的 v2
+use std::{
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// v2 的头以这 12 个字节开始, 然后是版本和命令, 地址族, 两个字节的长度
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+pub(crate) const V2_HEADER_LENGTH: usize = 16;
+// v1 的头最长 107 字节, 包括结尾的 \r\n
+pub(crate) const V1_MAX_LENGTH: usize = 107;
+// 两个版本的头都至少有这么长, 先读这么多再判断是哪个版本
+pub(crate) const PREFIX_LENGTH: usize = 8;
+
+pub(crate) enum Version {
+    V1,
+    V2,
+}
+
+// 没有 PROXY protocol 的头, 或者头的格式不对, 这时直接关闭连接
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {}", reason),
+    )
+}
+
+pub(crate) fn version(prefix: &[u8]) -> io::Result<Version> {
+    if prefix.starts_with(b"PROXY ") {
+        Ok(Version::V1)
+    } else if prefix.starts_with(&V2_SIGNATURE[..PREFIX_LENGTH]) {
+        Ok(Version::V2)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+// 读取 PROXY protocol 的头, 返回客户端的地址
+// 逐字节读取 v1 的头, 不会多读后面的数据, tls 和 http 的数据原样留在 reader 里
+pub(crate) fn read_header(reader: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; PREFIX_LENGTH];
+    reader.read_exact(&mut header)?;
+    match version(&header)? {
+        Version::V1 => {
+            while !header.ends_with(b"\r\n") {
+                if header.len() == V1_MAX_LENGTH {
+                    return Err(invalid("line too long"));
+                }
+                let mut byte = [0];
+                reader.read_exact(&mut byte)?;
+                header.push(byte[0]);
+            }
+            parse_v1(&header)
+        }
+        Version::V2 => {
+            header.resize(V2_HEADER_LENGTH, 0);
+            reader.read_exact(&mut header[PREFIX_LENGTH..])?;
+            let mut addresses = vec![0; v2_length(&header)?];
+            reader.read_exact(&mut addresses)?;
+            parse_v2(&header, &addresses)
+        }
+    }
+}
+
+// PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+// 负载均衡自己发起的连接 (例如健康检查) 是 PROXY UNKNOWN, 返回 None, 这时使用连接本身的地址
+pub(crate) fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("malformed line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let ip = match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") => fields
+            .get(2)
+            .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+            .map(IpAddr::V4),
+        Some(&"TCP6") => fields
+            .get(2)
+            .and_then(|ip| ip.parse::<Ipv6Addr>().ok())
+            .map(IpAddr::V6),
+        _ => return Err(invalid("unknown protocol")),
+    };
+    let port = fields.get(4).and_then(|port| parse_port(port));
+    match (fields.len(), ip, port) {
+        (6, Some(ip), Some(port)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(invalid("malformed address")),
+    }
+}
+
+// 端口是不带前导 0 的十进制数
+fn parse_port(port: &str) -> Option<u16> {
+    if port.len() > 1 && port.starts_with('0') {
+        return None;
+    }
+    port.parse().ok()
+}
+
+// v2 的头之后还有多少字节 (地址和 tlv)
+pub(crate) fn v2_length(header: &[u8]) -> io::Result<usize> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(invalid("missing header"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    Ok(u16::from_be_bytes([header[14], header[15]]) as usize)
+}
+
+// 地址之后的 tlv 不需要, 直接忽略
+// LOCAL 命令和不是 ip 的地址族 (unix socket, UNSPEC) 返回 None
+pub(crate) fn parse_v2(header: &[u8], addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match header[12] & 0x0f {
+        // LOCAL, 负载均衡自己发起的连接
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+    // 高 4 位是地址族, 低 4 位是 tcp (1) 或者 udp (2)
+    let (ip, port) = match header[13] >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            (IpAddr::from(ip), &addresses[8..10])
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            (IpAddr::from(ip), &addresses[32..34])
+        }
+        1 | 2 => return Err(invalid("address too short")),
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(
+        ip,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 原样返回 v2 的头和地址, 头的长度字段是地址的长度
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        (header, addresses.to_vec())
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let addr = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        // UNKNOWN 之后的地址可以有也可以没有
+        let line = b"PROXY UNKNOWN 192.0.2.1 198.51.100.1 56324 443\r\n";
+        assert_eq!(parse_v1(line).unwrap(), None);
+    }
+
+    #[test]
+    fn v1_malformed() {
+        let lines: [&[u8]; 7] = [
+            // 前导 0 的端口
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            // TCP4 后面是 ipv6 的地址
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 192.0.2.1  198.51.100.1 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n",
+        ];
+        for line in lines {
+            let err = parse_v1(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", line);
+        }
+        // 端口 0 本身没有前导 0
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 0 443\r\n").is_ok());
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // 地址之后的 tlv 被忽略
+        addresses.extend_from_slice(&[0x04, 0, 1, 0]);
+        let (header, addresses) = v2(1, 0x11, &addresses);
+        assert_eq!(v2_length(&header).unwrap(), addresses.len());
+        let addr = parse_v2(&header, &addresses).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let client: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut addresses = client.octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        let (header, addresses) = v2(1, 0x21, &addresses);
+        let addr = parse_v2(&header, &addresses).unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local_and_unspec() {
+        let (header, addresses) = v2(0, 0x11, &[0; 12]);
+        assert_eq!(parse_v2(&header, &addresses).unwrap(), None);
+        let (header, addresses) = v2(1, 0x00, &[]);
+        assert_eq!(parse_v2(&header, &addresses).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_short_address_block() {
+        for (family, length) in [(0x11, 11), (0x21, 35)] {
+            let (header, addresses) = v2(1, family, &vec![0; length]);
+            let err = parse_v2(&header, &addresses).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn v2_bad_version_and_command() {
+        let (mut header, addresses) = v2(1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(v2_length(&header).is_err());
+        header[12] = 0x22;
+        assert!(parse_v2(&header, &addresses).is_err());
+    }
+
+    // 读完头之后 reader 里剩下的数据不受影响
+    #[test]
+    fn read_header_leaves_rest() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_header(&mut data).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1, 0, 80, 1, 187];
+        let (mut stream, _) = v2(1, 0x11, &addresses);
+        stream.append(&mut addresses);
+        stream.extend_from_slice(b"rest");
+        let mut data = stream.as_slice();
+        let addr = read_header(&mut data).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:80".parse().unwrap()));
+        assert_eq!(data, b"rest");
+    }
+
+    #[test]
+    fn missing_header() {
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut data).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+// 每个连接的消息频率限制, 用令牌桶实现, 允许一秒之内的突发
+use crate::{Message, ProtocolError, WsError};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+// 限制的参数, 消息数量和字节数可以同时限制
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    // 每秒允许的消息数量, None 表示不限制
+    pub messages_per_second: Option<u32>,
+    // 每秒允许的 payload 字节数, None 表示不限制
+    pub bytes_per_second: Option<u64>,
+    // 超过限制之后的处理方式
+    pub action: RateLimitAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitAction {
+    // 等到令牌足够之后再处理, 不读数据之后客户端会被 tcp 的流控拖慢
+    #[default]
+    Delay,
+    // 用 1008 (policy violation) 关闭连接
+    Close,
+}
+
+struct Bucket {
+    // 每秒补充的令牌数, 也是桶的容量
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        // 速率为 0 时所有消息都会被限制, 避免除以 0
+        let rate = rate.max(f64::MIN_POSITIVE);
+        Bucket { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    }
+
+    // 令牌足够取出 cost 个之前需要等待的秒数
+    fn wait(&self, cost: f64) -> f64 {
+        ((cost - self.tokens) / self.rate).max(0.0)
+    }
+}
+
+// bind 时检查, 速率为 0 时 Delay 会永远等待下去, 占住 worker 线程
+pub(crate) fn check(rate_limit: Option<&RateLimit>) -> io::Result<()> {
+    let Some(limit) = rate_limit else {
+        return Ok(());
+    };
+    if limit.messages_per_second == Some(0) || limit.bytes_per_second == Some(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "rate_limit must be greater than 0",
+        ));
+    }
+    Ok(())
+}
+
+// 一个连接的限流状态, 没有配置限制时不做任何事情
+pub(crate) struct RateLimiter {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    action: RateLimitAction,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<&RateLimit>) -> Self {
+        RateLimiter {
+            messages: limit
+                .and_then(|limit| limit.messages_per_second)
+                .map(|rate| Bucket::new(rate as f64)),
+            bytes: limit
+                .and_then(|limit| limit.bytes_per_second)
+                .map(|rate| Bucket::new(rate as f64)),
+            action: limit.map_or_else(RateLimitAction::default, |limit| limit.action),
+            last_refill: Instant::now(),
+        }
+    }
+
+    // 只有 text 和 binary 消息计入限制, close, ping 和 pong 不消耗令牌也不需要等待
+    // 否则用完突发的客户端发送 close 时会收到 1008, 而不是正常的关闭握手
+    pub(crate) fn acquire_message(&mut self, message: &Message) -> Result<Duration, WsError> {
+        match message {
+            Message::Text(_) | Message::Binary(_) => self.acquire(1, message.len() as u64),
+            _ => Ok(Duration::ZERO),
+        }
+    }
+
+    // 收到数据之后调用, 返回处理之前需要等待的时间
+    // 令牌不够时先欠着, 等待的时间正好补上欠的部分
+    pub(crate) fn acquire(&mut self, messages: u32, bytes: u64) -> Result<Duration, WsError> {
+        if self.messages.is_none() && self.bytes.is_none() {
+            return Ok(Duration::ZERO);
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        let mut wait: f64 = 0.0;
+        for (bucket, cost) in [
+            (&mut self.messages, messages as f64),
+            (&mut self.bytes, bytes as f64),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket.refill(elapsed);
+                wait = wait.max(bucket.wait(cost));
+                bucket.tokens -= cost;
+            }
+        }
+
+        if wait > 0.0 && self.action == RateLimitAction::Close {
+            return Err(ProtocolError::new(1008, "rate limit exceeded").into());
+        }
+        Ok(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(messages: Option<u32>, bytes: Option<u64>, action: RateLimitAction) -> RateLimiter {
+        RateLimiter::new(Some(&RateLimit {
+            messages_per_second: messages,
+            bytes_per_second: bytes,
+            action,
+        }))
+    }
+
+    fn error_code(result: Result<Duration, WsError>) -> u16 {
+        match result {
+            Err(WsError::ProtocolViolation(err)) => err.code,
+            _ => panic!("expected a protocol error"),
+        }
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let mut limiter = RateLimiter::new(None);
+        for _ in 0..1000 {
+            assert_eq!(limiter.acquire(1, 1 << 20).unwrap(), Duration::ZERO);
+        }
+    }
+
+    // 一秒的突发之内不等待, 之后每条消息等待欠下的令牌补上的时间
+    #[test]
+    fn delay_after_burst() {
+        let mut limiter = limiter(Some(10), None, RateLimitAction::Delay);
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire(1, 100).unwrap(), Duration::ZERO);
+        }
+        let wait = limiter.acquire(1, 100).unwrap();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+        // 欠着的令牌累积, 下一条消息等得更久
+        let wait = limiter.acquire(1, 100).unwrap();
+        assert!(wait > Duration::from_millis(190) && wait <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn bytes_limit() {
+        let mut limiter = limiter(None, Some(1000), RateLimitAction::Delay);
+        assert_eq!(limiter.acquire(1, 1000).unwrap(), Duration::ZERO);
+        let wait = limiter.acquire(1, 500).unwrap();
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+    }
+
+    // 同时限制时等待两者中较长的时间
+    #[test]
+    fn both_limits() {
+        let mut limiter = limiter(Some(100), Some(1000), RateLimitAction::Delay);
+        assert_eq!(limiter.acquire(1, 1000).unwrap(), Duration::ZERO);
+        let wait = limiter.acquire(1, 100).unwrap();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn close_after_burst_is_1008() {
+        let mut limiter = limiter(Some(10), None, RateLimitAction::Close);
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire(1, 100).unwrap(), Duration::ZERO);
+        }
+        assert_eq!(error_code(limiter.acquire(1, 100)), 1008);
+
+        let mut limiter = self::limiter(None, Some(100), RateLimitAction::Close);
+        assert_eq!(error_code(limiter.acquire(1, 101)), 1008);
+    }
+
+    // 令牌按经过的时间补充, 最多补满一秒的量
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut limiter = limiter(Some(10), None, RateLimitAction::Close);
+        for _ in 0..10 {
+            limiter.acquire(1, 0).unwrap();
+        }
+        limiter.last_refill -= Duration::from_millis(500);
+        for _ in 0..5 {
+            assert_eq!(limiter.acquire(1, 0).unwrap(), Duration::ZERO);
+        }
+        assert_eq!(error_code(limiter.acquire(1, 0)), 1008);
+
+        let mut limiter = self::limiter(Some(10), None, RateLimitAction::Close);
+        limiter.last_refill -= Duration::from_secs(60);
+        for _ in 0..10 {
+            limiter.acquire(1, 0).unwrap();
+        }
+        assert_eq!(error_code(limiter.acquire(1, 0)), 1008);
+    }
+
+    // 速率为 0 的限制在 bind 时被拒绝
+    #[test]
+    fn zero_rate_is_rejected() {
+        for (messages, bytes) in [(Some(0), None), (None, Some(0)), (Some(10), Some(0))] {
+            for action in [RateLimitAction::Delay, RateLimitAction::Close] {
+                let limit = RateLimit {
+                    messages_per_second: messages,
+                    bytes_per_second: bytes,
+                    action,
+                };
+                let err = check(Some(&limit)).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+        assert!(check(None).is_ok());
+        assert!(check(Some(&RateLimit::default())).is_ok());
+    }
+
+    // 控制消息不受限制, 用完突发之后仍然可以 ping 和关闭连接
+    #[test]
+    fn control_messages_are_not_limited() {
+        let mut limiter = limiter(Some(2), Some(10), RateLimitAction::Close);
+        let text = Message::Text("hello".into());
+        for _ in 0..2 {
+            assert_eq!(limiter.acquire_message(&text).unwrap(), Duration::ZERO);
+        }
+        let control = [
+            Message::Ping(vec![0; 100]),
+            Message::Pong(vec![0; 100]),
+            Message::Close {
+                code: Some(1000),
+                reason: "bye".into(),
+            },
+        ];
+        for message in &control {
+            assert_eq!(limiter.acquire_message(message).unwrap(), Duration::ZERO);
+        }
+        assert_eq!(error_code(limiter.acquire_message(&text)), 1008);
+        let mut limiter = self::limiter(Some(1), None, RateLimitAction::Close);
+        assert_eq!(
+            limiter.acquire_message(&Message::Binary(vec![1])).unwrap(),
+            Duration::ZERO
+        );
+        assert_eq!(
+            error_code(limiter.acquire_message(&Message::Binary(vec![1]))),
+            1008
+        );
+    }
+}
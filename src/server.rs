@@ -0,0 +1,679 @@
+use crate::{
+    connection::{serve, Connections, Deadline},
+    handler_fn, handshake,
+    listener::{self, Listener, Stream},
+    metrics::{Counted, Transferred},
+    proxy_protocol, rate_limit, AccessLog, CloseReason, Config, ConnectionContext, ConnectionStats,
+    DataType, EchoHandler, EchoPolicy, ExtensionHandler, FlushPolicy, HandlerFactory, IpNetwork,
+    Message, MessageHandler, Metrics, RateLimit, TcpKeepalive, WsError,
+};
+use log::{error, info, warn};
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// websocket 服务端, 默认原样返回收到的消息
+pub struct Server<H = EchoHandler> {
+    // bind_all 可以同时监听多个地址, 至少有一个
+    listeners: Arc<Vec<Listener>>,
+    config: Arc<Config>,
+    handler: H,
+    connections: Arc<Connections>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Server> {
+        Server::builder().bind(addr)
+    }
+
+    // 监听 unix socket, 已经存在的旧 socket 文件会被删除, 服务结束时删除 socket 文件
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<Server> {
+        Server::builder().bind_unix(path)
+    }
+
+    // 使用已经 bind 好的 listener, 见 ServerBuilder::from_listener
+    pub fn from_listener(listener: TcpListener) -> io::Result<Server> {
+        Server::builder().from_listener(listener)
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+impl<H> Server<H> {
+    // 替换处理消息的 handler
+    pub fn with_handler<T: MessageHandler>(self, handler: T) -> Server<T> {
+        self.replace_handler(handler)
+    }
+
+    // 每个连接用 factory 创建一个新的 handler, 而不是 clone 同一个
+    pub fn with_handler_factory<T: HandlerFactory>(self, factory: T) -> Server<T> {
+        self.replace_handler(factory)
+    }
+
+    fn replace_handler<T>(self, handler: T) -> Server<T> {
+        Server {
+            listeners: self.listeners,
+            config: self.config,
+            handler,
+            connections: self.connections,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+        }
+    }
+
+    // 用闭包处理数据消息, 一行代码就可以定制回复, 例如
+    // server.run_with_handler(|msg| Some(msg))
+    // 闭包为每个连接 clone 一份, 见 handler_fn
+    pub fn run_with_handler<F>(self, f: F) -> io::Result<()>
+    where
+        F: FnMut(Message) -> Option<Message> + Clone + Send + 'static,
+    {
+        self.with_handler(handler_fn(f)).run()
+    }
+
+    // 当前的统计数据
+    pub fn metrics(&self) -> Metrics {
+        self.connections.counters.snapshot()
+    }
+
+    // 监听 unix socket 时返回 Unsupported, 监听多个地址时返回第一个
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    // 监听的所有地址, 顺序和 bind_all 的参数一样
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(Listener::local_addr).collect()
+    }
+
+    // 停止接受新的连接, 并给所有连接发送 1001 (going away) 的 close 帧
+    // run 会等待客户端关闭连接, 超过 shutdown_grace_period 之后断开剩下的连接并返回
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    // run 一直阻塞, 在其他线程 (例如信号处理或者测试) 里通过 handle 停止服务
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            listeners: Arc::clone(&self.listeners),
+            connections: Arc::clone(&self.connections),
+        }
+    }
+}
+
+// 停止服务, 和 Server::shutdown 一样, 可以 clone 之后在多个线程里使用
+// 服务已经停止之后再调用没有影响
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    listeners: Arc<Vec<Listener>>,
+    connections: Arc<Connections>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let open = self.connections.close_all(1001);
+        info!("shutting down, {} connections still open", open);
+        // accept 会一直阻塞, 连接一次自己让它返回
+        for listener in self.listeners.iter() {
+            listener.shutdown();
+        }
+    }
+}
+
+// 正常关闭和客户端断开连接之外的情况需要打印出原因
+pub(crate) fn log_disconnect(
+    ctx: &ConnectionContext,
+    stats: ConnectionStats,
+    result: thread::Result<Result<CloseReason, WsError>>,
+) {
+    let stats = format!(
+        "received {} bytes, sent {} bytes",
+        stats.bytes_received, stats.bytes_sent
+    );
+    match result {
+        Ok(Ok(reason)) if reason.is_normal() => {
+            info!("{} disconnected, {}: {}", ctx, stats, reason)
+        }
+        Ok(Ok(reason @ CloseReason::Io(_))) => {
+            error!("{} disconnected, {}: {}", ctx, stats, reason)
+        }
+        Ok(Ok(reason)) => warn!("{} disconnected, {}: {}", ctx, stats, reason),
+        // 握手完成之前客户端断开, 或者是健康检查的请求
+        Ok(Err(WsError::ConnectionClosed)) => info!("{} disconnected, {}", ctx, stats),
+        Ok(Err(WsError::Io(err))) => error!("{} disconnected, {}: io error: {}", ctx, stats, err),
+        // 握手失败, 协议错误和在 frame 中间断开都是客户端的问题
+        Ok(Err(err)) => warn!("{} disconnected, {}: {}", ctx, stats, err),
+        Err(_) => error!("{} disconnected, {}: handler panicked", ctx, stats),
+    }
+}
+
+impl<H: HandlerFactory + Clone + Send + 'static> Server<H> {
+    // 连接交给固定数量的 worker 线程处理, 每个连接用 new_handler 创建一个 handler
+    pub fn run(&self) -> io::Result<()> {
+        let worker_count = self.config.worker_count.max(1);
+
+        // 队列满了之后 send 会阻塞, 不再 accept 新的连接
+        let (sender, receiver) = mpsc::sync_channel::<(Stream, ConnectionContext)>(worker_count);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..worker_count {
+            let config = Arc::clone(&self.config);
+            let connections = Arc::clone(&self.connections);
+            let receiver = Arc::clone(&receiver);
+            let handler = self.handler.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                let Ok((stream, mut ctx)) = job else {
+                    return;
+                };
+                // 排队期间服务已经开始关闭
+                if connections.is_closing() {
+                    continue;
+                }
+                info!("{} connected", ctx);
+                let mut handler = handler.new_handler();
+                let transferred = Arc::new(Transferred::default());
+                let counters = &connections.counters;
+                // handshake_timeout 从 worker 开始处理连接时计算, 包括 PROXY 头, tls 握手和 http 握手
+                let deadline = config
+                    .handshake_timeout
+                    .map(|timeout| Instant::now() + timeout);
+                // 一个连接 panic 不能让 worker 线程退出
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    stream.set_nodelay(config.tcp_nodelay)?;
+                    if let Some(keepalive) = &config.tcp_keepalive {
+                        stream.set_keepalive(keepalive)?;
+                    }
+                    // PROXY protocol 的头在 tls 握手之前
+                    if config.proxy_protocol {
+                        let mut reader = Deadline::new(&stream, &stream, deadline);
+                        if let Some(peer_addr) = proxy_protocol::read_header(&mut reader)? {
+                            info!("{} proxied for {}", ctx, peer_addr);
+                            ctx.peer_addr = Some(peer_addr);
+                        }
+                    }
+                    // 只有 tcp 连接会使用 tls, bind_unix 不允许配置 tls
+                    #[cfg(feature = "tls")]
+                    if let (Some(tls), Stream::Tcp(tcp)) = (&tls, &stream) {
+                        let (reader, writer) = crate::tls::accept(tcp.try_clone()?, tls, deadline)?;
+                        return serve(
+                            &stream,
+                            Counted::new(reader, counters, &transferred),
+                            Counted::new(writer, counters, &transferred),
+                            deadline,
+                            &config,
+                            &mut handler,
+                            &connections,
+                            &mut ctx,
+                        );
+                    }
+                    let writer = stream.try_clone()?;
+                    serve(
+                        &stream,
+                        Counted::new(&stream, counters, &transferred),
+                        Counted::new(writer, counters, &transferred),
+                        deadline,
+                        &config,
+                        &mut handler,
+                        &connections,
+                        &mut ctx,
+                    )
+                }));
+                log_disconnect(&ctx, transferred.snapshot(), result);
+            });
+        }
+
+        // 所有连接共用一个线程发送 ping
+        // 读数据的线程一直阻塞在 read 上, ping 和超时之后的 close 帧都由这个线程发送
+        if let Some(ping_interval) = self.config.ping_interval {
+            let pong_timeout = self.config.pong_timeout;
+            let connections = Arc::clone(&self.connections);
+            let tick = ping_interval.min(pong_timeout) / 4;
+            thread::spawn(move || {
+                while !connections.is_closing() {
+                    thread::sleep(tick.max(Duration::from_millis(10)));
+                    connections.keepalive(ping_interval, pong_timeout);
+                }
+            });
+        }
+
+        // 每个 listener 一个 accept 线程, 连接都交给上面的 worker
+        // 一个 listener 不能再使用时整个服务停止, 返回第一个错误
+        let handle = &self.shutdown_handle();
+        let result = thread::scope(|scope| {
+            let accepting: Vec<_> = handle
+                .listeners
+                .iter()
+                .map(|listener| {
+                    let sender = sender.clone();
+                    scope.spawn(move || accept_loop(listener, handle, sender))
+                })
+                .collect();
+            drop(sender);
+            accepting
+                .into_iter()
+                .map(|accepting| accepting.join().unwrap())
+                .fold(Ok(()), io::Result::and)
+        });
+
+        // 等待客户端回复 close 帧之后断开连接
+        let deadline = Instant::now() + self.config.shutdown_grace_period;
+        while self.connections.len() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // 客户端一直不回复, 直接断开, worker 线程的读写马上返回
+        let remaining = self.connections.disconnect_all();
+        if !remaining.is_empty() {
+            warn!(
+                "{} connections did not close in time, force closed: {:?}",
+                remaining.len(),
+                remaining
+            );
+        }
+
+        result
+    }
+}
+
+// accept 出错时不能退出, 只有关闭服务或者 listener 不能再使用时才结束
+fn accept_loop(
+    listener: &Listener,
+    handle: &ShutdownHandle,
+    sender: mpsc::SyncSender<(Stream, ConnectionContext)>,
+) -> io::Result<()> {
+    let connections = &handle.connections;
+    loop {
+        let (stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(_) if connections.is_closing() => return Ok(()),
+            Err(err) => match listener::accept_backoff(&err) {
+                Some(backoff) => {
+                    warn!("accept failed: {}", err);
+                    thread::sleep(backoff);
+                    continue;
+                }
+                None => {
+                    error!("accept failed, stop serving: {}", err);
+                    // 其他的 listener 也停止 accept
+                    handle.shutdown();
+                    return Err(err);
+                }
+            },
+        };
+        let ctx = ConnectionContext {
+            id: connections.next_id(),
+            peer_addr,
+            forwarded_for: None,
+            protocol: None,
+        };
+        if connections.is_closing() || sender.send((stream, ctx)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// 创建 Server 之前配置参数
+#[derive(Default)]
+pub struct ServerBuilder {
+    pub(crate) config: Config,
+    // 证书和私钥的路径
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl ServerBuilder {
+    pub fn max_payload_length(mut self, max_payload_length: u64) -> Self {
+        self.config.max_payload_length = max_payload_length;
+        self
+    }
+
+    pub fn max_message_length(mut self, max_message_length: u64) -> Self {
+        self.config.max_message_length = max_message_length;
+        self
+    }
+
+    pub fn max_fragments(mut self, max_fragments: usize) -> Self {
+        self.config.max_fragments = max_fragments;
+        self
+    }
+
+    // 只接受这一种数据消息, 另一种消息的第一个 frame 到达时用 1003 关闭连接
+    pub fn data_type(mut self, data_type: DataType) -> Self {
+        self.config.data_type = Some(data_type);
+        self
+    }
+
+    // 握手请求最多有多少行头信息, 默认 100
+    pub fn max_header_count(mut self, max_header_count: usize) -> Self {
+        self.config.max_header_count = max_header_count;
+        self
+    }
+
+    // 请求行和每一行头信息的最大字节数 (包括换行), 默认 8 KiB
+    pub fn max_header_line_bytes(mut self, max_header_line_bytes: usize) -> Self {
+        self.config.max_header_line_bytes = max_header_line_bytes;
+        self
+    }
+
+    // 请求行和头信息加起来的最大字节数, 默认 16 KiB
+    pub fn max_total_header_bytes(mut self, max_total_header_bytes: usize) -> Self {
+        self.config.max_total_header_bytes = max_total_header_bytes;
+        self
+    }
+
+    // 握手请求之前可以先发送 CONNECT host:port, 服务端回复 200 Connection Established
+    pub fn connect_tunnel(mut self, connect_tunnel: bool) -> Self {
+        self.config.connect_tunnel = connect_tunnel;
+        self
+    }
+
+    pub fn protocols<S: Into<String>>(mut self, protocols: impl IntoIterator<Item = S>) -> Self {
+        self.config.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn permessage_deflate(mut self, enabled: bool) -> Self {
+        self.config.permessage_deflate = enabled;
+        self
+    }
+
+    // 短于这个长度的消息不压缩, 很小的消息压缩之后几乎不会变短, 只是浪费 cpu
+    pub fn deflate_min_size(mut self, deflate_min_size: usize) -> Self {
+        self.config.deflate_min_size = deflate_min_size;
+        self
+    }
+
+    // 同时处理连接的 worker 线程数量
+    pub fn worker_count(mut self, worker_count: usize) -> Self {
+        self.config.worker_count = worker_count;
+        self
+    }
+
+    // 同时在线的连接数量上限, 达到上限之后新的连接在握手时收到 503 并被关闭
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = Some(max_connections);
+        self
+    }
+
+    // 同一个客户端 ip 同时在线的连接数量上限, 超过时新的连接同样收到 503, unix socket 的连接不受限制
+    pub fn max_connections_per_ip(mut self, max_connections_per_ip: usize) -> Self {
+        self.config.max_connections_per_ip = Some(max_connections_per_ip);
+        self
+    }
+
+    // 使用 tls (wss://), 证书和私钥都是 pem 格式的文件, 在 bind 时读取
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.tls = Some((cert_path.into(), key_path.into()));
+        self
+    }
+
+    // 连接超过这个时间没有收到数据时发送 ping, 默认不发送
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.config.ping_interval = Some(ping_interval);
+        self
+    }
+
+    // 发送 ping 之后等待 pong 的时间, 超时之后用 1001 关闭连接
+    pub fn pong_timeout(mut self, pong_timeout: Duration) -> Self {
+        self.config.pong_timeout = pong_timeout;
+        self
+    }
+
+    // 握手请求需要在这个时间之内收完, 默认 10 秒, None 表示不限制
+    // 和握手之后的 read_timeout 分开设置
+    pub fn handshake_timeout(mut self, handshake_timeout: Option<Duration>) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    // 回复的 flush 策略, 客户端连续发送大量小消息时 Batched 或者 Timed 可以提高吞吐量
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.config.flush_policy = flush_policy;
+        self
+    }
+
+    // 按消息类型丢弃或者拒绝 text 和 binary 消息, 拒绝时用 1003 关闭连接
+    pub fn echo_policy(mut self, echo_policy: EchoPolicy) -> Self {
+        self.config.echo_policy = echo_policy;
+        self
+    }
+
+    // 客户端不接收数据时, 写操作最多阻塞这么久, 超时之后断开连接
+    pub fn write_timeout(mut self, write_timeout: Option<Duration>) -> Self {
+        self.config.write_timeout = write_timeout;
+        self
+    }
+
+    // 连接超过这个时间没有发送任何数据时用 1001 关闭连接
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.config.read_timeout = Some(read_timeout);
+        self
+    }
+
+    // 一个 frame 开始之后超过这个时间还没有收完时用 1001 关闭连接, 默认不限制
+    // read_timeout 只限制两次读之间的间隔, 慢慢发送的客户端可以一直占着连接
+    pub fn frame_timeout(mut self, frame_timeout: Duration) -> Self {
+        self.config.frame_timeout = Some(frame_timeout);
+        self
+    }
+
+    // 握手之后超过这个时间用 1001 关闭连接, 默认不限制
+    pub fn max_connection_duration(mut self, max_connection_duration: Duration) -> Self {
+        self.config.max_connection_duration = Some(max_connection_duration);
+        self
+    }
+
+    // 关闭服务时等待客户端回复 close 帧的时间, 默认 5 秒, 超过之后直接断开连接
+    pub fn shutdown_grace_period(mut self, shutdown_grace_period: Duration) -> Self {
+        self.config.shutdown_grace_period = shutdown_grace_period;
+        self
+    }
+
+    // 超过这个长度的 binary frame 读到一块就写回一块, 只对 echoes_binary 的 handler 生效
+    // streams_messages 的 handler 的 text 和 binary 消息读到一块就交给 on_chunk
+    // AsyncServer 不支持, bind_async 返回 Unsupported
+    pub fn stream_threshold(mut self, stream_threshold: u64) -> Self {
+        self.config.stream_threshold = Some(stream_threshold);
+        self
+    }
+
+    // 监听 ipv6 地址 (例如 [::]) 时只接受 ipv6 的连接, 默认是双栈
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.config.ipv6_only = ipv6_only;
+        self
+    }
+
+    // 设置 TCP_NODELAY, 默认开启, 关闭之后小的 frame 会被合并发送
+    pub fn tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.config.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    // 开启 TCP keepalive, 默认不开启
+    // 和 ping_interval 互补, 不需要应用层的配合, 只发送数据的连接也可以发现已经断开的客户端
+    pub fn tcp_keepalive(mut self, tcp_keepalive: TcpKeepalive) -> Self {
+        self.config.tcp_keepalive = Some(tcp_keepalive);
+        self
+    }
+
+    // 设置 SO_REUSEADDR, 默认开启
+    pub fn reuse_address(mut self, reuse_address: bool) -> Self {
+        self.config.reuse_address = reuse_address;
+        self
+    }
+
+    // 设置 SO_REUSEPORT, 默认关闭, 只在 unix 上生效
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.config.reuse_port = reuse_port;
+        self
+    }
+
+    // listen 的等待队列长度, 默认 128, 同时发起大量连接时调大, 超过系统上限的值会被系统截断
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.config.backlog = backlog;
+        self
+    }
+
+    // 对端在这些网段里时信任握手请求的 X-Forwarded-For, 用其中最左边的地址作为客户端的地址
+    pub fn trusted_proxies(mut self, trusted_proxies: impl IntoIterator<Item = IpNetwork>) -> Self {
+        self.config.trusted_proxies = trusted_proxies.into_iter().collect();
+        self
+    }
+
+    // 部署在 haproxy 或者 aws nlb 之后时开启, 从 PROXY protocol 的头里得到客户端的真实地址
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    // 注册一个扩展, 客户端 offer 了同名的扩展时由它决定是否接受
+    pub fn extension(mut self, extension: impl ExtensionHandler + 'static) -> Self {
+        self.config.extensions.push(Arc::new(extension));
+        self
+    }
+
+    // 在 101 响应里追加一个头, 例如 X-Server-Id, 可以调用多次
+    // 名字不是 token, 值有换行或者是 Upgrade, Sec-WebSocket-Accept 之类握手生成的头时 bind 返回错误
+    pub fn extra_response_header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.config
+            .extra_response_headers
+            .push((name.into(), value.into()));
+        self
+    }
+
+    // 记录每个握手请求的访问日志
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    // 浏览器打开这个路径 (例如 /) 时返回一个可以收发消息的测试页面, 默认不开启
+    // websocket 的握手带着 Upgrade 头, 同一个路径的握手不受影响
+    pub fn serve_demo_page(mut self, path: impl Into<String>) -> Self {
+        self.config.serve_demo_page = Some(path.into());
+        self
+    }
+
+    // 健康检查的路径, 默认是 /healthz, None 表示关闭
+    pub fn health_path(mut self, health_path: Option<impl Into<String>>) -> Self {
+        self.config.health_path = health_path.map(Into::into);
+        self
+    }
+
+    // 只接受这些 Origin 发起的握手, 其他的返回 403, 没有 Origin 的请求不是来自浏览器, 不检查
+    pub fn allowed_origins<S: Into<String>>(
+        mut self,
+        allowed_origins: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.config.allowed_origins = allowed_origins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // 限制每个连接每秒收到的 text 和 binary 消息数量和字节数, 超过之后延迟处理或者用 1008 关闭连接
+    // 控制消息不受限制, 速率为 0 时 bind 返回错误
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    // 不带升级头信息的 GET 请求访问这个路径时返回 prometheus 格式的统计数据, 默认不开启
+    pub fn metrics_path(mut self, metrics_path: impl Into<String>) -> Self {
+        self.config.metrics_path = Some(metrics_path.into());
+        self
+    }
+
+    pub fn bind(self, addr: impl ToSocketAddrs) -> io::Result<Server> {
+        self.bind_all([addr])
+    }
+
+    // 同时监听多个地址, 例如内网的 ipv6 地址和公网的 ipv4 地址, 所有地址的连接共用 worker 线程
+    // 每个地址和 bind 一样解析, 任何一个地址 bind 失败都返回错误
+    pub fn bind_all<A: ToSocketAddrs>(
+        self,
+        addrs: impl IntoIterator<Item = A>,
+    ) -> io::Result<Server> {
+        handshake::check_response_headers(&self.config.extra_response_headers)?;
+        rate_limit::check(self.config.rate_limit.as_ref())?;
+        let listeners = addrs
+            .into_iter()
+            .map(|addr| listener::bind(addr, &self.config))
+            .collect::<io::Result<Vec<_>>>()?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to bind",
+            ));
+        }
+        self.build_tcp(listeners)
+    }
+
+    // 使用已经 bind 好的 listener, 例如测试里 bind 127.0.0.1:0 之后读出分配的端口
+    // listener 的选项由调用方设置, backlog, ipv6_only, reuse_address 和 reuse_port 不生效
+    pub fn from_listener(self, listener: TcpListener) -> io::Result<Server> {
+        // 非阻塞的 listener 会让 accept 一直返回 WouldBlock
+        listener.set_nonblocking(false)?;
+        handshake::check_response_headers(&self.config.extra_response_headers)?;
+        rate_limit::check(self.config.rate_limit.as_ref())?;
+        self.build_tcp(vec![listener])
+    }
+
+    fn build_tcp(self, listeners: Vec<TcpListener>) -> io::Result<Server> {
+        #[cfg(feature = "tls")]
+        let tls = match &self.tls {
+            Some((cert_path, key_path)) => Some(crate::tls::load_config(cert_path, key_path)?),
+            None => None,
+        };
+        let server = self.build(listeners.into_iter().map(Listener::Tcp).collect());
+        #[cfg(feature = "tls")]
+        let server = Server { tls, ..server };
+        Ok(server)
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix(self, path: impl AsRef<Path>) -> io::Result<Server> {
+        // tls 只支持 tcp 连接
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tls is not supported on unix sockets",
+            ));
+        }
+        handshake::check_response_headers(&self.config.extra_response_headers)?;
+        rate_limit::check(self.config.rate_limit.as_ref())?;
+        let listener = listener::bind_unix(path.as_ref(), &self.config)?;
+        Ok(self.build(vec![listener]))
+    }
+
+    fn build(self, listeners: Vec<Listener>) -> Server {
+        Server {
+            listeners: Arc::new(listeners),
+            config: Arc::new(self.config),
+            handler: EchoHandler,
+            connections: Arc::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
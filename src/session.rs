@@ -0,0 +1,69 @@
+// 连接的状态, 只能按 Handshaking -> Open -> Closing -> Closed 的顺序前进
+// 握手只在 Handshaking 状态进行一次, 之后收到的任何数据 (即使看起来像另一个 http 请求) 都按 frame 解析
+use crate::WsError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    Handshaking,
+    Open,
+    // 发送或者收到了 close 帧, 不再处理新的消息
+    Closing,
+    Closed,
+}
+
+pub(crate) struct Session {
+    state: State,
+}
+
+impl Session {
+    pub(crate) fn new() -> Self {
+        Session {
+            state: State::Handshaking,
+        }
+    }
+
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+
+    // 握手成功之后进入 Open, 失败时直接 Closed
+    // 只有这里可以调用握手, 同一个连接调用第二次是 bug
+    pub(crate) fn handshake<T>(
+        &mut self,
+        accept: impl FnOnce() -> Result<T, WsError>,
+    ) -> Result<T, WsError> {
+        debug_assert_eq!(self.state, State::Handshaking, "handshake called twice");
+        let result = accept();
+        self.advance(match result {
+            Ok(_) => State::Open,
+            Err(_) => State::Closed,
+        });
+        result
+    }
+
+    // 已经是 Closing 时不变, 例如服务端发送了 close 帧之后又收到客户端的确认
+    pub(crate) fn closing(&mut self) {
+        if self.state != State::Closing {
+            self.advance(State::Closing);
+        }
+    }
+
+    // 连接断开, 客户端可能没有完成关闭握手就直接断开
+    pub(crate) fn closed(&mut self) {
+        self.advance(State::Closed);
+    }
+
+    fn advance(&mut self, next: State) {
+        use State::*;
+        let valid = matches!(
+            (self.state, next),
+            (Handshaking, Open) | (Open, Closing) | (Handshaking | Open | Closing, Closed)
+        );
+        debug_assert!(
+            valid,
+            "invalid session transition: {:?} -> {:?}",
+            self.state, next
+        );
+        self.state = next;
+    }
+}
@@ -0,0 +1,154 @@
+// 基于 rustls 的 wss:// 支持
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection,
+};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+// 读取 pem 格式的证书链和私钥
+pub(crate) fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(invalid_data)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_data)?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// 完成 tls 握手, 返回读写两端, 到了 deadline 还没有完成时返回 TimedOut
+// 读和写共用一个 tls 连接, 写入可能来自其他线程, 所以读的一端等待数据的时候不能持有锁
+pub(crate) fn accept(
+    stream: TcpStream,
+    config: &Arc<ServerConfig>,
+    deadline: Option<Instant>,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let mut connection = ServerConnection::new(Arc::clone(config)).map_err(invalid_data)?;
+    // complete_io 内部会循环读多次, 截止时间需要在每次读之前检查
+    let mut handshake = HandshakeStream {
+        stream: &stream,
+        deadline,
+    };
+    while connection.is_handshaking() {
+        connection.complete_io(&mut handshake)?;
+    }
+
+    let connection = Arc::new(Mutex::new(connection));
+    let reader = TlsReader {
+        stream: stream.try_clone()?,
+        connection: Arc::clone(&connection),
+    };
+    let writer = TlsWriter { stream, connection };
+    Ok((reader, writer))
+}
+
+// tls 握手时使用的 socket, 每次读之前把读超时设置成剩下的时间
+// 一个字节一个字节地发送也不能延长握手
+struct HandshakeStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Read for HandshakeStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "tls handshake timed out",
+                ));
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for HandshakeStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn lock(connection: &Mutex<ServerConnection>) -> MutexGuard<'_, ServerConnection> {
+    connection.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 发送 tls 连接中等待发送的数据
+fn write_pending(connection: &mut ServerConnection, mut stream: &TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(&mut stream)?;
+    }
+    Ok(())
+}
+
+pub(crate) struct TlsReader {
+    stream: TcpStream,
+    connection: Arc<Mutex<ServerConnection>>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut connection = lock(&self.connection);
+                match connection.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+
+            // 不持有锁, 阻塞到 socket 上有新的数据
+            if self.stream.peek(&mut [0])? == 0 {
+                return Ok(0);
+            }
+
+            let mut connection = lock(&self.connection);
+            connection.read_tls(&mut &self.stream)?;
+            connection.process_new_packets().map_err(invalid_data)?;
+            // 处理数据时可能产生需要回复的 tls 消息
+            write_pending(&mut connection, &self.stream)?;
+        }
+    }
+}
+
+pub(crate) struct TlsWriter {
+    stream: TcpStream,
+    connection: Arc<Mutex<ServerConnection>>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = lock(&self.connection);
+        let size = connection.writer().write(buf)?;
+        write_pending(&mut connection, &self.stream)?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = lock(&self.connection);
+        connection.writer().flush()?;
+        write_pending(&mut connection, &self.stream)
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    net::{Shutdown, SocketAddr},
+    time::{Duration, Instant},
+};
+
+// 发送 request 之后服务端马上回复 400 并关闭连接, 不会等到 handshake_timeout (默认 10 秒)
+fn assert_rejected(addr: SocketAddr, request: &[u8]) {
+    let mut raw = Raw::connect(addr);
+    raw.write(request);
+    let started = Instant::now();
+    let response = raw.read_to_end();
+    assert!(response.starts_with("HTTP/1.1 400 "), "{:?}", response);
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+// 连接之后马上发送空行, 没有请求行
+fn empty_request_line(addr: SocketAddr) {
+    assert_rejected(addr, b"\r\n");
+}
+
+// 什么都不发送就关闭了写的一端
+fn missing_request_line(addr: SocketAddr) {
+    let mut raw = Raw::connect(addr);
+    raw.stream.shutdown(Shutdown::Write).unwrap();
+    let response = raw.read_to_end();
+    assert!(response.starts_with("HTTP/1.1 400 "), "{:?}", response);
+}
+
+// 只用 \n 结束头信息, 同步版本读到这一行就拒绝, 异步版本也不能继续等待 \r\n
+fn bare_newline_after_headers(addr: SocketAddr) {
+    assert_rejected(
+        addr,
+        b"GET / HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\n",
+    );
+}
+
+// 请求行只用 \n 结尾
+fn bare_newline_request_line(addr: SocketAddr) {
+    assert_rejected(addr, b"GET / HTTP/1.1\nHost: localhost\n\n");
+}
+
+#[test]
+fn empty_request_line_is_rejected() {
+    let server = TestServer::start(common::builder());
+    empty_request_line(server.addr);
+}
+
+#[test]
+fn missing_request_line_is_rejected() {
+    let server = TestServer::start(common::builder());
+    missing_request_line(server.addr);
+}
+
+#[test]
+fn bare_newline_after_headers_is_rejected() {
+    let server = TestServer::start(common::builder());
+    bare_newline_after_headers(server.addr);
+}
+
+#[test]
+fn bare_newline_request_line_is_rejected() {
+    let server = TestServer::start(common::builder());
+    bare_newline_request_line(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn empty_request_line_is_rejected_async() {
+    empty_request_line(common::start_async(common::builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn missing_request_line_is_rejected_async() {
+    missing_request_line(common::start_async(common::builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn bare_newline_after_headers_is_rejected_async() {
+    bare_newline_after_headers(common::start_async(common::builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn bare_newline_request_line_is_rejected_async() {
+    bare_newline_request_line(common::start_async(common::builder()));
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    io::Read,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::BroadcastHandler;
+
+fn text(raw: &mut Raw) -> String {
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.opcode(), 1);
+    String::from_utf8(frame.payload().to_vec()).unwrap()
+}
+
+fn binary(raw: &mut Raw) -> Vec<u8> {
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.opcode(), 2);
+    frame.payload().to_vec()
+}
+
+// 连接加入和离开注册表都在服务端的线程里完成, 等到数量符合预期
+fn wait_for_count(handler: &BroadcastHandler, expected: usize) {
+    let started = Instant::now();
+    while handler.connection_count() != expected {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{} connections, expected {}",
+            handler.connection_count(),
+            expected
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// 三个客户端轮流发送, 包括发送者自己, 每个客户端都按相同的顺序收到所有消息
+// 不同客户端的消息在服务端的不同线程里处理, 先后顺序不一定和发送的顺序一样
+fn assert_fan_out(addr: SocketAddr, handler: &BroadcastHandler) {
+    let mut clients: Vec<Raw> = (0..3).map(|_| Raw::open(addr)).collect();
+    wait_for_count(handler, 3);
+    let mut sent = Vec::new();
+    for round in 0..3 {
+        for (i, client) in clients.iter_mut().enumerate() {
+            let text = format!("client {} round {}", i, round);
+            client.write(&common::text(&text));
+            sent.push(text);
+        }
+    }
+    let received: Vec<Vec<String>> = clients
+        .iter_mut()
+        .map(|client| (0..sent.len()).map(|_| text(client)).collect())
+        .collect();
+    assert!(received.iter().all(|texts| *texts == received[0]));
+    let mut sorted = received[0].clone();
+    sorted.sort();
+    sent.sort();
+    assert_eq!(sorted, sent);
+    // 同一个客户端的消息保持发送的顺序
+    for i in 0..3 {
+        let prefix = format!("client {} ", i);
+        let rounds: Vec<&String> = received[0]
+            .iter()
+            .filter(|text| text.starts_with(&prefix))
+            .collect();
+        assert!(rounds.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    // 离开的连接不再收到消息
+    let mut leaving = clients.pop().unwrap();
+    leaving.write(&common::frame(8, true, &1000u16.to_be_bytes()));
+    assert_eq!(leaving.read_close_code(), Some(1000));
+    wait_for_count(handler, 2);
+    clients[0].write(&common::text("after leave"));
+    for client in &mut clients {
+        assert_eq!(text(client), "after leave");
+    }
+}
+
+#[test]
+fn messages_fan_out_to_all_clients() {
+    let handler = BroadcastHandler::new();
+    let server = TestServer::start_with(common::builder(), handler.clone());
+    assert_fan_out(server.addr, &handler);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn messages_fan_out_to_all_clients_async() {
+    let handler = BroadcastHandler::new();
+    let addr = common::start_async_with(common::builder(), handler.clone());
+    assert_fan_out(addr, &handler);
+}
+
+// 一直不读数据的客户端, 发送线程阻塞之后队列变满, 服务端断开它
+// 其他客户端继续收到所有的消息, 发送者不会被拖慢
+#[test]
+fn slow_client_is_dropped() {
+    let handler = BroadcastHandler::with_queue_size(2);
+    let server = TestServer::start_with(common::builder(), handler.clone());
+    let mut sender = Raw::open(server.addr);
+    let mut receiver = Raw::open(server.addr);
+    let mut slow = Raw::open(server.addr);
+    wait_for_count(&handler, 3);
+
+    let payload = vec![7; 256 * 1024];
+    let started = Instant::now();
+    while handler.connection_count() == 3 {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "slow client not dropped"
+        );
+        sender.write(&common::frame(2, true, &payload));
+        assert_eq!(binary(&mut sender), payload);
+        assert_eq!(binary(&mut receiver), payload);
+    }
+    assert_eq!(handler.connection_count(), 2);
+
+    // 断开之后慢的客户端读完已经收到的数据就遇到连接关闭
+    let mut rest = Vec::new();
+    let _ = slow.reader.read_to_end(&mut rest);
+    sender.write(&common::text("still here"));
+    assert_eq!(text(&mut sender), "still here");
+    assert_eq!(text(&mut receiver), "still here");
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::TestServer;
+use std::{net::TcpListener, thread, time::Duration};
+use ws_server::{Client, DataType, FlushPolicy, Message, Server};
+
+fn assert_close(client: &mut Client, expected: u16) {
+    loop {
+        match client.recv().unwrap() {
+            Message::Close { code, .. } => {
+                assert_eq!(code, Some(expected));
+                return;
+            }
+            Message::Ping(_) => {}
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+}
+
+#[test]
+fn shutdown_sends_1001() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::builder()
+        .worker_count(2)
+        .shutdown_grace_period(Duration::from_secs(1))
+        .from_listener(listener)
+        .unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let mut client = Client::connect(&format!("ws://{}/", addr)).unwrap();
+    client
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send(&Message::Text("hello".into())).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::Text("hello".into()));
+
+    handle.shutdown();
+    assert_close(&mut client, 1001);
+    // 回复 close 之后 run 不用等到 shutdown_grace_period 就返回
+    client.close(1000).unwrap();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn message_too_big_sends_1009() {
+    let server = TestServer::start(common::builder().max_payload_length(16));
+    let mut client = server.client();
+    client.send(&Message::Binary(vec![0; 17])).unwrap();
+    assert_close(&mut client, 1009);
+}
+
+#[test]
+fn unsupported_data_sends_1003() {
+    let server = TestServer::start(common::builder().data_type(DataType::Text));
+    let mut client = server.client();
+    client.send(&Message::Binary(vec![1, 2, 3])).unwrap();
+    assert_close(&mut client, 1003);
+}
+
+#[test]
+fn client_close_is_echoed() {
+    let server = TestServer::start(common::builder());
+    let mut client = server.client();
+    client
+        .send(&Message::Close {
+            code: Some(4000),
+            reason: String::new(),
+        })
+        .unwrap();
+    assert_close(&mut client, 4000);
+}
+
+#[test]
+fn close_is_flushed_with_batched_replies() {
+    // 回复攒够 64 条才 flush, close 帧和它之前缓冲的回复仍然马上发出去
+    let server = TestServer::start(
+        common::builder()
+            .flush_policy(FlushPolicy::Batched(64))
+            .max_payload_length(16),
+    );
+    let mut raw = common::Raw::open(server.addr);
+    let mut data = common::text("one");
+    data.extend(common::text("two"));
+    data.extend(common::frame(2, true, &[0; 17]));
+    raw.write(&data);
+    assert_eq!(raw.read_frame().unwrap().payload(), b"one");
+    assert_eq!(raw.read_frame().unwrap().payload(), b"two");
+    assert_eq!(raw.read_close_code(), Some(1009));
+}
+
+// 在后台线程里运行 AsyncServer, run 返回之后线程结束
+#[cfg(feature = "tokio")]
+fn start_async(
+    grace: Duration,
+) -> (
+    std::net::SocketAddr,
+    ws_server::AsyncShutdownHandle,
+    thread::JoinHandle<std::io::Result<()>>,
+) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let running = thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let server = common::builder()
+                .shutdown_grace_period(grace)
+                .bind_async("127.0.0.1:0")
+                .await
+                .unwrap();
+            sender
+                .send((server.local_addr().unwrap(), server.shutdown_handle()))
+                .unwrap();
+            server.run().await
+        })
+    });
+    let (addr, handle) = receiver.recv().unwrap();
+    (addr, handle, running)
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_shutdown_sends_1001() {
+    let (addr, handle, running) = start_async(Duration::from_secs(5));
+    let mut client = Client::connect(&format!("ws://{}/", addr)).unwrap();
+    client
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send(&Message::Text("hello".into())).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::Text("hello".into()));
+
+    let started = std::time::Instant::now();
+    handle.shutdown();
+    assert_close(&mut client, 1001);
+    // 回复 close 之后 run 不用等到 shutdown_grace_period 就返回
+    client.close(1000).unwrap();
+    running.join().unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // 之后不再接受新的连接
+    assert!(Client::connect(&format!("ws://{}/", addr)).is_err());
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_shutdown_force_closes_after_grace_period() {
+    let grace = Duration::from_millis(300);
+    let (addr, handle, running) = start_async(grace);
+    // 不回复 close 帧的客户端, 和一个还没有发送握手请求的连接
+    let mut raw = common::Raw::open(addr);
+    let mut handshaking = common::Raw::connect(addr);
+    raw.write(&common::text("hello"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"hello");
+
+    let started = std::time::Instant::now();
+    handle.shutdown();
+    assert_eq!(raw.read_close_code(), Some(1001));
+    running.join().unwrap().unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= grace && elapsed < grace * 5, "{:?}", elapsed);
+    assert!(raw.is_closed());
+    assert!(handshaking.is_closed());
+}
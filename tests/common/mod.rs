@@ -0,0 +1,254 @@
+// 集成测试共用的工具: 在随机端口上启动服务端, 用原始的字节和服务端交互
+// 每个测试文件只用到其中的一部分
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use ws_server::{Client, EchoHandler, MessageHandler, Server, ServerBuilder, ShutdownHandle};
+
+// RFC 6455 1.3 里的例子
+pub const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+pub const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Host: localhost\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+// 测试机器可能只有一个 cpu, worker 太少时并发的连接会排队
+// 测试结束时不用等 5 秒的 shutdown_grace_period
+pub fn builder() -> ServerBuilder {
+    Server::builder()
+        .worker_count(8)
+        .shutdown_grace_period(Duration::from_millis(200))
+}
+
+// 在后台线程里运行的同步服务端, drop 的时候关闭
+pub struct TestServer {
+    pub addr: SocketAddr,
+    handle: ShutdownHandle,
+    running: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl TestServer {
+    pub fn start(builder: ServerBuilder) -> TestServer {
+        TestServer::start_with(builder, EchoHandler)
+    }
+
+    pub fn start_with<H: MessageHandler + Clone + Send + 'static>(
+        builder: ServerBuilder,
+        handler: H,
+    ) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = builder
+            .from_listener(listener)
+            .unwrap()
+            .with_handler(handler);
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+        TestServer {
+            addr,
+            handle,
+            running: Some(running),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    pub fn client(&self) -> Client {
+        let client = Client::connect(&self.url()).unwrap();
+        client
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(running) = self.running.take() {
+            let _ = running.join();
+        }
+    }
+}
+
+// 在后台线程的 tokio runtime 里运行的 AsyncServer, 返回监听的地址
+#[cfg(feature = "tokio")]
+pub fn start_async(builder: ServerBuilder) -> SocketAddr {
+    start_async_with(builder, EchoHandler)
+}
+
+#[cfg(feature = "tokio")]
+pub fn start_async_with<H: MessageHandler + Clone + Send + 'static>(
+    builder: ServerBuilder,
+    handler: H,
+) -> SocketAddr {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let server = builder
+                .bind_async("127.0.0.1:0")
+                .await
+                .unwrap()
+                .with_handler(handler);
+            sender.send(server.local_addr().unwrap()).unwrap();
+            let _ = server.run().await;
+        });
+    });
+    receiver.recv().unwrap()
+}
+
+// 服务端发送的 frame, 没有掩码
+pub struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+// 没有经过客户端实现的连接, 可以发送任意的字节
+pub struct Raw {
+    pub stream: TcpStream,
+    pub reader: BufReader<TcpStream>,
+}
+
+impl Raw {
+    pub fn connect(addr: SocketAddr) -> Raw {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Raw { stream, reader }
+    }
+
+    // 完成握手, 之后只有 frame
+    pub fn open(addr: SocketAddr) -> Raw {
+        let mut raw = Raw::connect(addr);
+        raw.write(HANDSHAKE);
+        let response = raw.read_response();
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        raw
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    // 状态行和头信息, 到空行为止
+    pub fn read_response(&mut self) -> String {
+        let mut response = String::new();
+        loop {
+            let size = self.reader.read_line(&mut response).unwrap();
+            if size == 0 || response.ends_with("\r\n\r\n") {
+                return response;
+            }
+        }
+    }
+
+    // 握手失败之后服务端关闭连接, 响应的 body 一起读出来
+    pub fn read_to_end(&mut self) -> String {
+        let mut response = Vec::new();
+        let _ = self.reader.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    // 服务端发送的下一个 frame, 连接关闭时返回 None
+    pub fn read_frame(&mut self) -> Option<Frame> {
+        let mut header = [0; 2];
+        self.reader.read_exact(&mut header).ok()?;
+        let length = match header[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                self.reader.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.reader.read_exact(&mut length).unwrap();
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload).unwrap();
+        Some(Frame {
+            opcode: header[0] & 0x0f,
+            payload,
+        })
+    }
+
+    // 跳过数据帧和 ping, 返回 close 帧的状态码
+    pub fn read_close_code(&mut self) -> Option<u16> {
+        loop {
+            let frame = self
+                .read_frame()
+                .expect("connection closed without close frame");
+            if frame.opcode() == 8 {
+                return close_code(&frame);
+            }
+        }
+    }
+
+    // 服务端已经关闭了连接 (不管之前有没有 close 帧)
+    pub fn is_closed(&mut self) -> bool {
+        let mut buffer = [0; 1];
+        matches!(self.reader.read(&mut buffer), Ok(0) | Err(_))
+    }
+}
+
+pub fn close_code(frame: &Frame) -> Option<u16> {
+    let payload = frame.payload();
+    (payload.len() >= 2).then(|| u16::from_be_bytes([payload[0], payload[1]]))
+}
+
+// 客户端发送的 frame 必须有 mask, 使用固定的 key
+pub fn frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let key = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![(fin as u8) << 7 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(0x80 | length as u8),
+        length @ 126..=65535 => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&key);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ key[i % 4]),
+    );
+    frame
+}
+
+pub fn text(payload: &str) -> Vec<u8> {
+    frame(1, true, payload.as_bytes())
+}
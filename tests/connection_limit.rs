@@ -0,0 +1,73 @@
+mod common;
+
+use common::{Raw, TestServer, HANDSHAKE};
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::ServerBuilder;
+
+const LIMIT: usize = 2;
+
+fn builder() -> ServerBuilder {
+    common::builder().max_connections_per_ip(LIMIT)
+}
+
+// 握手的响应, 失败时服务端回复之后关闭连接
+fn handshake(addr: SocketAddr) -> (Raw, String) {
+    let mut raw = Raw::connect(addr);
+    raw.write(HANDSHAKE);
+    let response = raw.read_response();
+    (raw, response)
+}
+
+// 所有连接都来自 127.0.0.1, 第 LIMIT + 1 个连接收到 503
+// 关闭其中一个之后名额释放, 可以重新连接
+fn assert_limited(addr: SocketAddr) {
+    let mut open: Vec<Raw> = (0..LIMIT).map(|_| Raw::open(addr)).collect();
+    let (_, response) = handshake(addr);
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+
+    let mut closed = open.pop().unwrap();
+    closed.write(&common::frame(8, true, &1000u16.to_be_bytes()));
+    assert_eq!(closed.read_close_code(), Some(1000));
+    assert!(closed.is_closed());
+    // 服务端处理完断开之后才释放名额
+    let started = Instant::now();
+    loop {
+        let (mut raw, response) = handshake(addr);
+        if response.starts_with("HTTP/1.1 101 ") {
+            raw.write(&common::text("again"));
+            assert_eq!(raw.read_frame().unwrap().payload(), b"again");
+            break;
+        }
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "slot not released"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn connections_per_ip_are_limited() {
+    let server = TestServer::start(builder());
+    assert_limited(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn connections_per_ip_are_limited_async() {
+    assert_limited(common::start_async(builder()));
+}
+
+// max_connections 和 per ip 的名额在同一个地方占用
+#[test]
+fn total_connections_are_limited() {
+    let server = TestServer::start(common::builder().max_connections(LIMIT));
+    let _open: Vec<Raw> = (0..LIMIT).map(|_| Raw::open(server.addr)).collect();
+    let (_, response) = handshake(server.addr);
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+}
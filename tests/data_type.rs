@@ -0,0 +1,31 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::net::SocketAddr;
+use ws_server::{DataType, ServerBuilder};
+
+fn builder() -> ServerBuilder {
+    common::builder().data_type(DataType::Text)
+}
+
+// text-only 的服务端照常回复 text, 收到 binary 时用 1003 关闭连接
+fn assert_binary_closed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    raw.write(&common::text("hello"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"hello");
+    raw.write(&common::frame(2, true, &[1, 2, 3]));
+    assert_eq!(raw.read_close_code(), Some(1003));
+    assert!(raw.is_closed());
+}
+
+#[test]
+fn binary_on_text_only_server_is_1003() {
+    let server = TestServer::start(builder());
+    assert_binary_closed(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn binary_on_text_only_server_is_1003_async() {
+    assert_binary_closed(common::start_async(builder()));
+}
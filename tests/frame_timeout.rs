@@ -0,0 +1,70 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    io::Write,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::ServerBuilder;
+
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+fn builder() -> ServerBuilder {
+    common::builder().frame_timeout(TIMEOUT)
+}
+
+// 2 字节扩展长度的 frame 只发送了长度的第一个字节
+// 服务端在 frame_timeout 之后用 1001 关闭, 之前不能把读到的半个头当成 frame 回复
+fn assert_stalled_length_closed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let frame = common::frame(2, true, &[7; 200]);
+    raw.write(&frame[..3]);
+    let started = Instant::now();
+    let close = raw.read_frame().unwrap();
+    assert_eq!(close.opcode(), 8);
+    assert_eq!(common::close_code(&close), Some(1001));
+    let elapsed = started.elapsed();
+    assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 5, "{:?}", elapsed);
+    // 剩下的部分到达时连接已经关闭
+    let _ = raw.stream.write_all(&frame[3..]);
+    assert!(raw.is_closed());
+}
+
+// 每个 frame 都在 frame_timeout 之内收完, 两个 frame 之间的等待不受限制
+fn assert_slow_frames_kept(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    for _ in 0..2 {
+        thread::sleep(TIMEOUT * 2);
+        let frame = common::frame(2, true, &[7; 200]);
+        raw.write(&frame[..3]);
+        thread::sleep(TIMEOUT / 3);
+        raw.write(&frame[3..]);
+        assert_eq!(raw.read_frame().unwrap().payload(), &[7; 200][..]);
+    }
+}
+
+#[test]
+fn stalled_extended_length_is_closed() {
+    let server = TestServer::start(builder());
+    assert_stalled_length_closed(server.addr);
+}
+
+#[test]
+fn slow_frames_within_timeout_are_kept() {
+    let server = TestServer::start(builder());
+    assert_slow_frames_kept(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn stalled_extended_length_is_closed_async() {
+    assert_stalled_length_closed(common::start_async(builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn slow_frames_within_timeout_are_kept_async() {
+    assert_slow_frames_kept(common::start_async(builder()));
+}
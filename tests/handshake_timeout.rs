@@ -0,0 +1,94 @@
+mod common;
+
+use common::{TestServer, HANDSHAKE};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// 每 100ms 发送一个字节, 每次都在 handshake_timeout 之内, 直到服务端关闭连接
+// 返回从连接到被关闭经过的时间
+fn trickle(addr: SocketAddr, data: &[u8]) -> Duration {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let start = Instant::now();
+    let mut buffer = [0; 512];
+    for byte in data {
+        if stream.write_all(&[*byte]).is_err() {
+            return start.elapsed();
+        }
+        match stream.read(&mut buffer) {
+            // 被关闭, 或者收到了 408 的响应
+            Ok(_) => return start.elapsed(),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => return start.elapsed(),
+        }
+    }
+    panic!("the whole request was sent without the connection being closed");
+}
+
+fn assert_cut_off(elapsed: Duration) {
+    assert!(elapsed >= TIMEOUT, "{:?}", elapsed);
+    assert!(elapsed < TIMEOUT * 4, "{:?}", elapsed);
+}
+
+#[test]
+fn trickled_http_request_is_cut_off() {
+    let server = TestServer::start(common::builder().handshake_timeout(Some(TIMEOUT)));
+    assert_cut_off(trickle(server.addr, HANDSHAKE));
+}
+
+#[test]
+fn trickled_proxy_header_is_cut_off() {
+    let server = TestServer::start(
+        common::builder()
+            .proxy_protocol(true)
+            .handshake_timeout(Some(TIMEOUT)),
+    );
+    let mut request = b"PROXY TCP4 192.0.2.1 192.0.2.2 40000 443\r\n".to_vec();
+    request.extend_from_slice(HANDSHAKE);
+    assert_cut_off(trickle(server.addr, &request));
+}
+
+#[test]
+fn proxy_header_and_request_share_one_deadline() {
+    let server = TestServer::start(
+        common::builder()
+            .proxy_protocol(true)
+            .handshake_timeout(Some(TIMEOUT)),
+    );
+    // PROXY 头马上发送完, 之后慢慢发送的握手请求只剩下同一个截止时间的余量
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream
+        .write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 40000 443\r\n")
+        .unwrap();
+    std::thread::sleep(TIMEOUT / 2);
+    let start = Instant::now();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    assert!(start.elapsed() < TIMEOUT, "{:?}", start.elapsed());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn trickled_tls_handshake_is_cut_off() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    let server = TestServer::start(
+        common::builder()
+            .with_tls(format!("{}/cert.pem", dir), format!("{}/key.pem", dir))
+            .handshake_timeout(Some(TIMEOUT)),
+    );
+    // tls 记录的头声明了 512 字节的 ClientHello, rustls 要等到收完整个记录才会处理
+    let mut record = vec![0x16, 0x03, 0x01, 0x02, 0x00];
+    record.resize(5 + 512, 0);
+    assert_cut_off(trickle(server.addr, &record));
+}
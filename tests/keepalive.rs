@@ -0,0 +1,30 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::time::{Duration, Instant};
+
+#[test]
+fn data_after_ping_does_not_disable_keepalive() {
+    let server = TestServer::start(
+        common::builder()
+            .ping_interval(Duration::from_millis(300))
+            .pong_timeout(Duration::from_millis(300)),
+    );
+    let mut raw = Raw::open(server.addr);
+
+    // 空闲之后收到第一个 ping, 不回复 pong, 而是发送一条数据消息
+    let ping = raw.read_frame().unwrap();
+    assert_eq!(ping.opcode(), 9);
+    raw.write(&common::text("alive"));
+    let echo = raw.read_frame().unwrap();
+    assert_eq!(echo.payload(), b"alive");
+
+    // 之后什么都不发送, 服务端重新发送 ping, 等不到 pong 时用 1001 关闭
+    let idle = Instant::now();
+    let ping = raw.read_frame().unwrap();
+    assert_eq!(ping.opcode(), 9);
+    assert_eq!(raw.read_close_code(), Some(1001));
+    let elapsed = idle.elapsed();
+    assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
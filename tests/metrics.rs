@@ -0,0 +1,86 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::ServerBuilder;
+
+fn builder() -> ServerBuilder {
+    common::builder().metrics_path("/metrics")
+}
+
+// metrics_path 返回的某一项的值, 这个请求本身也算一个连接
+fn metric(addr: SocketAddr, name: &str) -> u64 {
+    let mut raw = Raw::connect(addr);
+    raw.write(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let response = raw.read_to_end();
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("{} not in {}", name, response))
+        .parse()
+        .unwrap()
+}
+
+// 连接断开之后计数器才更新, 等一会直到符合预期
+fn wait_for(addr: SocketAddr, name: &str, expected: u64) {
+    let started = Instant::now();
+    loop {
+        let value = metric(addr, name);
+        if value == expected {
+            return;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{} is {}, expected {}",
+            name,
+            value,
+            expected
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// 一个正常的连接, 一个握手失败的连接, 一个违反协议的连接
+fn assert_counted(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    raw.write(&common::text("hello"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"hello");
+    wait_for(addr, "ws_connections_active", 1);
+    raw.write(&common::frame(8, true, &1000u16.to_be_bytes()));
+    assert_eq!(raw.read_close_code(), Some(1000));
+
+    let mut rejected = Raw::connect(addr);
+    rejected.write(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n");
+    assert!(rejected.read_to_end().starts_with("HTTP/1.1 400 "));
+
+    let mut violation = Raw::open(addr);
+    // 客户端的 frame 没有 mask
+    violation.write(&[0x81, 0x02, b'h', b'i']);
+    assert_eq!(violation.read_close_code(), Some(1002));
+
+    wait_for(addr, "ws_connections_active", 0);
+    wait_for(addr, "ws_messages_echoed_total", 1);
+    wait_for(addr, "ws_handshake_failures_total", 1);
+    wait_for(addr, "ws_protocol_errors_total", 1);
+    // 三个 websocket 连接和前面所有的 metrics 请求
+    assert!(metric(addr, "ws_connections_total") > 3);
+    assert!(metric(addr, "ws_received_bytes_total") > 0);
+    assert!(metric(addr, "ws_sent_bytes_total") > 0);
+}
+
+#[test]
+fn connections_and_messages_are_counted() {
+    let server = TestServer::start(builder());
+    assert_counted(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn connections_and_messages_are_counted_async() {
+    assert_counted(common::start_async(builder()));
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::net::SocketAddr;
+
+const PINGS: u16 = 1000;
+
+// 一次写出大量 ping, 服务端每次读完缓冲的数据才回复一个 pong, pong 的数量远少于 ping
+// 最后一个 pong 一定是最后一个 ping 的数据, 之后的消息照常回复
+fn assert_pongs_coalesced(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let flood: Vec<u8> = (0..PINGS)
+        .flat_map(|i| common::frame(9, true, &i.to_be_bytes()))
+        .collect();
+    raw.write(&flood);
+    let last = (PINGS - 1).to_be_bytes();
+    let mut pongs = 0;
+    loop {
+        let frame = raw.read_frame().unwrap();
+        assert_eq!(frame.opcode(), 10);
+        pongs += 1;
+        if frame.payload() == last {
+            break;
+        }
+    }
+    assert!(pongs < PINGS / 10, "{} pongs", pongs);
+
+    raw.write(&common::text("after"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"after");
+}
+
+#[test]
+fn ping_flood_is_coalesced() {
+    let server = TestServer::start(common::builder());
+    assert_pongs_coalesced(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn ping_flood_is_coalesced_async() {
+    assert_pongs_coalesced(common::start_async(common::builder()));
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::{Raw, TestServer, HANDSHAKE};
+use std::{
+    io::{BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+// 握手请求和 frame 在同一次 write_all 里发送, 握手时读进缓冲区的 frame 不能丢
+fn connect_pipelined(addr: SocketAddr, frames: &[u8]) -> Raw {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = HANDSHAKE.to_vec();
+    request.extend_from_slice(frames);
+    stream.write_all(&request).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    let mut raw = Raw { stream, reader };
+    let response = raw.read_response();
+    assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+    raw
+}
+
+#[test]
+fn frame_sent_with_handshake_is_echoed() {
+    let server = TestServer::start(common::builder());
+    let mut raw = connect_pipelined(server.addr, &common::text("pipelined"));
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.opcode(), 1);
+    assert_eq!(frame.payload(), b"pipelined");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn frame_sent_with_handshake_is_echoed_async() {
+    let addr = common::start_async(common::builder());
+    let mut raw = connect_pipelined(addr, &common::text("pipelined"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"pipelined");
+}
+
+// 握手时 BufReader 读进来的可能不止一个 frame, 包括分片和控制帧
+fn several_frames() -> Vec<u8> {
+    let mut frames = common::text("first");
+    frames.extend(common::frame(2, true, &[7; 300]));
+    frames.extend(common::frame(1, false, b"frag"));
+    frames.extend(common::frame(9, true, b"ping"));
+    frames.extend(common::frame(0, true, b"mented"));
+    frames.extend(common::text("last"));
+    frames
+}
+
+fn assert_echoes_in_order(raw: &mut Raw) {
+    let mut replies = Vec::new();
+    for _ in 0..5 {
+        let frame = raw.read_frame().unwrap();
+        replies.push((frame.opcode(), frame.payload().to_vec()));
+    }
+    // pong 在收到的数据处理完之后才发送, 位置取决于服务端一次读到了多少, 只检查数据消息的顺序
+    let pongs: Vec<_> = replies.iter().filter(|(opcode, _)| *opcode == 10).collect();
+    assert_eq!(pongs, vec![&(10, b"ping".to_vec())]);
+    replies.retain(|(opcode, _)| *opcode != 10);
+    assert_eq!(
+        replies,
+        vec![
+            (1, b"first".to_vec()),
+            (2, vec![7; 300]),
+            (1, b"fragmented".to_vec()),
+            (1, b"last".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn several_frames_sent_with_handshake_are_echoed_in_order() {
+    let server = TestServer::start(common::builder());
+    let mut raw = connect_pipelined(server.addr, &several_frames());
+    assert_echoes_in_order(&mut raw);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn several_frames_sent_with_handshake_are_echoed_in_order_async() {
+    let addr = common::start_async(common::builder());
+    let mut raw = connect_pipelined(addr, &several_frames());
+    assert_echoes_in_order(&mut raw);
+}
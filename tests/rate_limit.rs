@@ -0,0 +1,151 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use ws_server::{RateLimit, RateLimitAction, ServerBuilder};
+
+const RATE: u32 = 20;
+
+fn builder(action: RateLimitAction) -> ServerBuilder {
+    common::builder().rate_limit(RateLimit {
+        messages_per_second: Some(RATE),
+        bytes_per_second: None,
+        action,
+    })
+}
+
+// 一次发送 RATE * 3 / 2 条消息, 前 RATE 条马上回复, 剩下的每条间隔 1 / RATE 秒
+fn assert_burst_delayed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let count = RATE * 3 / 2;
+    let burst: Vec<u8> = (0..count)
+        .flat_map(|i| common::text(&i.to_string()))
+        .collect();
+    let started = Instant::now();
+    raw.write(&burst);
+    for i in 0..count {
+        assert_eq!(
+            raw.read_frame().unwrap().payload(),
+            i.to_string().as_bytes()
+        );
+    }
+    let elapsed = started.elapsed();
+    let expected = Duration::from_secs(1) * (count - RATE) / RATE;
+    assert!(
+        elapsed >= expected * 9 / 10 && elapsed < expected * 4,
+        "{:?}",
+        elapsed
+    );
+}
+
+// 超过限制的第一条消息用 1008 关闭, 其他连接不受影响
+fn assert_burst_closed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let burst: Vec<u8> = (0..RATE + 1)
+        .flat_map(|i| common::text(&i.to_string()))
+        .collect();
+    raw.write(&burst);
+    for i in 0..RATE {
+        assert_eq!(
+            raw.read_frame().unwrap().payload(),
+            i.to_string().as_bytes()
+        );
+    }
+    assert_eq!(raw.read_close_code(), Some(1008));
+    assert!(raw.is_closed());
+
+    let mut other = Raw::open(addr);
+    other.write(&common::text("quiet"));
+    assert_eq!(other.read_frame().unwrap().payload(), b"quiet");
+}
+
+#[test]
+fn burst_is_delayed() {
+    let server = TestServer::start(builder(RateLimitAction::Delay));
+    assert_burst_delayed(server.addr);
+}
+
+#[test]
+fn burst_is_closed_with_1008() {
+    let server = TestServer::start(builder(RateLimitAction::Close));
+    assert_burst_closed(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn burst_is_delayed_async() {
+    assert_burst_delayed(common::start_async(builder(RateLimitAction::Delay)));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn burst_is_closed_with_1008_async() {
+    assert_burst_closed(common::start_async(builder(RateLimitAction::Close)));
+}
+
+// 用完突发之后 ping 照常回复 pong, close 得到 1000 的关闭握手而不是 1008
+fn assert_close_after_burst(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let burst: Vec<u8> = (0..RATE)
+        .flat_map(|i| common::text(&i.to_string()))
+        .collect();
+    raw.write(&burst);
+    for i in 0..RATE {
+        assert_eq!(
+            raw.read_frame().unwrap().payload(),
+            i.to_string().as_bytes()
+        );
+    }
+    raw.write(&common::frame(9, true, b"still alive"));
+    let pong = raw.read_frame().unwrap();
+    assert_eq!((pong.opcode(), pong.payload()), (10, &b"still alive"[..]));
+    raw.write(&common::frame(8, true, &1000u16.to_be_bytes()));
+    assert_eq!(raw.read_close_code(), Some(1000));
+    assert!(raw.is_closed());
+}
+
+#[test]
+fn close_after_burst_is_not_limited() {
+    let server = TestServer::start(builder(RateLimitAction::Close));
+    assert_close_after_burst(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn close_after_burst_is_not_limited_async() {
+    assert_close_after_burst(common::start_async(builder(RateLimitAction::Close)));
+}
+
+fn zero_rate() -> ServerBuilder {
+    common::builder().rate_limit(RateLimit {
+        messages_per_second: Some(0),
+        bytes_per_second: None,
+        action: RateLimitAction::Delay,
+    })
+}
+
+// Delay 的速率为 0 时会永远等待, bind 时就拒绝
+#[test]
+fn zero_rate_is_rejected_at_bind() {
+    let err = zero_rate()
+        .bind("127.0.0.1:0")
+        .err()
+        .expect("bind accepted");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn zero_rate_is_rejected_at_bind_async() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let result = runtime.block_on(zero_rate().bind_async("127.0.0.1:0"));
+    let err = result.err().expect("bind_async accepted");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
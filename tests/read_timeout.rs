@@ -0,0 +1,83 @@
+mod common;
+
+use common::{Raw, TestServer};
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use ws_server::ServerBuilder;
+
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+fn builder() -> ServerBuilder {
+    common::builder().read_timeout(TIMEOUT)
+}
+
+// 握手之后什么都不发送, 超过 read_timeout 之后收到 1001
+fn assert_idle_closed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let started = Instant::now();
+    assert_eq!(raw.read_close_code(), Some(1001));
+    let elapsed = started.elapsed();
+    assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 5, "{:?}", elapsed);
+    assert!(raw.is_closed());
+}
+
+// 只发送 frame 的头和一部分 payload, 停在 read_exact 的中间
+fn assert_stalled_frame_closed(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    let frame = common::text("hello world");
+    raw.write(&frame[..8]);
+    assert_eq!(raw.read_close_code(), Some(1001));
+    assert!(raw.is_closed());
+}
+
+// 每次发送的间隔都小于 read_timeout, 总时间超过也不会断开
+fn assert_active_kept(addr: SocketAddr) {
+    let mut raw = Raw::open(addr);
+    for i in 0..4 {
+        thread::sleep(TIMEOUT / 2);
+        let message = format!("message {}", i);
+        raw.write(&common::text(&message));
+        let frame = raw.read_frame().unwrap();
+        assert_eq!(frame.opcode(), 1);
+        assert_eq!(frame.payload(), message.as_bytes());
+    }
+}
+
+#[test]
+fn idle_connection_is_closed() {
+    let server = TestServer::start(builder());
+    assert_idle_closed(server.addr);
+}
+
+#[test]
+fn stalled_frame_is_closed() {
+    let server = TestServer::start(builder());
+    assert_stalled_frame_closed(server.addr);
+}
+
+#[test]
+fn active_connection_is_kept() {
+    let server = TestServer::start(builder());
+    assert_active_kept(server.addr);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn idle_connection_is_closed_async() {
+    assert_idle_closed(common::start_async(builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn stalled_frame_is_closed_async() {
+    assert_stalled_frame_closed(common::start_async(builder()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn active_connection_is_kept_async() {
+    assert_active_kept(common::start_async(builder()));
+}
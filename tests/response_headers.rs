@@ -0,0 +1,78 @@
+mod common;
+
+use common::{Raw, TestServer, HANDSHAKE};
+use std::{io::ErrorKind, net::TcpListener};
+use ws_server::ServerBuilder;
+
+fn builder() -> ServerBuilder {
+    common::builder().extra_response_header("X-Server-Id", "echo-1")
+}
+
+#[test]
+fn extra_header_in_101() {
+    let server = TestServer::start(builder());
+    let mut raw = Raw::connect(server.addr);
+    raw.write(HANDSHAKE);
+    let response = raw.read_response();
+    assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+    assert!(
+        response.contains("\r\nX-Server-Id: echo-1\r\n"),
+        "{}",
+        response
+    );
+    // 握手之后照常回复
+    raw.write(&common::text("hello"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"hello");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn extra_header_in_101_async() {
+    let addr = common::start_async(builder());
+    let mut raw = Raw::connect(addr);
+    raw.write(HANDSHAKE);
+    let response = raw.read_response();
+    assert!(
+        response.contains("\r\nX-Server-Id: echo-1\r\n"),
+        "{}",
+        response
+    );
+}
+
+// 不合法的头在 bind 时就返回错误, 不会等到第一个握手
+fn invalid() -> [ServerBuilder; 3] {
+    [
+        common::builder().extra_response_header("Sec-WebSocket-Accept", "forged"),
+        common::builder().extra_response_header("X Server", "echo-1"),
+        common::builder().extra_response_header("X-Server-Id", "a\r\nX-Injected: b"),
+    ]
+}
+
+#[test]
+fn invalid_headers_are_rejected_at_bind() {
+    for builder in invalid() {
+        let err = builder.bind("127.0.0.1:0").err().expect("bind accepted");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let err = common::builder()
+        .extra_response_header("Upgrade", "h2c")
+        .from_listener(listener)
+        .err()
+        .expect("from_listener accepted");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn invalid_headers_are_rejected_at_bind_async() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for builder in invalid() {
+        let result = runtime.block_on(builder.bind_async("127.0.0.1:0"));
+        let err = result.err().expect("bind_async accepted");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
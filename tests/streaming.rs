@@ -0,0 +1,115 @@
+mod common;
+
+use common::{Raw, TestServer};
+use ws_server::{Chunk, Message, MessageHandler};
+
+// 分块把 ascii 转成大写, broken 时每一块多输出一个字节
+#[derive(Clone)]
+struct Upper {
+    preserves_length: bool,
+    broken: bool,
+}
+
+impl MessageHandler for Upper {
+    fn on_message(&mut self, msg: Message) -> Option<Message> {
+        Some(msg)
+    }
+
+    fn streams_messages(&self) -> bool {
+        true
+    }
+
+    fn on_chunk(&mut self, chunk: Chunk<'_>, out: &mut Vec<u8>) {
+        out.extend(chunk.data.iter().map(u8::to_ascii_uppercase));
+        if self.broken {
+            out.push(b'!');
+        }
+    }
+
+    fn preserves_length(&self) -> bool {
+        self.preserves_length
+    }
+}
+
+fn start(preserves_length: bool, broken: bool) -> TestServer {
+    TestServer::start_with(
+        common::builder().stream_threshold(16),
+        Upper {
+            preserves_length,
+            broken,
+        },
+    )
+}
+
+#[test]
+fn transforms_chunks_without_fixed_length() {
+    let server = start(false, false);
+    let mut raw = Raw::open(server.addr);
+    let text = "streamed through on_chunk ".repeat(1000);
+    raw.write(&common::text(&text));
+    // 回复是多个分片, 拼接之后和整条消息转换的结果一样
+    let first = raw.read_frame().unwrap();
+    assert_eq!(first.opcode(), 1);
+    let mut payload = first.payload().to_vec();
+    while payload.len() < text.len() {
+        let frame = raw.read_frame().unwrap();
+        assert_eq!(frame.opcode(), 0);
+        payload.extend_from_slice(frame.payload());
+    }
+    assert_eq!(payload, text.to_uppercase().as_bytes());
+    // 短于 stream_threshold 的消息交给 on_message
+    raw.write(&common::text("short"));
+    assert_eq!(raw.read_frame().unwrap().payload(), b"short");
+}
+
+#[test]
+fn preserves_length_mirrors_the_client_frame() {
+    let server = start(true, false);
+    let mut raw = Raw::open(server.addr);
+    let payload = b"abcdefghij".repeat(20);
+    raw.write(&common::frame(2, true, &payload));
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.opcode(), 2);
+    assert_eq!(frame.payload(), payload.to_ascii_uppercase());
+}
+
+#[test]
+fn broken_preserves_length_closes_with_1011() {
+    let server = start(true, true);
+    let mut raw = Raw::open(server.addr);
+    raw.write(&common::frame(2, true, &[b'a'; 200]));
+    // 回复的头已经发送, 服务端用 0 填满这个 frame 之后再发送 close
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.opcode(), 2);
+    assert_eq!(frame.payload().len(), 200);
+    assert_eq!(raw.read_close_code(), Some(1011));
+}
+
+#[test]
+fn invalid_utf8_with_preserves_length_still_sends_close() {
+    let server = start(true, false);
+    let mut raw = Raw::open(server.addr);
+    let mut payload = vec![b'a'; 100];
+    payload[50] = 0xff;
+    raw.write(&common::frame(1, true, &payload));
+    let frame = raw.read_frame().unwrap();
+    assert_eq!(frame.payload().len(), 100);
+    assert_eq!(raw.read_close_code(), Some(1007));
+}
+
+// 异步的实现不支持分块处理, 不能悄悄地忽略
+#[cfg(feature = "tokio")]
+#[test]
+fn stream_threshold_is_rejected_by_async_server() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let result = runtime.block_on(
+        common::builder()
+            .stream_threshold(1024)
+            .bind_async("127.0.0.1:0"),
+    );
+    let err = result.err().expect("bind_async accepted stream_threshold");
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}